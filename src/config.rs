@@ -1,38 +1,199 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use std::{
+    collections::HashSet,
     fs,
     net::{SocketAddr, ToSocketAddrs as _},
+    path::PathBuf,
     time,
 };
 
 use reqwest::Url;
 use serde::Deserialize;
 
+use crate::ssdp::discovery::Discovery;
+use crate::ssdp::interfaces::InterfaceSelection;
+use crate::rewrite::RewriteRule;
+use crate::ssdp::IpVersion;
+use crate::tcp_proxy::Unreachable;
 use crate::CommandLineConf;
 
 #[derive(Deserialize)]
 struct RawConfig {
+    verbose: Option<u8>,
+    iface: Option<OneOrMany<String>>,
+    exclude_iface: Option<Vec<String>>,
+    ip_version: Option<IpVersion>,
+    bind_to_device: Option<bool>,
+    unicast_targets: Option<Vec<String>>,
+    learn_unicast_targets: Option<bool>,
+    wait: Option<u64>,
+    boot_id_file: Option<PathBuf>,
+    reflector: Option<RawReflectorConfig>,
+    server: Option<Vec<RawServerConfig>>,
+
+    /// Top-level server settings: a single server when `description_url` is set,
+    /// otherwise defaults for every `[[server]]` entry.
+    #[serde(flatten)]
+    defaults: RawServerConfig,
+}
+
+/// The `[reflector]` table.
+#[derive(Deserialize)]
+struct RawReflectorConfig {
+    interfaces: Vec<String>,
+    device_types: Option<Vec<String>>,
+}
+
+/// A `[[rewrite]]` entry.
+#[derive(Clone, Deserialize)]
+struct RawRewriteRule {
+    #[serde(rename = "match")]
+    pattern: String,
+    replace: String,
+    regex: Option<bool>,
+    content_type: Option<OneOrMany<String>>,
+    path: Option<String>,
+}
+
+/// The `[unreachable]` table.
+#[derive(Clone, Deserialize)]
+struct RawUnreachable {
+    refused: Option<RawErrorStatus>,
+    timeout: Option<RawErrorStatus>,
+    retry_after: Option<u64>,
+}
+
+/// An error status, or `"close"` to answer with none.
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+enum RawErrorStatus {
+    Status(u16),
+    Close(String),
+}
+
+/// A single value or a list of them, e.g. `iface = "eth0"` or `iface = ["eth0", "wlan0"]`.
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> From<OneOrMany<T>> for Vec<T> {
+    fn from(value: OneOrMany<T>) -> Self {
+        match value {
+            OneOrMany::One(one) => vec![one],
+            OneOrMany::Many(many) => many,
+        }
+    }
+}
+
+/// Per-server settings, either top-level or in a `[[server]]` entry.
+#[derive(Default, Deserialize)]
+struct RawServerConfig {
     description_url: Option<String>,
+    discover: Option<String>,
+    device_type: Option<String>,
+    friendly_name: Option<String>,
+    udn: Option<String>,
+    /// Interfaces this server is announced on, among the ones SSDP runs on.
+    announce_iface: Option<OneOrMany<String>>,
     period: Option<u64>,
     proxy: Option<String>,
-    verbose: Option<u8>,
-    iface: Option<String>,
-    wait: Option<u64>,
+    /// Port the proxy receives GENA events on, any free one when unset.
+    event_port: Option<u16>,
     connect_timeout: Option<u64>,
     proxy_timeout: Option<u64>,
     stream_timeout: Option<u64>,
+    cache_ttl: Option<u64>,
+    cache_max_stale: Option<u64>,
+    down_after_failures: Option<u32>,
+    up_after_successes: Option<u32>,
+    /// Extra rewriting of the proxy's responses, in order.
+    rewrite: Option<Vec<RawRewriteRule>>,
+    /// How clients are answered when the server can't be reached.
+    unreachable: Option<RawUnreachable>,
+    /// Whether to ask the server for uncompressed responses.
+    identity_encoding: Option<bool>,
 }
 
-pub struct Config {
-    pub description_url: Url,
+impl RawServerConfig {
+    /// Fill unset timings from `defaults`. Where the server is, where it is
+    /// announced and the proxy and event ports are never inherited, since each
+    /// proxy needs its own sockets.
+    fn inherit(&self, defaults: &RawServerConfig) -> RawServerConfig {
+        RawServerConfig {
+            description_url: self.description_url.clone(),
+            discover: self.discover.clone(),
+            device_type: self.device_type.clone(),
+            friendly_name: self.friendly_name.clone(),
+            udn: self.udn.clone(),
+            announce_iface: self.announce_iface.clone(),
+            proxy: self.proxy.clone(),
+            event_port: self.event_port,
+            period: self.period.or(defaults.period),
+            connect_timeout: self.connect_timeout.or(defaults.connect_timeout),
+            proxy_timeout: self.proxy_timeout.or(defaults.proxy_timeout),
+            stream_timeout: self.stream_timeout.or(defaults.stream_timeout),
+            cache_ttl: self.cache_ttl.or(defaults.cache_ttl),
+            cache_max_stale: self.cache_max_stale.or(defaults.cache_max_stale),
+            down_after_failures: self.down_after_failures.or(defaults.down_after_failures),
+            up_after_successes: self.up_after_successes.or(defaults.up_after_successes),
+            rewrite: self.rewrite.clone().or(defaults.rewrite.clone()),
+            unreachable: self.unreachable.clone().or(defaults.unreachable.clone()),
+            identity_encoding: self.identity_encoding.or(defaults.identity_encoding),
+        }
+    }
+}
+
+/// Where to find a remote server's description.
+pub enum ServerSource {
+    Url(Url),
+    /// Found by unicast M-SEARCH, and looked for again when it goes away.
+    Discover(Discovery),
+}
+
+/// Settings for a single remote DLNA server announced by this process.
+pub struct ServerConfig {
+    pub source: ServerSource,
+    /// Interfaces the server is announced on, every one when empty.
+    pub announce_iface: Vec<String>,
     pub period: time::Duration,
     pub proxy: Option<SocketAddr>,
-    pub broadcast_iface: Option<String>,
-    pub verbose: log::LevelFilter,
-    pub wait: Option<time::Duration>,
+    /// Port of the GENA callback listener of the proxy, any free one when unset.
+    pub event_port: Option<u16>,
     pub connect_timeout: time::Duration,
     pub proxy_timeout: time::Duration,
     pub stream_timeout: time::Duration,
+    pub cache_ttl: time::Duration,
+    pub cache_max_stale: time::Duration,
+    pub down_after_failures: u32,
+    pub up_after_successes: u32,
+    /// Rules applied to the proxy's responses before the origin's URLs are rewritten.
+    pub rewrite: Vec<RewriteRule>,
+    pub unreachable: Unreachable,
+    /// Whether requests to the server only accept uncompressed responses.
+    pub identity_encoding: bool,
+}
+
+/// SSDP traffic reflected between local interfaces.
+pub struct ReflectorConfig {
+    pub interfaces: Vec<String>,
+    /// Device types to reflect, every device when empty.
+    pub device_types: Vec<String>,
+}
+
+pub struct Config {
+    pub servers: Vec<ServerConfig>,
+    pub reflector: Option<ReflectorConfig>,
+    pub interfaces: InterfaceSelection,
+    pub ip_version: IpVersion,
+    pub bind_to_device: bool,
+    pub unicast_targets: Vec<String>,
+    pub learn_unicast_targets: bool,
+    pub verbose: log::LevelFilter,
+    pub wait: Option<time::Duration>,
+    pub boot_id_file: Option<PathBuf>,
 }
 
 impl TryFrom<CommandLineConf> for Config {
@@ -50,62 +211,80 @@ fn get_config(args: CommandLineConf) -> Result<Config> {
         .map(|file| fs::read_to_string(file).context("Could not open/read config file."))
         .transpose()?;
 
-    let (
-        description_url,
-        period,
-        proxy,
-        broadcast_iface,
-        verbose,
-        wait,
-        connect_timeout,
-        proxy_timeout,
-        stream_timeout,
-    ) = if let Some(config_file) = config_as_file {
+    let (servers, reflector, interfaces, ip_version, bind_to_device, unicast_targets, learn_unicast_targets, verbose, wait, boot_id_file) = if let Some(config_file) = config_as_file {
         let raw_config: RawConfig =
             toml::from_str(&config_file).context("failed to parse config file.")?;
 
-        let desc_url = raw_config
-            .description_url
-            .ok_or(anyhow!("Missing description URL"))
-            .and_then(|s| Url::parse(&s).context("Bad description URL."))?;
-
-        let period = raw_config.period;
+        let servers = servers_from_raw(&raw_config)?;
 
-        let proxy: Option<SocketAddr> = raw_config
-            .proxy
-            .as_deref()
-            .map(str::parse)
-            .transpose()
-            .context("Bad proxy address")?;
+        let reflector = match raw_config.reflector {
+            Some(reflector) => reflector_config(reflector.interfaces, reflector.device_types.unwrap_or_default())?,
+            None => None,
+        };
 
         (
-            desc_url,
-            period,
-            proxy,
-            raw_config.iface,
+            servers,
+            reflector,
+            interface_selection(
+                raw_config.iface.map(Vec::from).unwrap_or_default(),
+                raw_config.exclude_iface,
+            )?,
+            raw_config.ip_version,
+            raw_config.bind_to_device.unwrap_or(false),
+            raw_config.unicast_targets.unwrap_or_default(),
+            raw_config.learn_unicast_targets.unwrap_or(false),
             raw_config.verbose,
             raw_config.wait,
-            raw_config.connect_timeout,
-            raw_config.proxy_timeout,
-            raw_config.stream_timeout,
+            raw_config.boot_id_file,
         )
     } else {
+        let source = match (args.description_url, args.discover) {
+            (Some(url), _) => Some(ServerSource::Url(url)),
+            (None, Some(target)) => {
+                Some(ServerSource::Discover(Discovery::new(&target, args.device_type, args.friendly_name, args.udn)?))
+            }
+            (None, None) => None,
+        };
+
+        let reflector = reflector_config(args.reflect, args.reflect_device_type)?;
+
+        if source.is_none() && reflector.is_none() {
+            bail!("Missing description URL");
+        }
+
+        let timings = RawServerConfig {
+            event_port: args.event_port,
+            period: args.interval,
+            connect_timeout: args.connect_timeout,
+            proxy_timeout: args.proxy_timeout,
+            stream_timeout: args.stream_timeout,
+            cache_ttl: args.cache_ttl,
+            cache_max_stale: args.cache_max_stale,
+            down_after_failures: args.down_after_failures,
+            up_after_successes: args.up_after_successes,
+            ..Default::default()
+        };
+
+        let servers = source
+            .map(|source| server_config(source, args.proxy, &timings))
+            .transpose()?
+            .into_iter()
+            .collect();
+
         (
-            args.description_url
-                .ok_or(anyhow!("Missing description URL"))?,
-            args.interval,
-            args.proxy,
-            args.iface,
+            servers,
+            reflector,
+            interface_selection(args.iface, (!args.exclude_iface.is_empty()).then_some(args.exclude_iface))?,
+            args.ip_version,
+            args.bind_to_device,
+            args.unicast_target,
+            args.learn_unicast_targets,
             Some(args.verbose),
             args.wait,
-            args.connect_timeout,
-            args.proxy_timeout,
-            args.stream_timeout,
+            args.boot_id_file,
         )
     };
 
-    let period = period.or(Some(895)).map(time::Duration::from_secs).unwrap();
-
     let verbose = verbose.map_or(log::LevelFilter::Warn, |v| match v {
         0 => log::LevelFilter::Warn,
         1 => log::LevelFilter::Info,
@@ -113,37 +292,244 @@ fn get_config(args: CommandLineConf) -> Result<Config> {
         _ => log::LevelFilter::Trace,
     });
 
+    if interfaces == InterfaceSelection::Default && servers.iter().any(|s| !s.announce_iface.is_empty()) {
+        bail!("announce_iface requires the interfaces SSDP runs on to be set with iface");
+    }
+
     // Default: 30 seconds retry interval when waiting
     let wait = wait.map(time::Duration::from_secs);
 
+    Ok(Config {
+        servers,
+        reflector,
+        interfaces,
+        ip_version: ip_version.unwrap_or_default(),
+        bind_to_device,
+        unicast_targets,
+        learn_unicast_targets,
+        verbose,
+        wait,
+        boot_id_file,
+    })
+}
+
+/// Interfaces to announce on: the listed ones, or `all` of them but the excluded ones.
+/// Without either, the routing table decides.
+fn interface_selection(iface: Vec<String>, exclude_iface: Option<Vec<String>>) -> Result<InterfaceSelection> {
+    let all = iface.iter().any(|name| name == "all");
+
+    match (iface.is_empty(), exclude_iface) {
+        (true, None) => Ok(InterfaceSelection::Default),
+        (true, Some(excluded)) => Ok(InterfaceSelection::AllExcept(excluded)),
+        (false, excluded) if all => Ok(InterfaceSelection::AllExcept(excluded.unwrap_or_default())),
+        (false, None) => Ok(InterfaceSelection::Only(iface)),
+        (false, Some(_)) => bail!("exclude_iface can only be combined with iface = \"all\""),
+    }
+}
+
+/// Reflect between `interfaces`, if any.
+fn reflector_config(interfaces: Vec<String>, device_types: Vec<String>) -> Result<Option<ReflectorConfig>> {
+    match interfaces.len() {
+        0 => Ok(None),
+        1 => bail!("The reflector needs at least two interfaces to reflect between"),
+        _ => Ok(Some(ReflectorConfig {
+            interfaces,
+            device_types,
+        })),
+    }
+}
+
+/// Build the list of servers from a parsed config file.
+///
+/// Either a top-level `description_url`/`discover` (single server) or a list of
+/// `[[server]]` tables must be present, but not both. There may be none when
+/// the process only reflects SSDP traffic.
+fn servers_from_raw(raw_config: &RawConfig) -> Result<Vec<ServerConfig>> {
+    let defaults = &raw_config.defaults;
+
+    let servers = match (server_source(defaults)?, &raw_config.server) {
+        (Some(_), Some(_)) => {
+            bail!("Use either a top-level description_url or [[server]] entries, not both")
+        }
+        (None, None) if raw_config.reflector.is_some() => Vec::new(),
+        (None, None) => bail!("Missing description URL"),
+        (None, Some(entries)) if entries.is_empty() => bail!("Missing description URL"),
+
+        (Some(source), None) => vec![server_config(
+            source,
+            parse_proxy(defaults.proxy.as_deref())?,
+            defaults,
+        )?],
+
+        (None, Some(entries)) => entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                let source = server_source(entry)?
+                    .ok_or(anyhow!("Missing description URL in [[server]] #{}", i + 1))?;
+
+                server_config(
+                    source,
+                    parse_proxy(entry.proxy.as_deref())?,
+                    &entry.inherit(defaults),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?,
+    };
+
+    // Each proxy needs its own listening socket.
+    let mut proxies = HashSet::new();
+    for proxy in servers.iter().filter_map(|s| s.proxy) {
+        if !proxies.insert(proxy) {
+            bail!("Proxy address {} is used by more than one server", proxy);
+        }
+    }
+
+    let mut event_ports = HashSet::new();
+    for port in servers.iter().filter_map(|s| s.event_port) {
+        if !event_ports.insert(port) {
+            bail!("Event port {} is used by more than one server", port);
+        }
+    }
+
+    Ok(servers)
+}
+
+/// Where the server configured by `raw` is, if it says so.
+fn server_source(raw: &RawServerConfig) -> Result<Option<ServerSource>> {
+    if raw.discover.is_none() && (raw.friendly_name.is_some() || raw.udn.is_some() || raw.device_type.is_some()) {
+        bail!("device_type, friendly_name and udn can only be used with discover");
+    }
+
+    match (&raw.description_url, &raw.discover) {
+        (Some(_), Some(_)) => bail!("Use either description_url or discover, not both"),
+        (Some(url), None) => Ok(Some(ServerSource::Url(parse_description_url(url)?))),
+        (None, Some(target)) => Ok(Some(ServerSource::Discover(Discovery::new(
+            target,
+            raw.device_type.clone(),
+            raw.friendly_name.clone(),
+            raw.udn.clone(),
+        )?))),
+        (None, None) => Ok(None),
+    }
+}
+
+fn parse_description_url(url: &str) -> Result<Url> {
+    Url::parse(url).context("Bad description URL.")
+}
+
+fn parse_proxy(proxy: Option<&str>) -> Result<Option<SocketAddr>> {
+    proxy
+        .map(str::parse)
+        .transpose()
+        .context("Bad proxy address")
+}
+
+/// Apply defaults to the timings of a server.
+fn server_config(
+    source: ServerSource,
+    proxy: Option<SocketAddr>,
+    timings: &RawServerConfig,
+) -> Result<ServerConfig> {
+    let period = timings.period.or(Some(895)).map(time::Duration::from_secs).unwrap();
+
     // Default: 2 seconds HTTP connect timeout
-    let connect_timeout = connect_timeout
+    let connect_timeout = timings
+        .connect_timeout
         .map(time::Duration::from_secs)
         .unwrap_or(time::Duration::from_secs(2));
 
     // Default: 10 seconds TCP proxy connect timeout
-    let proxy_timeout = proxy_timeout
+    let proxy_timeout = timings
+        .proxy_timeout
         .map(time::Duration::from_secs)
         .unwrap_or(time::Duration::from_secs(10));
 
     // Default: 300 seconds (5 minutes) TCP stream read/write timeout
-    let stream_timeout = stream_timeout
+    let stream_timeout = timings
+        .stream_timeout
         .map(time::Duration::from_secs)
         .unwrap_or(time::Duration::from_secs(300));
 
-    Ok(Config {
-        description_url,
-        proxy,
+    // Default: 60 seconds before the cached description is revalidated with the origin.
+    // Health checks run as often, so 0 would poll the origin back-to-back.
+    if timings.cache_ttl == Some(0) {
+        bail!("cache_ttl must be at least 1 second");
+    }
+
+    let cache_ttl = timings
+        .cache_ttl
+        .map(time::Duration::from_secs)
+        .unwrap_or(time::Duration::from_secs(60));
+
+    // Default: 600 seconds (10 minutes) during which a stale description is still served
+    let cache_max_stale = timings
+        .cache_max_stale
+        .map(time::Duration::from_secs)
+        .unwrap_or(time::Duration::from_secs(600));
+
+    // Default: origin declared down after 3 failed checks, up again after 1 successful one
+    let down_after_failures = timings.down_after_failures.unwrap_or(3);
+    let up_after_successes = timings.up_after_successes.unwrap_or(1);
+
+    let rewrite = timings
+        .rewrite
+        .iter()
+        .flatten()
+        .map(|rule| {
+            RewriteRule::new(
+                &rule.pattern,
+                &rule.replace,
+                rule.regex.unwrap_or(false),
+                rule.content_type.clone().map(Vec::from).unwrap_or_default(),
+                rule.path.clone(),
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Default: 502 when the server refuses connections, 503 with a Retry-After of 30 seconds on timeouts
+    let unreachable = match &timings.unreachable {
+        Some(raw) => {
+            let defaults = Unreachable::default();
+            Unreachable {
+                refused: error_status(raw.refused.as_ref(), defaults.refused)?,
+                timeout: error_status(raw.timeout.as_ref(), defaults.timeout)?,
+                retry_after: raw.retry_after.map_or(defaults.retry_after, |seconds| (seconds > 0).then_some(seconds)),
+            }
+        }
+        None => Unreachable::default(),
+    };
+
+    Ok(ServerConfig {
+        source,
+        announce_iface: timings.announce_iface.clone().map(Vec::from).unwrap_or_default(),
         period,
-        broadcast_iface,
-        verbose,
-        wait,
+        proxy,
+        event_port: timings.event_port,
         connect_timeout,
         proxy_timeout,
         stream_timeout,
+        cache_ttl,
+        cache_max_stale,
+        down_after_failures,
+        up_after_successes,
+        rewrite,
+        unreachable,
+        identity_encoding: timings.identity_encoding.unwrap_or(false),
     })
 }
 
+/// The status of an `[unreachable]` setting, None for `"close"`.
+fn error_status(raw: Option<&RawErrorStatus>, default: Option<u16>) -> Result<Option<u16>> {
+    match raw {
+        None => Ok(default),
+        Some(RawErrorStatus::Status(status)) if (500..600).contains(status) => Ok(Some(*status)),
+        Some(RawErrorStatus::Status(status)) => bail!("Unreachable server status must be a 5xx one, not {}", status),
+        Some(RawErrorStatus::Close(close)) if close == "close" => Ok(None),
+        Some(RawErrorStatus::Close(other)) => bail!("Unreachable server status must be a number or \"close\", not '{}'", other),
+    }
+}
+
 pub fn sockaddr_from_url(url: &Url) -> Result<SocketAddr> {
     let host = url
         .host()
@@ -165,3 +551,418 @@ pub fn sockaddr_from_url(url: &Url) -> Result<SocketAddr> {
         .copied()
         .ok_or_else(|| anyhow!("No valid socket address resolved for URL: {}", url))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn servers(toml: &str) -> Result<Vec<ServerConfig>> {
+        let raw: RawConfig = toml::from_str(toml).unwrap();
+        servers_from_raw(&raw)
+    }
+
+    fn description_url(server: &ServerConfig) -> &str {
+        match &server.source {
+            ServerSource::Url(url) => url.as_str(),
+            ServerSource::Discover(_) => panic!("server is discovered"),
+        }
+    }
+
+    #[test]
+    fn test_single_top_level_server() {
+        let servers = servers(
+            r#"
+description_url = "http://192.168.1.100:8200/rootDesc.xml"
+period = 60
+proxy = "192.168.1.50:8200"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(servers.len(), 1);
+        assert_eq!(description_url(&servers[0]), "http://192.168.1.100:8200/rootDesc.xml");
+        assert_eq!(servers[0].period.as_secs(), 60);
+        assert_eq!(servers[0].proxy, Some("192.168.1.50:8200".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_server_array_inherits_top_level_defaults() {
+        let servers = servers(
+            r#"
+period = 120
+connect_timeout = 5
+
+[[server]]
+description_url = "http://10.0.0.1:8200/rootDesc.xml"
+proxy = "192.168.1.50:8200"
+
+[[server]]
+description_url = "http://10.0.0.2:1338/rootDesc.xml"
+proxy = "192.168.1.50:1338"
+period = 30
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0].period.as_secs(), 120);
+        assert_eq!(servers[0].connect_timeout.as_secs(), 5);
+        assert_eq!(servers[1].period.as_secs(), 30);
+        assert_eq!(servers[1].connect_timeout.as_secs(), 5);
+        assert_eq!(servers[1].proxy, Some("192.168.1.50:1338".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_server_array_does_not_inherit_proxy() {
+        let servers = servers(
+            r#"
+proxy = "192.168.1.50:8200"
+cache_ttl = 30
+
+[[server]]
+description_url = "http://10.0.0.1:8200/rootDesc.xml"
+"#,
+        )
+        .unwrap();
+
+        assert!(servers[0].proxy.is_none());
+        assert_eq!(servers[0].cache_ttl.as_secs(), 30);
+    }
+
+    #[test]
+    fn test_zero_cache_ttl() {
+        let result = servers(
+            r#"
+[[server]]
+description_url = "http://10.0.0.1:8200/rootDesc.xml"
+cache_ttl = 0
+"#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_server_defaults() {
+        let servers = servers(
+            r#"
+[[server]]
+description_url = "http://10.0.0.1:8200/rootDesc.xml"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(servers[0].period.as_secs(), 895);
+        assert_eq!(servers[0].connect_timeout.as_secs(), 2);
+        assert_eq!(servers[0].proxy_timeout.as_secs(), 10);
+        assert_eq!(servers[0].stream_timeout.as_secs(), 300);
+        assert_eq!(servers[0].cache_ttl.as_secs(), 60);
+        assert_eq!(servers[0].cache_max_stale.as_secs(), 600);
+        assert_eq!(servers[0].down_after_failures, 3);
+        assert_eq!(servers[0].up_after_successes, 1);
+        assert!(servers[0].proxy.is_none());
+    }
+
+    #[test]
+    fn test_top_level_url_and_server_array_conflict() {
+        let result = servers(
+            r#"
+description_url = "http://10.0.0.1:8200/rootDesc.xml"
+
+[[server]]
+description_url = "http://10.0.0.2:8200/rootDesc.xml"
+"#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_missing_description_url() {
+        assert!(servers("period = 30").is_err());
+        assert!(servers("[[server]]\nperiod = 30").is_err());
+    }
+
+    #[test]
+    fn test_discovered_servers() {
+        let servers = servers(
+            r#"
+[[server]]
+discover = "10.8.0.5"
+friendly_name = "NAS"
+
+[[server]]
+discover = "10.8.0.0/28"
+udn = "uuid:4d696e69-444c-164e-9d41-b827eb5a31b6"
+"#,
+        )
+        .unwrap();
+
+        let ServerSource::Discover(discovery) = &servers[1].source else {
+            panic!("server should be discovered");
+        };
+        assert_eq!(discovery.target(), "10.8.0.0/28");
+    }
+
+    #[test]
+    fn test_discover_conflicts() {
+        assert!(servers(
+            r#"
+description_url = "http://10.0.0.1:8200/rootDesc.xml"
+discover = "10.0.0.1"
+"#
+        )
+        .is_err());
+
+        assert!(servers(
+            r#"
+description_url = "http://10.0.0.1:8200/rootDesc.xml"
+friendly_name = "NAS"
+"#
+        )
+        .is_err());
+
+        assert!(servers("discover = \"nas.local\"").is_err());
+        assert!(servers("discover = \"10.0.0.1\"\ndevice_type = \"MediaRenderer\"").is_err());
+    }
+
+    #[test]
+    fn test_announce_iface() {
+        let servers = servers(
+            r#"
+iface = ["eth0", "tun0"]
+
+[[server]]
+description_url = "http://10.8.0.2:8200/rootDesc.xml"
+proxy = "192.168.1.50:8200"
+announce_iface = "eth0"
+
+[[server]]
+discover = "192.168.1.30"
+device_type = "urn:schemas-upnp-org:device:MediaRenderer:1"
+proxy = "10.8.0.1:49152"
+announce_iface = ["tun0"]
+
+[[server]]
+description_url = "http://10.8.0.3:8200/rootDesc.xml"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(servers[0].announce_iface, ["eth0"]);
+        assert_eq!(servers[1].announce_iface, ["tun0"]);
+        assert!(servers[2].announce_iface.is_empty());
+    }
+
+    #[test]
+    fn test_reflector_only() {
+        let servers = servers(
+            r#"
+[reflector]
+interfaces = ["eth0.10", "eth0.20"]
+device_types = ["MediaRenderer"]
+"#,
+        )
+        .unwrap();
+        assert!(servers.is_empty());
+
+        assert!(reflector_config(vec!["eth0.10".into()], vec![]).is_err());
+        assert!(reflector_config(vec![], vec![]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_duplicate_proxy_address() {
+        let result = servers(
+            r#"
+[[server]]
+description_url = "http://10.0.0.1:8200/rootDesc.xml"
+proxy = "192.168.1.50:8200"
+
+[[server]]
+description_url = "http://10.0.0.2:8200/rootDesc.xml"
+proxy = "192.168.1.50:8200"
+"#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_event_port() {
+        let configured = servers(
+            r#"
+event_port = 8300
+
+[[server]]
+description_url = "http://10.0.0.1:8200/rootDesc.xml"
+proxy = "192.168.1.50:8200"
+event_port = 8201
+
+[[server]]
+description_url = "http://10.0.0.2:8200/rootDesc.xml"
+proxy = "192.168.1.50:8202"
+"#,
+        )
+        .unwrap();
+        assert_eq!(configured[0].event_port, Some(8201));
+        // Not inherited
+        assert_eq!(configured[1].event_port, None);
+
+        let result = servers(
+            r#"
+[[server]]
+description_url = "http://10.0.0.1:8200/rootDesc.xml"
+proxy = "192.168.1.50:8200"
+event_port = 8201
+
+[[server]]
+description_url = "http://10.0.0.2:8200/rootDesc.xml"
+proxy = "192.168.1.50:8202"
+event_port = 8201
+"#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_rewrite_rules() {
+        let configured = servers(
+            r#"
+[[rewrite]]
+match = "http://10.0.0.1:8201"
+replace = "http://192.168.1.50:8201"
+
+[[rewrite]]
+match = 'https://media\.example\.com(:\d+)?'
+replace = "http://10.0.0.1:8200"
+regex = true
+content_type = ["text/xml", "application/xml"]
+path = "/ctl/"
+
+[[server]]
+description_url = "http://10.0.0.1:8200/rootDesc.xml"
+
+[[server]]
+description_url = "http://10.0.0.2:8200/rootDesc.xml"
+
+[[server.rewrite]]
+match = "/dlna/"
+replace = "/"
+"#,
+        )
+        .unwrap();
+        assert_eq!(configured[0].rewrite.len(), 2);
+        // Replaces the top-level rules
+        assert_eq!(configured[1].rewrite.len(), 1);
+
+        let result = servers(
+            r#"
+description_url = "http://10.0.0.1:8200/rootDesc.xml"
+
+[[rewrite]]
+match = "("
+replace = ""
+regex = true
+"#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let configured = servers(
+            r#"
+[unreachable]
+refused = 503
+retry_after = 0
+
+[[server]]
+description_url = "http://10.0.0.1:8200/rootDesc.xml"
+
+[[server]]
+description_url = "http://10.0.0.2:8200/rootDesc.xml"
+
+[server.unreachable]
+timeout = "close"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            configured[0].unreachable,
+            Unreachable { refused: Some(503), timeout: Some(503), retry_after: None }
+        );
+        // Replaces the top-level table
+        assert_eq!(
+            configured[1].unreachable,
+            Unreachable { refused: Some(502), timeout: None, retry_after: Some(30) }
+        );
+
+        assert!(!configured[0].identity_encoding);
+
+        for bad in ["refused = 404", "timeout = \"drop\""] {
+            let result = servers(&format!(
+                "description_url = \"http://10.0.0.1:8200/rootDesc.xml\"\n[unreachable]\n{}\n",
+                bad
+            ));
+            assert!(result.is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_identity_encoding() {
+        let configured = servers(
+            r#"
+identity_encoding = true
+
+[[server]]
+description_url = "http://10.0.0.1:8200/rootDesc.xml"
+
+[[server]]
+description_url = "http://10.0.0.2:8200/rootDesc.xml"
+identity_encoding = false
+"#,
+        )
+        .unwrap();
+        assert!(configured[0].identity_encoding);
+        assert!(!configured[1].identity_encoding);
+    }
+
+    #[test]
+    fn test_ip_version() {
+        let raw: RawConfig = toml::from_str("ip_version = \"dual\"").unwrap();
+        assert_eq!(raw.ip_version, Some(IpVersion::Dual));
+
+        let raw: RawConfig = toml::from_str("").unwrap();
+        assert_eq!(raw.ip_version, None);
+
+        assert!(toml::from_str::<RawConfig>("ip_version = \"v5\"").is_err());
+    }
+
+    #[test]
+    fn test_iface_one_or_many() {
+        let raw: RawConfig = toml::from_str("iface = \"eth0\"").unwrap();
+        assert_eq!(raw.iface.map(Vec::from), Some(vec!["eth0".to_string()]));
+
+        let raw: RawConfig = toml::from_str("iface = [\"eth0\", \"wlan0\"]").unwrap();
+        assert_eq!(raw.iface.map(Vec::from).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_interface_selection() {
+        assert_eq!(interface_selection(vec![], None).unwrap(), InterfaceSelection::Default);
+
+        assert_eq!(
+            interface_selection(vec!["eth0".into(), "wlan0".into()], None).unwrap(),
+            InterfaceSelection::Only(vec!["eth0".into(), "wlan0".into()])
+        );
+
+        assert_eq!(
+            interface_selection(vec!["all".into()], None).unwrap(),
+            InterfaceSelection::AllExcept(vec![])
+        );
+
+        assert_eq!(
+            interface_selection(vec![], Some(vec!["docker0".into()])).unwrap(),
+            InterfaceSelection::AllExcept(vec!["docker0".into()])
+        );
+
+        assert!(interface_selection(vec!["eth0".into()], Some(vec!["docker0".into()])).is_err());
+    }
+}
@@ -0,0 +1,360 @@
+use log::debug;
+
+use anyhow::{anyhow, Result};
+use quick_xml::{
+    escape::resolve_predefined_entity,
+    events::{BytesStart, BytesText, Event},
+    Reader, Writer,
+};
+use reqwest::Url;
+
+/*
+    DIDL-Lite reference: UPnP ContentDirectory:4, Annex B & C.
+    Metadata is carried XML-escaped in SOAP responses (Browse/Search `Result`,
+    AVTransport `CurrentURIMetaData`...), where a plain search & replace misses
+    URLs written with `&amp;`, percent-encoded hosts or the server's hostname.
+*/
+
+/// Rewrite the origin's URLs to the proxy's in a response body, looking into the
+/// DIDL-Lite metadata it carries, where the origin may also go by `origin_name`.
+pub fn rewrite(body: &str, origin_url_base: &str, origin_name: Option<&str>, proxy_url_base: &str) -> String {
+    let structured = if body.contains("DIDL-Lite") {
+        match UrlRewrite::new(origin_url_base, origin_name, proxy_url_base).and_then(|urls| rewrite_soap(body, &urls)) {
+            Ok(rewritten) => Some(rewritten),
+            Err(e) => {
+                debug!(target: "dlnaproxy", "Couldn't rewrite DIDL-Lite metadata, falling back to plain rewriting: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    structured.as_deref().unwrap_or(body).replace(origin_url_base, proxy_url_base)
+}
+
+/// Moves URLs pointing at the origin to the proxy.
+struct UrlRewrite {
+    origin: Url,
+    /// Hostname of the origin, e.g. from its description URL.
+    origin_name: Option<String>,
+    proxy: Url,
+}
+
+impl UrlRewrite {
+    fn new(origin_url_base: &str, origin_name: Option<&str>, proxy_url_base: &str) -> Result<Self> {
+        Ok(UrlRewrite {
+            origin: Url::parse(origin_url_base)?,
+            origin_name: origin_name.map(str::to_string),
+            proxy: Url::parse(proxy_url_base)?,
+        })
+    }
+
+    /// `value` pointing at the proxy, if it pointed at the origin: by its address, or
+    /// by its hostname, which the proxy host may not resolve. Other hosts are left
+    /// alone, even on the origin's port.
+    fn rewrite(&self, value: &str) -> Option<String> {
+        let mut url = Url::parse(value.trim()).ok()?;
+
+        let same_name = match (url.domain(), &self.origin_name) {
+            (Some(domain), Some(name)) => domain.eq_ignore_ascii_case(name),
+            _ => false,
+        };
+        let same_host = same_name || url.host_str() == self.origin.host_str();
+
+        if url.scheme() != self.origin.scheme() || url.port_or_known_default() != self.origin.port_or_known_default() || !same_host {
+            return None;
+        }
+
+        url.set_host(self.proxy.host_str()).ok()?;
+        url.set_port(self.proxy.port()).ok()?;
+
+        Some(url.into())
+    }
+}
+
+/// Rewrite the DIDL-Lite documents found in the elements of a SOAP message.
+fn rewrite_soap(body: &str, urls: &UrlRewrite) -> Result<String> {
+    rewrite_leaves(
+        body,
+        &|_, text| match text.trim_start().starts_with("<DIDL-Lite") {
+            true => rewrite_didl(text, urls).ok(),
+            false => None,
+        },
+        &|_, _| None,
+    )
+}
+
+/// Rewrite the URLs of the resources, album art, icons... of a DIDL-Lite document.
+fn rewrite_didl(didl: &str, urls: &UrlRewrite) -> Result<String> {
+    rewrite_leaves(
+        didl,
+        &|name, text| is_url_element(name).then(|| urls.rewrite(text)).flatten(),
+        &|name, value| (name == b"importUri").then(|| urls.rewrite(value)).flatten(),
+    )
+}
+
+/// Elements holding a URL, by local name: `res`, `upnp:albumArtURI`, `upnp:icon`,
+/// `upnp:lyricsURI`, Samsung's `sec:CaptionInfoEx` subtitles...
+fn is_url_element(name: &[u8]) -> bool {
+    matches!(name, b"res" | b"icon" | b"CaptionInfo" | b"CaptionInfoEx") || name.ends_with(b"URI")
+}
+
+/// Rewrite the text of leaf elements and the attribute values `rewrite_text` and
+/// `rewrite_attribute` return something for, given local names. Everything else is
+/// written back as it was read.
+fn rewrite_leaves(
+    xml: &str,
+    rewrite_text: &dyn Fn(&[u8], &str) -> Option<String>,
+    rewrite_attribute: &dyn Fn(&[u8], &str) -> Option<String>,
+) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+
+    // Events of the element being read, with its unescaped text, until it turns out to be a leaf.
+    let mut leaf: Option<(Vec<u8>, String, Vec<Event<'static>>)> = None;
+
+    loop {
+        let event = reader.read_event()?;
+
+        match event {
+            Event::Eof => break,
+            Event::Start(start) => {
+                write_pending(&mut writer, &mut leaf)?;
+
+                let start = rewrite_attributes(start, rewrite_attribute)?;
+                leaf = Some((start.local_name().as_ref().to_vec(), String::new(), vec![Event::Start(start.into_owned())]));
+            }
+            Event::Empty(start) => {
+                let start = rewrite_attributes(start, rewrite_attribute)?;
+                write_pending(&mut writer, &mut leaf)?;
+                writer.write_event(Event::Empty(start))?;
+            }
+            Event::Text(text) if leaf.is_some() => {
+                let (_, content, events) = leaf.as_mut().unwrap();
+                content.push_str(&text.xml_content()?);
+                events.push(Event::Text(text.into_owned()));
+            }
+            Event::CData(cdata) if leaf.is_some() => {
+                let (_, content, events) = leaf.as_mut().unwrap();
+                content.push_str(&cdata.xml_content()?);
+                events.push(Event::CData(cdata.into_owned()));
+            }
+            Event::GeneralRef(reference) if leaf.is_some() => {
+                let (_, content, events) = leaf.as_mut().unwrap();
+                match reference.resolve_char_ref()? {
+                    Some(ch) => content.push(ch),
+                    None => {
+                        let name = reference.xml_content()?;
+                        content.push_str(resolve_predefined_entity(&name).ok_or(anyhow!("unknown entity &{};", name))?);
+                    }
+                }
+                events.push(Event::GeneralRef(reference.into_owned()));
+            }
+            Event::End(end) => {
+                if let Some((name, content, mut events)) = leaf.take() {
+                    if let Some(rewritten) = rewrite_text(&name, &content) {
+                        // The start tag is kept, the text is replaced as a whole.
+                        events.truncate(1);
+                        events.push(Event::Text(BytesText::new(&rewritten).into_owned()));
+                    }
+                    for event in events {
+                        writer.write_event(event)?;
+                    }
+                }
+                writer.write_event(Event::End(end))?;
+            }
+            event => {
+                write_pending(&mut writer, &mut leaf)?;
+                writer.write_event(event)?;
+            }
+        }
+    }
+
+    write_pending(&mut writer, &mut leaf)?;
+
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+/// Give up on the element being read as a leaf, writing it as it was.
+fn write_pending(writer: &mut Writer<Vec<u8>>, leaf: &mut Option<(Vec<u8>, String, Vec<Event<'static>>)>) -> Result<()> {
+    if let Some((_, _, events)) = leaf.take() {
+        for event in events {
+            writer.write_event(event)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// `start` with the values `rewrite_attribute` returns something for replaced. Other
+/// attributes are kept as they were written, entities included.
+fn rewrite_attributes<'a>(
+    start: BytesStart<'a>,
+    rewrite_attribute: &dyn Fn(&[u8], &str) -> Option<String>,
+) -> Result<BytesStart<'a>> {
+    let mut new_start = BytesStart::new(String::from_utf8_lossy(start.name().as_ref()).into_owned());
+    let mut changed = false;
+
+    for attribute in start.attributes() {
+        let attribute = attribute?;
+        let value = attribute.unescape_value()?;
+        let key = String::from_utf8_lossy(attribute.key.as_ref()).into_owned();
+
+        match rewrite_attribute(attribute.key.local_name().as_ref(), &value) {
+            Some(new_value) => {
+                // A `&str` value is escaped as it is written, unlike a `&[u8]` one.
+                new_start.push_attribute((key.as_str(), new_value.as_str()));
+                changed = true;
+            }
+            // Written in double quotes, which a single-quoted value may contain raw.
+            None if attribute.value.contains(&b'"') => new_start.push_attribute((key.as_str(), value.as_ref())),
+            None => new_start.push_attribute((key.as_bytes(), attribute.value.as_ref())),
+        }
+    }
+
+    Ok(match changed {
+        true => new_start,
+        false => start,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: &str = "http://10.8.0.2:8200";
+    const PROXY: &str = "http://192.168.1.50:8200";
+
+    fn browse_response(result: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><u:BrowseResponse xmlns:u="urn:schemas-upnp-org:service:ContentDirectory:1"><Result>{}</Result><NumberReturned>1</NumberReturned><TotalMatches>1</TotalMatches><UpdateID>7</UpdateID></u:BrowseResponse></s:Body></s:Envelope>"#,
+            result
+        )
+    }
+
+    #[test]
+    fn test_url_rewrite() {
+        let urls = UrlRewrite::new(ORIGIN, Some("nas"), PROXY).unwrap();
+
+        assert_eq!(
+            urls.rewrite("http://10.8.0.2:8200/MediaItems/22.mkv?a=1&b=2").as_deref(),
+            Some("http://192.168.1.50:8200/MediaItems/22.mkv?a=1&b=2")
+        );
+        // Percent-encoded host
+        assert_eq!(
+            urls.rewrite("http://10%2E8%2E0%2E2:8200/a.jpg").as_deref(),
+            Some("http://192.168.1.50:8200/a.jpg")
+        );
+        // The origin's own hostname
+        assert_eq!(urls.rewrite("http://nas:8200/a.jpg").as_deref(), Some("http://192.168.1.50:8200/a.jpg"));
+
+        assert_eq!(urls.rewrite("http://10.8.0.3:8200/a.jpg"), None);
+        assert_eq!(urls.rewrite("http://nas/a.jpg"), None);
+        assert_eq!(urls.rewrite("http://cdn.example.com:8200/a.jpg"), None);
+        assert_eq!(urls.rewrite("https://10.8.0.2:8200/a.jpg"), None);
+        assert_eq!(urls.rewrite("not a url"), None);
+    }
+
+    #[test]
+    fn test_url_rewrite_port_80() {
+        // e.g. Jellyfin behind nginx, with album art from elsewhere
+        let urls = UrlRewrite::new("http://jellyfin.lan", Some("jellyfin.lan"), PROXY).unwrap();
+
+        assert_eq!(
+            urls.rewrite("http://jellyfin.lan/Items/1/Images/Primary").as_deref(),
+            Some("http://192.168.1.50:8200/Items/1/Images/Primary")
+        );
+        assert_eq!(urls.rewrite("http://cdn.example.com/art.jpg"), None);
+        assert_eq!(urls.rewrite("http://subtitles.example.org/a.srt"), None);
+
+        let urls = UrlRewrite::new("http://10.8.0.2", None, PROXY).unwrap();
+        assert_eq!(urls.rewrite("http://cdn.example.com/art.jpg"), None);
+    }
+
+    #[test]
+    fn test_rewrite_browse_result() {
+        let body = browse_response(
+            "&lt;DIDL-Lite xmlns=&quot;urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/&quot; xmlns:upnp=&quot;urn:schemas-upnp-org:metadata-1-0/upnp/&quot;&gt;\
+&lt;item id=&quot;64$0&quot; parentID=&quot;64&quot; restricted=&quot;1&quot;&gt;&lt;dc:title&gt;Movie&lt;/dc:title&gt;\
+&lt;upnp:albumArtURI&gt;http://nas:8200/AlbumArt/22-1.jpg&lt;/upnp:albumArtURI&gt;\
+&lt;res protocolInfo=&quot;http-get:*:video/x-matroska:*&quot;&gt;http://10.8.0.2:8200/MediaItems/22.mkv?x=1&amp;amp;y=2&lt;/res&gt;\
+&lt;/item&gt;&lt;/DIDL-Lite&gt;",
+        );
+
+        let rewritten = rewrite(&body, ORIGIN, Some("nas"), PROXY);
+
+        assert!(rewritten.contains("&lt;upnp:albumArtURI&gt;http://192.168.1.50:8200/AlbumArt/22-1.jpg&lt;/upnp:albumArtURI&gt;"));
+        assert!(rewritten.contains("http://192.168.1.50:8200/MediaItems/22.mkv?x=1&amp;amp;y=2&lt;/res&gt;"));
+        assert!(!rewritten.contains("10.8.0.2"));
+        assert!(!rewritten.contains("nas:8200"));
+        // Everything else is untouched
+        assert!(rewritten.contains("<NumberReturned>1</NumberReturned><TotalMatches>1</TotalMatches><UpdateID>7</UpdateID>"));
+        assert!(rewritten.contains("&lt;dc:title&gt;Movie&lt;/dc:title&gt;"));
+    }
+
+    #[test]
+    fn test_rewrite_cdata_result() {
+        let body = browse_response(
+            r#"<![CDATA[<DIDL-Lite><item><upnp:icon>http://10.8.0.2:8200/icon.png</upnp:icon><res importUri="http://nas:8200/import/1">http://10.8.0.2:8200/1.mp3</res></item></DIDL-Lite>]]>"#,
+        );
+
+        let rewritten = rewrite(&body, ORIGIN, Some("nas"), PROXY);
+
+        assert!(rewritten.contains("&lt;upnp:icon&gt;http://192.168.1.50:8200/icon.png&lt;/upnp:icon&gt;"));
+        assert!(rewritten.contains("importUri=&quot;http://192.168.1.50:8200/import/1&quot;"));
+        assert!(rewritten.contains("http://192.168.1.50:8200/1.mp3"));
+    }
+
+    #[test]
+    fn test_rewrite_escaped_attributes() {
+        let body = browse_response(
+            "&lt;DIDL-Lite&gt;&lt;item&gt;&lt;res importUri=&quot;http://10.8.0.2:8200/import?y=1&amp;amp;z=2&quot; \
+protocolInfo=&quot;http-get:*:audio/mpeg:DLNA.ORG_PN=&amp;lt;MP3&amp;gt;&quot;&gt;http://10.8.0.2:8200/1.mp3&lt;/res&gt;\
+&lt;/item&gt;&lt;/DIDL-Lite&gt;",
+        );
+
+        let rewritten = rewrite(&body, ORIGIN, Some("nas"), PROXY);
+
+        assert!(rewritten.contains("importUri=&quot;http://192.168.1.50:8200/import?y=1&amp;amp;z=2&quot;"));
+        assert!(rewritten.contains("protocolInfo=&quot;http-get:*:audio/mpeg:DLNA.ORG_PN=&amp;lt;MP3&amp;gt;&quot;"));
+
+        // Single-quoted, with a double quote inside
+        let start = BytesStart::from_content(r#"res importUri="http://10.8.0.2:8200/a?b=&quot;1&quot;" title='say "hi"'"#, 3);
+        let urls = UrlRewrite::new(ORIGIN, None, PROXY).unwrap();
+        let start = rewrite_attributes(start, &|_, value| urls.rewrite(value)).unwrap();
+        let mut writer = Writer::new(Vec::new());
+        writer.write_event(Event::Empty(start)).unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            r#"<res importUri="http://192.168.1.50:8200/a?b=%221%22" title="say &quot;hi&quot;"/>"#
+        );
+    }
+
+    #[test]
+    fn test_rewrite_leaves_other_urls_alone() {
+        let body = browse_response(
+            "&lt;DIDL-Lite&gt;&lt;item&gt;&lt;upnp:albumArtURI&gt;http://cdn.example.com/a.jpg&lt;/upnp:albumArtURI&gt;&lt;/item&gt;&lt;/DIDL-Lite&gt;",
+        );
+
+        assert_eq!(rewrite(&body, ORIGIN, Some("nas"), PROXY), body);
+    }
+
+    #[test]
+    fn test_rewrite_without_didl() {
+        let body = "<root><URLBase>http://10.8.0.2:8200/</URLBase></root>";
+
+        assert_eq!(rewrite(body, ORIGIN, Some("nas"), PROXY), "<root><URLBase>http://192.168.1.50:8200/</URLBase></root>");
+    }
+
+    #[test]
+    fn test_rewrite_malformed_falls_back() {
+        let body = "<Result>&lt;DIDL-Lite&gt;&lt;res&gt;http://10.8.0.2:8200/1.mp3&lt;/item&gt;</Result>";
+
+        assert_eq!(
+            rewrite(body, ORIGIN, Some("nas"), PROXY),
+            "<Result>&lt;DIDL-Lite&gt;&lt;res&gt;http://192.168.1.50:8200/1.mp3&lt;/item&gt;</Result>"
+        );
+    }
+}
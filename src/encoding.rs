@@ -0,0 +1,82 @@
+use std::io::{self, Read};
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+
+/// Undo the `Content-Encoding` of a body, codings listed in the order they were applied.
+/// Fails for unknown codings, corrupt data and bodies decoding to more than `max_size`.
+pub fn decode(content_encoding: &str, body: &[u8], max_size: usize) -> io::Result<Vec<u8>> {
+    let mut body = body.to_vec();
+
+    for coding in content_encoding.split(',').rev().map(|coding| coding.trim().to_lowercase()) {
+        body = match coding.as_str() {
+            "" | "identity" => continue,
+            "gzip" | "x-gzip" => read_limited(GzDecoder::new(&body[..]), max_size)?,
+            // Meant to be zlib-wrapped, but some servers send raw deflate data.
+            "deflate" => read_limited(ZlibDecoder::new(&body[..]), max_size)
+                .or_else(|_| read_limited(DeflateDecoder::new(&body[..]), max_size))?,
+            "br" => read_limited(brotli::Decompressor::new(&body[..], 4096), max_size)?,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("Unsupported content coding '{}'", coding),
+                ))
+            }
+        };
+    }
+
+    Ok(body)
+}
+
+fn read_limited<R: Read>(decoder: R, max_size: usize) -> io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    decoder.take(max_size as u64 + 1).read_to_end(&mut decoded)?;
+
+    if decoded.len() > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Decoded body exceeds maximum size ({} bytes)", max_size),
+        ));
+    }
+
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, write::ZlibEncoder, Compression};
+    use std::io::Write;
+
+    const XML: &[u8] = b"<root><URLBase>http://10.8.0.2:8200/</URLBase></root>";
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode("gzip", &gzip(XML), 1024).unwrap(), XML);
+        assert_eq!(decode("X-GZIP", &gzip(XML), 1024).unwrap(), XML);
+        assert_eq!(decode("identity", XML, 1024).unwrap(), XML);
+
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(XML).unwrap();
+        assert_eq!(decode("deflate", &zlib.finish().unwrap(), 1024).unwrap(), XML);
+
+        let mut br = Vec::new();
+        brotli::CompressorWriter::new(&mut br, 4096, 5, 22).write_all(XML).unwrap();
+        assert_eq!(decode("br", &br, 1024).unwrap(), XML);
+
+        // Applied gzip first, then gzip again.
+        assert_eq!(decode("gzip, gzip", &gzip(&gzip(XML)), 1024).unwrap(), XML);
+    }
+
+    #[test]
+    fn test_decode_failures() {
+        assert_eq!(decode("compress", XML, 1024).unwrap_err().kind(), io::ErrorKind::Unsupported);
+        assert!(decode("gzip", XML, 1024).is_err());
+        assert_eq!(decode("gzip", &gzip(XML), 10).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
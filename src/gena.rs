@@ -0,0 +1,637 @@
+use log::{debug, info, trace, warn};
+
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use httparse::{Request, EMPTY_HEADER};
+use rand::Rng as _;
+use reqwest::{Method, Url};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+use crate::rewrite::Rewriter;
+use crate::tcp_proxy::{
+    header_value, read_chunked_body, read_head, set_header, url_base, OriginAddr, MAX_REWRITABLE_BODY_SIZE,
+};
+
+/*
+    GENA (eventing) reference: UPnP Device Architecture 1.1, section 4.
+*/
+
+/// Path of the callback URLs given to the origin, followed by the subscription token.
+const EVENT_PATH: &str = "/dlnaproxy/events/";
+
+/// Subscriptions relayed at once, so that a misbehaving client can't grow the table forever.
+const MAX_SUBSCRIPTIONS: usize = 256;
+
+/// Used when the origin doesn't say how long a subscription lasts.
+const DEFAULT_SUBSCRIPTION_TIMEOUT: Duration = Duration::from_secs(1800);
+
+/// Subscriptions are kept a little longer than granted, in case a renewal is late.
+const EXPIRY_GRACE: Duration = Duration::from_secs(60);
+
+/// How long the origin has to send an event.
+const EVENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long each callback URL of a subscriber has to accept an event.
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long an event is tried on a subscriber's callback URLs before it is dropped.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Relays GENA events from the origin to subscribers it can't reach.
+///
+/// SUBSCRIBE requests going through the proxy get their CALLBACK replaced with a URL on
+/// this host; events the origin sends there are forwarded to the original callback URLs.
+/// The SID and SEQ of the origin are kept, since each relayed subscription maps to
+/// exactly one subscriber.
+pub struct EventRelay {
+    /// Port of the callback listener, on every address.
+    port: u16,
+    origin: OriginAddr,
+    http_client: reqwest::Client,
+    /// Relayed subscriptions, by the token in their callback URL.
+    subscriptions: Mutex<HashMap<String, Arc<Subscription>>>,
+}
+
+struct Subscription {
+    /// Where the subscriber wants its events, tried in order.
+    callbacks: Vec<Url>,
+    /// Moves events to the proxy URLs the subscriber knows, as its responses are.
+    rewriter: Rewriter,
+    state: Mutex<SubscriptionState>,
+    /// Events are delivered one at a time and in order.
+    delivery: tokio::sync::Mutex<()>,
+}
+
+struct SubscriptionState {
+    /// Known once the origin answered the SUBSCRIBE, or sent the first event.
+    sid: Option<String>,
+    last_seq: Option<u32>,
+    expires: Instant,
+}
+
+/// What the response to a request forwarded to the origin means for the relay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventRequest {
+    None,
+    /// A new subscription, by token.
+    Subscribe(String),
+    /// A renewal, by SID.
+    Renew(String),
+    Unsubscribe(String),
+}
+
+impl EventRelay {
+    /// Start listening for the origin's events on `port`, any port when not set.
+    pub async fn start(port: Option<u16>, origin: OriginAddr) -> Result<Arc<Self>> {
+        // The origin reaches us on the address of our connection to it, whichever it is.
+        let address: SocketAddr = match origin.get() {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, port.unwrap_or(0)).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, port.unwrap_or(0)).into(),
+        };
+
+        let listener = TcpListener::bind(address)
+            .await
+            .with_context(|| format!("Failed to bind GENA callback listener to {}", address))?;
+
+        let relay = Arc::new(EventRelay::new(listener.local_addr()?.port(), origin)?);
+
+        info!(target: "dlnaproxy", "Relaying GENA events through port {}", relay.port);
+
+        tokio::spawn(callback_loop(listener, relay.clone()));
+
+        Ok(relay)
+    }
+
+    fn new(port: u16, origin: OriginAddr) -> Result<Self> {
+        Ok(EventRelay {
+            port,
+            origin,
+            http_client: reqwest::Client::builder()
+                .timeout(CALLBACK_TIMEOUT)
+                .build()
+                .context("Failed to build HTTP client")?,
+            subscriptions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Rewrite the head of a request going to the origin, which we reach from `local_addr`.
+    ///
+    /// New subscriptions get a callback URL on this host; renewals and cancellations are
+    /// forwarded as they are, their response tells the outcome.
+    pub fn intercept(&self, head: String, local_addr: SocketAddr, rewriter: &Rewriter) -> (String, EventRequest) {
+        let method = head.split(' ').next().unwrap_or_default();
+
+        match method {
+            "SUBSCRIBE" => {
+                if let Some(sid) = header_value(&head, "SID") {
+                    let sid = sid.to_string();
+                    return (head, EventRequest::Renew(sid));
+                }
+
+                let Some(callbacks) = header_value(&head, "CALLBACK").map(parse_callbacks) else {
+                    return (head, EventRequest::None);
+                };
+
+                if callbacks.is_empty() {
+                    return (head, EventRequest::None);
+                }
+
+                let Some(token) = self.add(callbacks, rewriter) else {
+                    warn!(target: "dlnaproxy", "Too many event subscriptions, not relaying a new one.");
+                    return (head, EventRequest::None);
+                };
+
+                let callback = format!("<http://{}{}{}>", SocketAddr::new(local_addr.ip(), self.port), EVENT_PATH, token);
+                trace!(target: "dlnaproxy", "Replacing SUBSCRIBE callback with {}", callback);
+
+                (set_header(&head, "CALLBACK", &callback), EventRequest::Subscribe(token))
+            }
+            "UNSUBSCRIBE" => match header_value(&head, "SID") {
+                Some(sid) => {
+                    let sid = sid.to_string();
+                    (head, EventRequest::Unsubscribe(sid))
+                }
+                None => (head, EventRequest::None),
+            },
+            _ => (head, EventRequest::None),
+        }
+    }
+
+    /// Update the subscriptions from the origin's response to a request `intercept` flagged.
+    pub fn on_response(&self, request: EventRequest, status: u16, head: &str) {
+        let success = (200..300).contains(&status);
+        let sid = header_value(head, "SID").map(str::to_string);
+        let expires = Instant::now() + parse_timeout(header_value(head, "TIMEOUT")) + EXPIRY_GRACE;
+
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+
+        match request {
+            EventRequest::None => {}
+            EventRequest::Subscribe(token) if success => {
+                if let Some(subscription) = subscriptions.get(&token) {
+                    let mut state = subscription.state.lock().unwrap();
+                    let callbacks: Vec<&str> = subscription.callbacks.iter().map(Url::as_str).collect();
+                    debug!(target: "dlnaproxy", "Relaying events of subscription {} to {}", sid.as_deref().unwrap_or("?"), callbacks.join(", "));
+
+                    state.sid = sid.or(state.sid.take());
+                    state.expires = expires;
+                }
+            }
+            EventRequest::Subscribe(token) => {
+                debug!(target: "dlnaproxy", "Origin refused subscription with status {}", status);
+                subscriptions.remove(&token);
+            }
+            EventRequest::Renew(sid) => {
+                let found = subscriptions
+                    .iter()
+                    .find(|(_, s)| s.state.lock().unwrap().sid.as_deref() == Some(sid.as_str()))
+                    .map(|(token, s)| (token.clone(), s.clone()));
+
+                if let Some((token, subscription)) = found {
+                    if success {
+                        subscription.state.lock().unwrap().expires = expires;
+                    } else {
+                        debug!(target: "dlnaproxy", "Origin refused to renew subscription {} with status {}", sid, status);
+                        subscriptions.remove(&token);
+                    }
+                }
+            }
+            EventRequest::Unsubscribe(sid) => {
+                subscriptions.retain(|_, s| s.state.lock().unwrap().sid.as_deref() != Some(sid.as_str()));
+            }
+        }
+    }
+
+    /// Register a subscription, returning its token.
+    fn add(&self, callbacks: Vec<Url>, rewriter: &Rewriter) -> Option<String> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+
+        let now = Instant::now();
+        subscriptions.retain(|_, s| s.state.lock().unwrap().expires > now);
+
+        if subscriptions.len() >= MAX_SUBSCRIPTIONS {
+            return None;
+        }
+
+        let token = format!("{:016x}", rand::rng().random::<u64>());
+
+        subscriptions.insert(
+            token.clone(),
+            Arc::new(Subscription {
+                callbacks,
+                rewriter: rewriter.clone(),
+                state: Mutex::new(SubscriptionState {
+                    sid: None,
+                    last_seq: None,
+                    // Until the origin answers.
+                    expires: now + EVENT_TIMEOUT + EXPIRY_GRACE,
+                }),
+                delivery: tokio::sync::Mutex::new(()),
+            }),
+        );
+
+        Some(token)
+    }
+
+    /// The subscription an event from the origin is for, if it must be delivered.
+    ///
+    /// Err is the status to answer the origin with.
+    fn accept_event(&self, token: &str, sid: &str, seq: u32) -> Result<Option<Arc<Subscription>>, u16> {
+        let subscriptions = self.subscriptions.lock().unwrap();
+
+        // 412 Precondition Failed tells the origin to drop the subscription.
+        let subscription = subscriptions.get(token).ok_or(412u16)?;
+        let mut state = subscription.state.lock().unwrap();
+
+        match &state.sid {
+            Some(known) if known != sid => return Err(412),
+            Some(_) => {}
+            // The initial event may come before the response to SUBSCRIBE.
+            None => state.sid = Some(sid.to_string()),
+        }
+
+        // SEQ starts at 0 and wraps to 1, anything else not greater was seen already.
+        if seq != 0 && state.last_seq.is_some_and(|last| last >= seq && last != u32::MAX) {
+            trace!(target: "dlnaproxy", "Ignoring duplicate event {} of subscription {}", seq, sid);
+            return Ok(None);
+        }
+
+        state.last_seq = Some(seq);
+
+        Ok(Some(subscription.clone()))
+    }
+
+    /// An event's body for the subscriber, moved to the proxy like the origin's responses.
+    fn rewrite_event(&self, subscription: &Subscription, head: &str, body: &[u8]) -> Vec<u8> {
+        // The origin may have moved since the subscription was made.
+        let mut rewriter = subscription.rewriter.clone();
+        rewriter.origin_url_base = url_base(self.origin.get());
+
+        let content_type = header_value(head, "CONTENT-TYPE").unwrap_or("text/xml");
+        rewriter.rewrite(body, content_type, None)
+    }
+
+    /// Forward an event to the first callback URL of the subscriber that takes it.
+    async fn deliver(&self, subscription: Arc<Subscription>, head: String, body: Vec<u8>) {
+        let _ordered = subscription.delivery.lock().await;
+
+        // Waiting for earlier events doesn't count, only trying this one.
+        if timeout(DELIVERY_TIMEOUT, self.try_callbacks(&subscription, &head, body)).await.is_err() {
+            debug!(target: "dlnaproxy", "Gave up delivering event {} after {:?}", header_value(&head, "SEQ").unwrap_or("?"), DELIVERY_TIMEOUT);
+        }
+    }
+
+    async fn try_callbacks(&self, subscription: &Subscription, head: &str, body: Vec<u8>) {
+        let body = self.rewrite_event(subscription, head, &body);
+
+        let method = Method::from_bytes(b"NOTIFY").expect("NOTIFY is a valid method");
+
+        for callback in &subscription.callbacks {
+            let mut request = self.http_client.request(method.clone(), callback.clone());
+
+            for name in ["CONTENT-TYPE", "NT", "NTS", "SID", "SEQ"] {
+                if let Some(value) = header_value(head, name) {
+                    request = request.header(name, value);
+                }
+            }
+
+            match request.body(body.clone()).send().await {
+                Ok(response) if response.status().is_success() => {
+                    trace!(target: "dlnaproxy", "Delivered event {} to {}", header_value(head, "SEQ").unwrap_or("?"), callback);
+                    return;
+                }
+                Ok(response) => {
+                    debug!(target: "dlnaproxy", "Subscriber {} refused event with status {}", callback, response.status());
+                }
+                Err(e) => debug!(target: "dlnaproxy", "Couldn't deliver event to {}: {}", callback, e),
+            }
+        }
+    }
+}
+
+/// Accept event messages from the origin.
+async fn callback_loop(listener: TcpListener, relay: Arc<EventRelay>) {
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!(target: "dlnaproxy", "Failed to accept GENA callback connection: {}", e);
+                continue;
+            }
+        };
+
+        let relay = relay.clone();
+
+        tokio::spawn(async move {
+            // Only receiving the event is timed, delivering it has its own timeouts.
+            match timeout(EVENT_TIMEOUT, handle_event(stream, &relay)).await {
+                Ok(Ok(Some((subscription, head, body)))) => relay.deliver(subscription, head, body).await,
+                Ok(Ok(None)) => {}
+                Ok(Err(e)) => debug!(target: "dlnaproxy", "Bad event message from {}: {}", peer_addr, e),
+                Err(_) => debug!(target: "dlnaproxy", "Timeout reading event message from {}", peer_addr),
+            }
+        });
+    }
+}
+
+/// An event the origin sent for a subscription, with its head and body, once answered.
+type Event = (Arc<Subscription>, String, Vec<u8>);
+
+/// Receive an event message and answer it, returning the event if it is to be delivered.
+async fn handle_event(stream: TcpStream, relay: &EventRelay) -> Result<Option<Event>> {
+    let mut reader = BufReader::new(stream);
+
+    let head = read_head(&mut reader).await?;
+    if head.is_empty() {
+        return Ok(None);
+    }
+
+    let head = String::from_utf8_lossy(&head).into_owned();
+
+    let (token, sid, seq) = match parse_event(&head) {
+        Ok(event) => event,
+        Err(e) => {
+            respond(reader.get_mut(), 400, "Bad Request").await?;
+            return Err(e);
+        }
+    };
+
+    let body = if header_value(&head, "TRANSFER-ENCODING").is_some_and(|te| te.eq_ignore_ascii_case("chunked")) {
+        read_chunked_body(&mut reader, MAX_REWRITABLE_BODY_SIZE).await?
+    } else {
+        let length: usize = header_value(&head, "CONTENT-LENGTH")
+            .and_then(|length| length.parse().ok())
+            .unwrap_or(0);
+
+        if length > MAX_REWRITABLE_BODY_SIZE {
+            respond(reader.get_mut(), 413, "Payload Too Large").await?;
+            bail!("event of {} bytes is too large", length);
+        }
+
+        let mut body = vec![0u8; length];
+        reader.read_exact(&mut body).await?;
+        body
+    };
+
+    match relay.accept_event(&token, &sid, seq) {
+        Ok(subscription) => {
+            // Answer right away, the origin shouldn't wait for the subscriber.
+            respond(reader.get_mut(), 200, "OK").await?;
+
+            Ok(subscription.map(|subscription| (subscription, head, body)))
+        }
+        Err(status) => {
+            debug!(target: "dlnaproxy", "Rejecting event for unknown subscription {}", sid);
+            respond(reader.get_mut(), status, "Precondition Failed").await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Token, SID and SEQ of an event message.
+fn parse_event(head: &str) -> Result<(String, String, u32)> {
+    let mut headers = [EMPTY_HEADER; 32];
+    let mut request = Request::new(&mut headers);
+
+    request.parse(head.as_bytes()).context("Not an HTTP request")?;
+
+    if request.method != Some("NOTIFY") {
+        bail!("unexpected method {:?}", request.method);
+    }
+
+    let token = request
+        .path
+        .and_then(|path| path.strip_prefix(EVENT_PATH))
+        .ok_or(anyhow!("unexpected path {:?}", request.path))?;
+
+    let sid = header_value(head, "SID").ok_or(anyhow!("no SID"))?;
+    let seq = header_value(head, "SEQ")
+        .ok_or(anyhow!("no SEQ"))?
+        .parse()
+        .context("bad SEQ")?;
+
+    Ok((token.to_string(), sid.to_string(), seq))
+}
+
+async fn respond(stream: &mut TcpStream, status: u16, reason: &str) -> Result<()> {
+    let response = format!("HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status, reason);
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// URLs of a CALLBACK header, e.g. "<http://192.168.1.20:49152/events><http://...>".
+fn parse_callbacks(value: &str) -> Vec<Url> {
+    value
+        .split('<')
+        .filter_map(|part| part.split_once('>'))
+        .filter_map(|(url, _)| Url::parse(url.trim()).ok())
+        .filter(|url| url.scheme() == "http")
+        .collect()
+}
+
+/// Duration of a TIMEOUT header, e.g. "Second-1800".
+fn parse_timeout(value: Option<&str>) -> Duration {
+    value
+        .and_then(|value| value.trim().strip_prefix("Second-"))
+        .and_then(|seconds| seconds.parse().ok())
+        .map_or(DEFAULT_SUBSCRIPTION_TIMEOUT, Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relay() -> EventRelay {
+        EventRelay::new(40000, OriginAddr::new("10.8.0.2:8200".parse().unwrap(), None)).unwrap()
+    }
+
+    const SUBSCRIBE: &str = "SUBSCRIBE /evt/ContentDirectory HTTP/1.1\r\n\
+        HOST: 192.168.1.50:8200\r\n\
+        CALLBACK: <http://192.168.1.20:49152/events>\r\n\
+        NT: upnp:event\r\n\
+        TIMEOUT: Second-1800\r\n\
+        \r\n";
+
+    fn rewriter() -> Rewriter {
+        Rewriter::new(
+            "http://10.8.0.2:8200".into(),
+            "http://192.168.1.50:8200".into(),
+            "10.8.0.2:8200".into(),
+            Arc::new(Vec::new()),
+        )
+    }
+
+    fn subscribe(relay: &EventRelay) -> (String, String) {
+        let (head, request) = relay.intercept(SUBSCRIBE.to_string(), "10.8.0.1:51000".parse().unwrap(), &rewriter());
+
+        let EventRequest::Subscribe(token) = request else {
+            panic!("SUBSCRIBE should be relayed");
+        };
+
+        (head, token)
+    }
+
+    #[test]
+    fn test_parse_callbacks() {
+        let callbacks = parse_callbacks("<http://192.168.1.20:49152/a><http://[fd00::20]:80/b> ");
+        assert_eq!(callbacks.len(), 2);
+        assert_eq!(callbacks[1].as_str(), "http://[fd00::20]/b");
+
+        assert!(parse_callbacks("http://192.168.1.20/a").is_empty());
+        assert!(parse_callbacks("<https://192.168.1.20/a>").is_empty());
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!(parse_timeout(Some("Second-300")), Duration::from_secs(300));
+        assert_eq!(parse_timeout(Some("infinite")), DEFAULT_SUBSCRIPTION_TIMEOUT);
+        assert_eq!(parse_timeout(None), DEFAULT_SUBSCRIPTION_TIMEOUT);
+    }
+
+    #[test]
+    fn test_intercept_subscribe() {
+        let relay = relay();
+        let (head, token) = subscribe(&relay);
+
+        assert_eq!(
+            header_value(&head, "CALLBACK"),
+            Some(format!("<http://10.8.0.1:40000/dlnaproxy/events/{}>", token).as_str())
+        );
+        assert_eq!(header_value(&head, "NT"), Some("upnp:event"));
+    }
+
+    #[test]
+    fn test_intercept_renew_and_unsubscribe() {
+        let relay = relay();
+        let local_addr = "10.8.0.1:51000".parse().unwrap();
+
+        let renew = "SUBSCRIBE /evt HTTP/1.1\r\nSID: uuid:abc\r\nTIMEOUT: Second-1800\r\n\r\n".to_string();
+        let (head, request) = relay.intercept(renew.clone(), local_addr, &rewriter());
+        assert_eq!(head, renew);
+        assert_eq!(request, EventRequest::Renew("uuid:abc".into()));
+
+        let cancel = "UNSUBSCRIBE /evt HTTP/1.1\r\nSID: uuid:abc\r\n\r\n".to_string();
+        let (_, request) = relay.intercept(cancel, local_addr, &rewriter());
+        assert_eq!(request, EventRequest::Unsubscribe("uuid:abc".into()));
+
+        let (_, request) = relay.intercept("GET / HTTP/1.1\r\n\r\n".into(), local_addr, &rewriter());
+        assert_eq!(request, EventRequest::None);
+    }
+
+    #[test]
+    fn test_event_sid_and_seq() {
+        let relay = relay();
+        let (_, token) = subscribe(&relay);
+
+        relay.on_response(
+            EventRequest::Subscribe(token.clone()),
+            200,
+            "HTTP/1.1 200 OK\r\nSID: uuid:abc\r\nTIMEOUT: Second-1800\r\n\r\n",
+        );
+
+        assert!(relay.accept_event(&token, "uuid:abc", 0).unwrap().is_some());
+        assert!(relay.accept_event(&token, "uuid:abc", 1).unwrap().is_some());
+        // Duplicate
+        assert!(relay.accept_event(&token, "uuid:abc", 1).unwrap().is_none());
+
+        assert_eq!(relay.accept_event(&token, "uuid:other", 2).err(), Some(412));
+        assert_eq!(relay.accept_event("unknown", "uuid:abc", 2).err(), Some(412));
+
+        relay.on_response(EventRequest::Unsubscribe("uuid:abc".into()), 200, "HTTP/1.1 200 OK\r\n\r\n");
+        assert_eq!(relay.accept_event(&token, "uuid:abc", 2).err(), Some(412));
+    }
+
+    #[test]
+    fn test_rewrite_event() {
+        let relay = relay();
+        let (_, token) = subscribe(&relay);
+        let subscription = relay.subscriptions.lock().unwrap()[&token].clone();
+
+        let head = "NOTIFY /dlnaproxy/events/0 HTTP/1.1\r\nCONTENT-TYPE: text/xml; charset=\"iso-8859-1\"\r\n\r\n";
+        // Latin-1, with DIDL-Lite metadata in LastChange
+        let body = b"<e:property><LastChange>&lt;DIDL-Lite&gt;&lt;res&gt;http://10.8.0.2:8200/M\xe9dia.mp3&lt;/res&gt;&lt;/DIDL-Lite&gt;</LastChange></e:property>";
+
+        assert_eq!(
+            relay.rewrite_event(&subscription, head, body),
+            b"<e:property><LastChange>&lt;DIDL-Lite&gt;&lt;res&gt;http://192.168.1.50:8200/M\xe9dia.mp3&lt;/res&gt;&lt;/DIDL-Lite&gt;</LastChange></e:property>"
+        );
+
+        // Moved origin
+        relay.origin.set("10.8.0.3:8200".parse().unwrap());
+        assert_eq!(
+            relay.rewrite_event(&subscription, head, b"<a>http://10.8.0.3:8200/x</a>"),
+            b"<a>http://192.168.1.50:8200/x</a>"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deliver_falls_back() {
+        // Nothing listens on the first callback any more.
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead_url = Url::parse(&format!("http://{}/events", dead.local_addr().unwrap())).unwrap();
+        drop(dead);
+
+        let live = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live_url = Url::parse(&format!("http://{}/events", live.local_addr().unwrap())).unwrap();
+        let subscriber = tokio::spawn(async move {
+            let (stream, _) = live.accept().await.unwrap();
+            let mut reader = BufReader::new(stream);
+            let head = String::from_utf8(read_head(&mut reader).await.unwrap()).unwrap();
+            let length: usize = header_value(&head, "content-length").unwrap().parse().unwrap();
+            let mut body = vec![0u8; length];
+            reader.read_exact(&mut body).await.unwrap();
+            respond(reader.get_mut(), 200, "OK").await.unwrap();
+            (head, body)
+        });
+
+        let relay = relay();
+        let token = relay.add(vec![dead_url, live_url], &rewriter()).unwrap();
+        let subscription = relay.accept_event(&token, "uuid:abc", 0).unwrap().unwrap();
+
+        let head = "NOTIFY /dlnaproxy/events/0 HTTP/1.1\r\nSID: uuid:abc\r\nSEQ: 0\r\n\r\n";
+        relay.deliver(subscription, head.into(), b"<a>http://10.8.0.2:8200/x</a>".to_vec()).await;
+
+        let (head, body) = subscriber.await.unwrap();
+        assert!(head.starts_with("NOTIFY /events HTTP/1.1"));
+        assert_eq!(header_value(&head, "SEQ"), Some("0"));
+        assert_eq!(body, b"<a>http://192.168.1.50:8200/x</a>");
+    }
+
+    #[test]
+    fn test_refused_subscription() {
+        let relay = relay();
+        let (_, token) = subscribe(&relay);
+
+        relay.on_response(EventRequest::Subscribe(token.clone()), 412, "HTTP/1.1 412 Precondition Failed\r\n\r\n");
+
+        assert_eq!(relay.accept_event(&token, "uuid:abc", 0).err(), Some(412));
+    }
+
+    #[test]
+    fn test_parse_event() {
+        let head = "NOTIFY /dlnaproxy/events/0123 HTTP/1.1\r\n\
+            NT: upnp:event\r\n\
+            NTS: upnp:propchange\r\n\
+            SID: uuid:abc\r\n\
+            SEQ: 7\r\n\
+            \r\n";
+
+        assert_eq!(parse_event(head).unwrap(), ("0123".into(), "uuid:abc".into(), 7));
+
+        assert!(parse_event("NOTIFY /other HTTP/1.1\r\nSID: uuid:abc\r\nSEQ: 0\r\n\r\n").is_err());
+        assert!(parse_event("NOTIFY /dlnaproxy/events/0123 HTTP/1.1\r\nSID: uuid:abc\r\n\r\n").is_err());
+    }
+}
@@ -1,20 +1,30 @@
 mod config;
+mod didl;
+mod encoding;
+mod gena;
+mod rewrite;
 mod ssdp;
 mod tcp_proxy;
 
-use std::{net::SocketAddr, path::PathBuf};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
-use config::Config;
+use config::{Config, ServerSource};
 
 use reqwest::Url;
 
 use anyhow::Result;
 use clap::{ArgAction, Parser};
-use log::{debug, trace};
+use log::{debug, trace, warn};
 use ssdp::main_task;
 
-use crate::ssdp::SSDPManager;
-use crate::tcp_proxy::TCPProxy;
+use crate::ssdp::cache::DescriptionCache;
+use crate::ssdp::health::OriginHealth;
+use crate::ssdp::reflector::{reflector_task, Reflector};
+use crate::ssdp::unicast::UnicastTargets;
+use crate::ssdp::discovery::{Discovery, Rediscovery};
+use crate::ssdp::utils::endpoint_urls;
+use crate::ssdp::{InterfaceScope, IpVersion, Origin, SSDPManager};
+use crate::tcp_proxy::{OriginAddr, TCPProxy};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -23,13 +33,29 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 #[clap(author, version, about, long_about = None)]
 struct CommandLineConf {
     /// TOML config file.
-    #[clap(short, long, value_name = "/path/to/config.conf", conflicts_with_all(&["description_url", "interval", "proxy"]))]
+    #[clap(short, long, value_name = "/path/to/config.conf", conflicts_with_all(&["description_url", "discover", "reflect", "interval", "proxy"]))]
     config: Option<PathBuf>,
 
     /// URL pointing to the remote DLNA server's root XML description.
-    #[clap(short = 'u', long, value_name = "URL", required_unless_present("config"), value_parser = Url::parse)]
+    #[clap(short = 'u', long, value_name = "URL", required_unless_present_any(&["config", "discover", "reflect"]), value_parser = Url::parse)]
     description_url: Option<Url>,
 
+    /// Find the remote server by unicast M-SEARCH to this host or CIDR range instead of a fixed URL.
+    #[clap(long, value_name = "IP[/PREFIX]", conflicts_with("description_url"))]
+    discover: Option<String>,
+
+    /// Device type to discover, e.g. "urn:schemas-upnp-org:device:MediaRenderer:1" (default: MediaServer).
+    #[clap(long, value_name = "URN", requires("discover"))]
+    device_type: Option<String>,
+
+    /// Only pick a discovered server with this friendlyName.
+    #[clap(long, value_name = "NAME", requires("discover"))]
+    friendly_name: Option<String>,
+
+    /// Only pick a discovered server with this UDN.
+    #[clap(long, value_name = "UUID", requires("discover"))]
+    udn: Option<String>,
+
     /// Interval at which we will check the remote server's presence and broadcast on its behalf, in seconds.
     #[clap(short = 'd', long, value_name = "DURATION")]
     interval: Option<u64>,
@@ -38,9 +64,41 @@ struct CommandLineConf {
     #[clap(short = 'p', long, value_name = "IP:PORT", value_parser)]
     proxy: Option<SocketAddr>,
 
-    /// Network interface on which to broadcast (requires root or CAP_NET_RAW capability).
-    #[clap(short, long, value_name = "IFACE")]
-    iface: Option<String>,
+    /// Port on which the proxy receives the remote server's GENA events (default: any free port).
+    #[clap(long, value_name = "PORT", requires("proxy"))]
+    event_port: Option<u16>,
+
+    /// Network interface(s) on which to broadcast, by name or IP address, or "all".
+    #[clap(short, long, value_name = "IFACE", value_delimiter = ',')]
+    iface: Vec<String>,
+
+    /// Network interface(s) to leave out when broadcasting on all of them.
+    #[clap(long, value_name = "IFACE", value_delimiter = ',')]
+    exclude_iface: Vec<String>,
+
+    /// Pin SSDP sockets to their interface with SO_BINDTODEVICE (Linux only, requires root or CAP_NET_RAW capability).
+    #[clap(long)]
+    bind_to_device: bool,
+
+    /// Host(s) or CIDR range(s) to also unicast every NOTIFY to, for networks filtering multicast.
+    #[clap(long, value_name = "IP[/PREFIX]", value_delimiter = ',')]
+    unicast_target: Vec<String>,
+
+    /// Add every host that sends us an M-SEARCH to the unicast targets.
+    #[clap(long)]
+    learn_unicast_targets: bool,
+
+    /// Reflect SSDP traffic between these network interfaces (at least two), by name or IP address.
+    #[clap(long, value_name = "IFACE", value_delimiter = ',')]
+    reflect: Vec<String>,
+
+    /// Only reflect devices of these types, e.g. "MediaRenderer" or a full device type URN.
+    #[clap(long, value_name = "TYPE", value_delimiter = ',', requires("reflect"))]
+    reflect_device_type: Vec<String>,
+
+    /// IP versions to run SSDP over (default: v4).
+    #[clap(long, value_name = "VERSION", value_enum)]
+    ip_version: Option<IpVersion>,
 
     /// Wait for remote server to become available at startup. Retries every SECONDS (default: 30).
     #[clap(short = 'w', long, value_name = "SECONDS", default_missing_value = "30", num_args = 0..=1)]
@@ -58,6 +116,26 @@ struct CommandLineConf {
     #[clap(long, value_name = "SECONDS")]
     stream_timeout: Option<u64>,
 
+    /// How long the remote server's description is cached before being revalidated, in seconds.
+    #[clap(long, value_name = "SECONDS")]
+    cache_ttl: Option<u64>,
+
+    /// How long a stale description may still be served while the remote server is revalidated, in seconds.
+    #[clap(long, value_name = "SECONDS")]
+    cache_max_stale: Option<u64>,
+
+    /// Number of consecutive failed checks before the remote server is declared down.
+    #[clap(long, value_name = "COUNT")]
+    down_after_failures: Option<u32>,
+
+    /// Number of consecutive successful checks before the remote server is declared up again.
+    #[clap(long, value_name = "COUNT")]
+    up_after_successes: Option<u32>,
+
+    /// File where the UPnP boot counter (BOOTID.UPNP.ORG) is persisted across restarts.
+    #[clap(long, value_name = "/path/to/bootid")]
+    boot_id_file: Option<PathBuf>,
+
     /// Verbosity level. The more v, the more verbose.
     #[clap(short, long, action=ArgAction::Count)]
     verbose: u8,
@@ -73,40 +151,102 @@ async fn main() -> Result<()> {
 
     println!("dlna-proxy v{}", VERSION);
 
-    let mut url = config.description_url;
-
-    let _tcp_proxy_thread = if let Some(proxy_addr) = config.proxy {
-        let server_addr = config::sockaddr_from_url(&url)?;
-
-        url.set_ip_host(proxy_addr.ip()).unwrap();
-        url.set_port(Some(proxy_addr.port())).unwrap();
-
-        let proxy = TCPProxy::new(
-            config.proxy_timeout,
-            config.stream_timeout,
-            server_addr,
-            proxy_addr,
-        );
-
-        trace!(target: "dlnaproxy", "server: {}", server_addr);
-
-        Some(proxy.start(server_addr, proxy_addr).await?)
-    } else {
-        None
-    };
-
-    debug!(target: "dlnaproxy", "Desc URL: '{}', interval: {}s, verbosity: {}", url, config.period.as_secs(), config.verbose);
+    let reflector = config
+        .reflector
+        .map(|reflector| {
+            Reflector::open(
+                reflector.interfaces,
+                reflector.device_types,
+                config.ip_version,
+                config.bind_to_device,
+            )
+        })
+        .transpose()?;
+
+    if config.servers.is_empty() {
+        // Only reflecting, there is nothing to announce.
+        if let Some(reflector) = reflector {
+            reflector_task(reflector).await;
+        }
+        return Ok(());
+    }
+
+    let _reflector_handle = reflector.map(|reflector| tokio::spawn(reflector_task(reflector)));
+
+    let mut ssdp = SSDPManager::new(
+        config.interfaces,
+        config.ip_version,
+        config.bind_to_device,
+        UnicastTargets::new(&config.unicast_targets, config.learn_unicast_targets)?,
+        config.boot_id_file,
+    ).await?;
+
+    // Proxy handles must outlive the SSDP tasks.
+    let mut _tcp_proxy_threads = Vec::with_capacity(config.servers.len());
+
+    for server in config.servers {
+        let (origin_url, discovery) = match server.source {
+            ServerSource::Url(url) => (url, None),
+            ServerSource::Discover(discovery) => {
+                let http_client = reqwest::Client::builder()
+                    .connect_timeout(server.connect_timeout)
+                    .build()?;
+
+                let url = discover(&discovery, &http_client, config.wait).await?;
+
+                (url, Some((discovery, http_client)))
+            }
+        };
+
+        let mut proxy_origin = None;
+
+        if let Some(proxy_addr) = server.proxy {
+            let server_addr = OriginAddr::new(config::sockaddr_from_url(&origin_url)?, origin_url.domain());
+
+            let proxy = TCPProxy::new(
+                server.proxy_timeout,
+                server.stream_timeout,
+                server_addr.clone(),
+                proxy_addr,
+                server.event_port,
+                server.rewrite,
+                server.unreachable,
+                server.identity_encoding,
+            );
+
+            trace!(target: "dlnaproxy", "server: {}", server_addr.get());
+
+            _tcp_proxy_threads.push(proxy.start(proxy_addr).await?);
+
+            proxy_origin = Some((proxy_addr, server_addr));
+        }
+
+        let (url, location) = endpoint_urls(&origin_url, server.proxy);
+
+        debug!(target: "dlnaproxy", "Desc URL: '{}', interval: {}s, verbosity: {}", url, server.period.as_secs(), config.verbose);
+
+        let description_cache = DescriptionCache::new(server.cache_ttl, server.cache_max_stale);
+
+        let rediscovery = discovery.map(|(discovery, http_client)| {
+            Rediscovery::new(discovery, http_client, origin_url, proxy_origin)
+        });
+
+        ssdp.add_server(
+            Origin {
+                description_url: url.to_string(),
+                location,
+                rediscovery,
+            },
+            server.period,
+            Some(server.connect_timeout),
+            description_cache,
+            OriginHealth::new(server.down_after_failures, server.up_after_successes),
+            InterfaceScope::new(server.announce_iface),
+        )?;
+    }
 
     let wait_mode = config.wait.is_some();
 
-    let ssdp = SSDPManager::new(
-        url.as_str(),
-        config.period,
-        Some(config.connect_timeout),
-        config.broadcast_iface,
-    )
-    .await?;
-
     let handle = tokio::spawn(main_task(ssdp, wait_mode));
 
     let _ = handle.await;
@@ -114,6 +254,22 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Discover a server at startup, retrying every `wait` if set.
+async fn discover(discovery: &Discovery, http_client: &reqwest::Client, wait: Option<Duration>) -> Result<Url> {
+    loop {
+        match discovery.discover(http_client).await {
+            Ok(url) => return Ok(url),
+            Err(e) => match wait {
+                Some(wait) => {
+                    warn!(target: "dlnaproxy", "{}. Retrying in {}s.", e, wait.as_secs());
+                    tokio::time::sleep(wait).await;
+                }
+                None => return Err(e),
+            },
+        }
+    }
+}
+
 fn init_logging(verbosity: log::LevelFilter) -> log::LevelFilter {
     fern::Dispatch::new().
         format(|out, message, record| {
@@ -0,0 +1,381 @@
+use std::{borrow::Cow, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use regex::bytes::Regex;
+use reqwest::Url;
+
+use crate::didl;
+
+/// A `[[rewrite]]` rule, applied to response bodies before the origin's URLs are
+/// moved to the proxy.
+pub struct RewriteRule {
+    pattern: Pattern,
+    replace: String,
+    /// Media types the rule applies to, by prefix, every rewritable one when empty.
+    content_types: Vec<String>,
+    /// Prefix of the request paths the rule applies to.
+    path: Option<String>,
+}
+
+enum Pattern {
+    Literal(Vec<u8>),
+    Regex(Regex),
+}
+
+impl RewriteRule {
+    /// `pattern` is a regex when `regex` is set, `replace` may then refer to its groups as `$1`.
+    pub fn new(
+        pattern: &str,
+        replace: &str,
+        regex: bool,
+        content_types: Vec<String>,
+        path: Option<String>,
+    ) -> Result<Self> {
+        let pattern = match regex {
+            true => Pattern::Regex(Regex::new(pattern).with_context(|| format!("Bad rewrite regex '{}'", pattern))?),
+            false if pattern.is_empty() => bail!("Empty rewrite match"),
+            false => Pattern::Literal(pattern.as_bytes().to_vec()),
+        };
+
+        Ok(RewriteRule {
+            pattern,
+            replace: replace.to_string(),
+            content_types: content_types.into_iter().map(|t| t.to_lowercase()).collect(),
+            path,
+        })
+    }
+
+    /// Whether the rule applies to a response of `content_type` to a request for `path`.
+    fn applies(&self, content_type: &str, path: Option<&str>) -> bool {
+        let content_type = content_type.trim().to_lowercase();
+
+        let type_matches = self.content_types.is_empty() || self.content_types.iter().any(|t| content_type.starts_with(t));
+
+        let path_matches = match (&self.path, path) {
+            (None, _) => true,
+            (Some(prefix), Some(path)) => path.starts_with(prefix),
+            (Some(_), None) => false,
+        };
+
+        type_matches && path_matches
+    }
+
+    fn apply<'a>(&self, body: &'a [u8]) -> Cow<'a, [u8]> {
+        match &self.pattern {
+            Pattern::Literal(literal) => replace_bytes(body, literal, self.replace.as_bytes()),
+            Pattern::Regex(regex) => regex.replace_all(body, self.replace.as_bytes()),
+        }
+    }
+}
+
+/// Replace every `pattern` in `body` by `replacement`, leaving all other bytes as they are.
+fn replace_bytes<'a>(body: &'a [u8], pattern: &[u8], replacement: &[u8]) -> Cow<'a, [u8]> {
+    let mut replacer = StreamReplacer::new(pattern, replacement);
+    let mut out = Vec::new();
+    replacer.feed(body, &mut out);
+    replacer.finish(&mut out);
+
+    match out == body {
+        true => Cow::Borrowed(body),
+        false => Cow::Owned(out),
+    }
+}
+
+/// Move the URLs in a body from `from` to `to`: structurally if it is UTF-8 text, which
+/// DIDL-Lite metadata needs, byte for byte otherwise, e.g. in Latin-1 documents.
+/// `from_name` is the hostname `from` may also go by.
+fn move_urls(body: &[u8], from: &str, from_name: Option<&str>, to: &str) -> Vec<u8> {
+    match std::str::from_utf8(body) {
+        Ok(text) => didl::rewrite(text, from, from_name, to).into_bytes(),
+        Err(_) => replace_bytes(body, from.as_bytes(), to.as_bytes()).into_owned(),
+    }
+}
+
+/// Replaces a byte string in a body fed to it piece by piece, matches split between
+/// pieces included. It holds back at most the length of the pattern.
+struct StreamReplacer {
+    pattern: Vec<u8>,
+    replacement: Vec<u8>,
+    /// End of the input so far that may be the start of a match.
+    carry: Vec<u8>,
+}
+
+impl StreamReplacer {
+    fn new(pattern: &[u8], replacement: &[u8]) -> Self {
+        StreamReplacer {
+            pattern: pattern.to_vec(),
+            replacement: replacement.to_vec(),
+            carry: Vec::new(),
+        }
+    }
+
+    fn feed(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        let mut data = std::mem::take(&mut self.carry);
+        data.extend_from_slice(input);
+
+        let mut copied = 0;
+        let mut i = 0;
+        while i + self.pattern.len() <= data.len() {
+            if data[i..].starts_with(&self.pattern) {
+                out.extend_from_slice(&data[copied..i]);
+                out.extend_from_slice(&self.replacement);
+                i += self.pattern.len();
+                copied = i;
+            } else {
+                i += 1;
+            }
+        }
+
+        // Hold back the longest tail the next piece could complete into a match.
+        let held = (i..data.len())
+            .find(|&start| self.pattern.starts_with(&data[start..]))
+            .unwrap_or(data.len());
+
+        out.extend_from_slice(&data[copied..held]);
+        self.carry = data.split_off(held);
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.carry);
+    }
+}
+
+/// Rewrites a response body as it streams through, in bounded memory: the literal
+/// rules in order, then the origin's URLs to the proxy's.
+pub struct StreamRewriter {
+    stages: Vec<StreamReplacer>,
+}
+
+impl StreamRewriter {
+    /// Rewrite the next piece of the body, returning what can be sent on.
+    pub fn feed(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut data = input.to_vec();
+
+        for stage in &mut self.stages {
+            let mut out = Vec::with_capacity(data.len());
+            stage.feed(&data, &mut out);
+            data = out;
+        }
+
+        data
+    }
+
+    /// End of the body: whatever was held back for a possible match.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        for stage in &mut self.stages {
+            let mut out = Vec::new();
+            stage.feed(&data, &mut out);
+            stage.finish(&mut out);
+            data = out;
+        }
+
+        data
+    }
+
+    /// Whether the rewritten body is as long as the original, so its Content-Length holds.
+    pub fn preserves_length(&self) -> bool {
+        self.stages.iter().all(|stage| stage.pattern.len() == stage.replacement.len())
+    }
+}
+
+/// Rewrites the bodies of a proxy's responses: the configured rules in order, then
+/// the origin's URLs to the proxy's. Requests go the other way.
+#[derive(Clone)]
+pub struct Rewriter {
+    pub origin_url_base: String,
+    pub proxy_url_base: String,
+    /// Host header of requests to the origin.
+    pub origin_host: String,
+    /// Hostname in `origin_host`, if not an IP address.
+    origin_name: Option<String>,
+    /// Whether requests ask the origin for uncompressed responses.
+    pub identity_encoding: bool,
+    rules: Arc<Vec<RewriteRule>>,
+}
+
+impl Rewriter {
+    pub fn new(origin_url_base: String, proxy_url_base: String, origin_host: String, rules: Arc<Vec<RewriteRule>>) -> Self {
+        let origin_name = Url::parse(&format!("http://{}/", origin_host))
+            .ok()
+            .and_then(|url| url.domain().map(str::to_string));
+
+        Rewriter {
+            origin_url_base,
+            proxy_url_base,
+            origin_host,
+            origin_name,
+            identity_encoding: false,
+            rules,
+        }
+    }
+
+    /// Rewrite a request body, the proxy's URLs back to the origin's.
+    pub fn rewrite_request(&self, body: &[u8]) -> Vec<u8> {
+        move_urls(body, &self.proxy_url_base, None, &self.origin_url_base)
+    }
+
+    /// Rewrite a response body of `content_type`, answering a request for `path` if known.
+    pub fn rewrite(&self, body: &[u8], content_type: &str, path: Option<&str>) -> Vec<u8> {
+        let mut body = Cow::Borrowed(body);
+
+        for rule in self.rules.iter().filter(|rule| rule.applies(content_type, path)) {
+            if let Cow::Owned(rewritten) = rule.apply(&body) {
+                body = Cow::Owned(rewritten);
+            }
+        }
+
+        move_urls(&body, &self.origin_url_base, self.origin_name.as_deref(), &self.proxy_url_base)
+    }
+
+    /// Whether rules for a response of `content_type` need its whole body, which
+    /// regex ones do to match across pieces.
+    pub fn needs_whole_body(&self, content_type: &str, path: Option<&str>) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.applies(content_type, path) && matches!(rule.pattern, Pattern::Regex(_)))
+    }
+
+    /// Rewrite a response body as it streams through. Regex rules are left out, as is
+    /// the structural DIDL-Lite rewriting, both needing the whole body.
+    pub fn stream(&self, content_type: &str, path: Option<&str>) -> StreamRewriter {
+        let rules = self.rules.iter().filter(|rule| rule.applies(content_type, path));
+
+        let mut stages: Vec<StreamReplacer> = rules
+            .filter_map(|rule| match &rule.pattern {
+                Pattern::Literal(literal) => Some(StreamReplacer::new(literal, rule.replace.as_bytes())),
+                Pattern::Regex(_) => None,
+            })
+            .collect();
+
+        stages.push(StreamReplacer::new(
+            self.origin_url_base.as_bytes(),
+            self.proxy_url_base.as_bytes(),
+        ));
+
+        StreamRewriter { stages }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewriter(rules: Vec<RewriteRule>) -> Rewriter {
+        Rewriter::new(
+            "http://10.8.0.2:8200".into(),
+            "http://192.168.1.50:8200".into(),
+            "10.8.0.2:8200".into(),
+            Arc::new(rules),
+        )
+    }
+
+    #[test]
+    fn test_literal_rule() {
+        let rewriter = rewriter(vec![
+            RewriteRule::new("http://10.8.0.2:8201", "http://192.168.1.50:8201", false, vec![], None).unwrap(),
+        ]);
+
+        assert_eq!(
+            rewriter.rewrite(b"<a>http://10.8.0.2:8201/x</a><b>http://10.8.0.2:8200/y</b>", "text/xml", None),
+            b"<a>http://192.168.1.50:8201/x</a><b>http://192.168.1.50:8200/y</b>"
+        );
+    }
+
+    #[test]
+    fn test_rules_in_order() {
+        // The public hostname is first mapped to the origin, then to the proxy.
+        let rewriter = rewriter(vec![
+            RewriteRule::new(r"https://media\.example\.com(:\d+)?", "http://10.8.0.2:8200", true, vec![], None).unwrap(),
+            RewriteRule::new(r"(http://10\.8\.0\.2:8200)/dlna/", "$1/", true, vec![], None).unwrap(),
+        ]);
+
+        assert_eq!(
+            rewriter.rewrite(b"<url>https://media.example.com:443/dlna/a.mkv</url>", "text/xml", None),
+            b"<url>http://192.168.1.50:8200/a.mkv</url>"
+        );
+    }
+
+    #[test]
+    fn test_rule_scoping() {
+        let rule = RewriteRule::new("a", "b", false, vec!["Text/XML".into()], Some("/ctl/".into())).unwrap();
+
+        assert!(rule.applies("text/xml; charset=\"utf-8\"", Some("/ctl/ContentDir")));
+        assert!(!rule.applies("application/json", Some("/ctl/ContentDir")));
+        assert!(!rule.applies("text/xml", Some("/rootDesc.xml")));
+        assert!(!rule.applies("text/xml", None));
+
+        let rewriter = rewriter(vec![rule]);
+        assert_eq!(rewriter.rewrite(b"a", "text/html", Some("/ctl/")), b"a");
+        assert_eq!(rewriter.rewrite(b"a", "text/xml", Some("/ctl/")), b"b");
+    }
+
+    #[test]
+    fn test_bad_regex() {
+        assert!(RewriteRule::new("(", "", true, vec![], None).is_err());
+        assert!(RewriteRule::new("(", "", false, vec![], None).is_ok());
+        assert!(RewriteRule::new("", "", false, vec![], None).is_err());
+    }
+
+    #[test]
+    fn test_non_utf8_body() {
+        // Latin-1 "é" around the URL stays as it was.
+        let body = b"<r>caf\xe9 http://10.8.0.2:8200/a.mp3 \xe9</r>";
+
+        assert_eq!(
+            rewriter(vec![]).rewrite(body, "text/xml", None),
+            b"<r>caf\xe9 http://192.168.1.50:8200/a.mp3 \xe9</r>"
+        );
+    }
+
+    #[test]
+    fn test_stream_split_matches() {
+        let rewriter = rewriter(vec![
+            RewriteRule::new("http://10.8.0.2:8201", "http://192.168.1.50:8201", false, vec![], None).unwrap(),
+        ]);
+        let body = b"<a>http://10.8.0.2:8201/x</a>http://10.8.0.2:82<b>http://10.8.0.2:8200/\xff</b>http://10.8.0.2";
+        let expected = b"<a>http://192.168.1.50:8201/x</a>http://10.8.0.2:82<b>http://192.168.1.50:8200/\xff</b>http://10.8.0.2";
+
+        // Every split of the body into pieces of each size gives the same result.
+        for size in 1..=body.len() {
+            let mut stream = rewriter.stream("text/xml", None);
+            let mut out = Vec::new();
+            for piece in body.chunks(size) {
+                out.extend(stream.feed(piece));
+            }
+            out.extend(stream.finish());
+
+            assert_eq!(out, expected, "pieces of {}", size);
+        }
+    }
+
+    #[test]
+    fn test_stream_preserves_length() {
+        let same = RewriteRule::new("abc", "xyz", false, vec![], None).unwrap();
+        let regex = RewriteRule::new("a+", "b", true, vec![], None).unwrap();
+        let equal = Rewriter::new("http://a:1".into(), "http://b:1".into(), "a:1".into(), Arc::new(vec![same, regex]));
+
+        assert!(equal.stream("text/xml", None).preserves_length());
+        assert!(!rewriter(vec![]).stream("text/xml", None).preserves_length());
+    }
+
+    #[test]
+    fn test_needs_whole_body() {
+        let rewriter = rewriter(vec![
+            RewriteRule::new("abc", "xyz", false, vec![], None).unwrap(),
+            RewriteRule::new("a+", "b", true, vec!["text/html".into()], None).unwrap(),
+        ]);
+
+        assert!(rewriter.needs_whole_body("text/html", None));
+        assert!(!rewriter.needs_whole_body("text/xml", None));
+    }
+
+    #[test]
+    fn test_origin_name() {
+        let named = Rewriter::new("http://10.8.0.2".into(), "http://b:1".into(), "Jellyfin.lan".into(), Arc::new(vec![]));
+        assert_eq!(named.origin_name.as_deref(), Some("jellyfin.lan"));
+
+        assert_eq!(rewriter(vec![]).origin_name, None);
+    }
+}
@@ -0,0 +1,378 @@
+use crate::ssdp::utils::DeviceInfo;
+
+/*
+    UPnP Device Architecture 1.1, section 1.1.2: a root device with d embedded devices
+    and k distinct service types sends 3 + 2d + k discovery messages.
+*/
+
+pub const ROOT_DEVICE: &str = "upnp:rootdevice";
+
+pub const SSDP_ALL: &str = "ssdp:all";
+
+/// One NT/USN pair announced on behalf of a device.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Advertisement {
+    pub notification_type: String,
+    pub unique_service_name: String,
+}
+
+impl Advertisement {
+    fn new(notification_type: &str, unique_service_name: String) -> Self {
+        Advertisement {
+            notification_type: notification_type.to_string(),
+            unique_service_name,
+        }
+    }
+}
+
+/// Full set of advertisements for a device tree, root device first.
+pub fn advertisements(devices: &[DeviceInfo]) -> Vec<Advertisement> {
+    let mut ads = Vec::new();
+
+    for (i, device) in devices.iter().enumerate() {
+        let udn = &device.unique_device_name;
+
+        if i == 0 {
+            ads.push(Advertisement::new(ROOT_DEVICE, format!("{}::{}", udn, ROOT_DEVICE)));
+        }
+
+        ads.push(Advertisement::new(udn, udn.clone()));
+        ads.push(Advertisement::new(
+            &device.device_type,
+            format!("{}::{}", udn, device.device_type),
+        ));
+
+        let mut seen: Vec<&str> = Vec::with_capacity(device.service_types.len());
+        for service_type in &device.service_types {
+            if seen.contains(&service_type.as_str()) {
+                continue;
+            }
+            seen.push(service_type);
+
+            ads.push(Advertisement::new(service_type, format!("{}::{}", udn, service_type)));
+        }
+    }
+
+    ads
+}
+
+/// Advertisements withdrawn and added between two versions of a description.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AdvertisementDiff {
+    pub removed: Vec<Advertisement>,
+    pub added: Vec<Advertisement>,
+}
+
+impl AdvertisementDiff {
+    pub fn between(previous: &[DeviceInfo], current: &[DeviceInfo]) -> Self {
+        let previous = advertisements(previous);
+        let current = advertisements(current);
+
+        AdvertisementDiff {
+            removed: previous.iter().filter(|a| !current.contains(a)).cloned().collect(),
+            added: current.iter().filter(|a| !previous.contains(a)).cloned().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.added.is_empty()
+    }
+}
+
+/// Responses owed to an M-SEARCH for `search_target`, one per matching target.
+///
+/// The ST and USN of each response follow UDA 1.1 section 1.3.3: `ssdp:all` gets
+/// the full advertisement set, while a device or service type search matches
+/// any equal or higher version we have and echoes back the version asked for.
+pub fn search_responses(devices: &[DeviceInfo], search_target: &str) -> Vec<Advertisement> {
+    let search_target = search_target.trim();
+
+    if search_target == SSDP_ALL {
+        return advertisements(devices);
+    }
+
+    if search_target == ROOT_DEVICE {
+        return devices
+            .first()
+            .map(|root| {
+                let usn = format!("{}::{}", root.unique_device_name, ROOT_DEVICE);
+                vec![Advertisement::new(ROOT_DEVICE, usn)]
+            })
+            .unwrap_or_default();
+    }
+
+    if search_target.starts_with("uuid:") {
+        return devices
+            .iter()
+            .filter(|d| d.unique_device_name.eq_ignore_ascii_case(search_target))
+            .map(|d| Advertisement::new(search_target, d.unique_device_name.clone()))
+            .collect();
+    }
+
+    let mut responses = Vec::new();
+
+    for device in devices {
+        let udn = &device.unique_device_name;
+
+        let matches_device = type_matches(&device.device_type, search_target);
+        let matches_service = device
+            .service_types
+            .iter()
+            .any(|service_type| type_matches(service_type, search_target));
+
+        if matches_device || matches_service {
+            responses.push(Advertisement::new(search_target, format!("{}::{}", udn, search_target)));
+        }
+    }
+
+    responses
+}
+
+/// Whether a device or service type we provide satisfies a searched type.
+///
+/// `urn:domain:device:Type:2` satisfies searches for versions 1 and 2 of the same type.
+fn type_matches(provided: &str, searched: &str) -> bool {
+    match (split_version(provided), split_version(searched)) {
+        (Some((provided_type, provided_ver)), Some((searched_type, searched_ver))) => {
+            provided_type == searched_type && searched_ver <= provided_ver
+        }
+        _ => provided == searched,
+    }
+}
+
+fn split_version(urn: &str) -> Option<(&str, u32)> {
+    if !urn.starts_with("urn:") {
+        return None;
+    }
+
+    let (base, version) = urn.rsplit_once(':')?;
+    version.parse().ok().map(|v| (base, v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn media_server() -> Vec<DeviceInfo> {
+        vec![DeviceInfo {
+            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_device_name: "uuid:root".to_string(),
+            service_types: vec![
+                "urn:schemas-upnp-org:service:ContentDirectory:1".to_string(),
+                "urn:schemas-upnp-org:service:ConnectionManager:1".to_string(),
+                "urn:microsoft.com:service:X_MS_MediaReceiverRegistrar:1".to_string(),
+            ],
+        }]
+    }
+
+    #[test]
+    fn test_root_device_message_count() {
+        // 3 for the root device + 3 services
+        assert_eq!(advertisements(&media_server()).len(), 6);
+    }
+
+    #[test]
+    fn test_root_device_advertisements() {
+        let ads = advertisements(&media_server());
+
+        assert_eq!(ads[0].notification_type, "upnp:rootdevice");
+        assert_eq!(ads[0].unique_service_name, "uuid:root::upnp:rootdevice");
+
+        assert_eq!(ads[1].notification_type, "uuid:root");
+        assert_eq!(ads[1].unique_service_name, "uuid:root");
+
+        assert_eq!(ads[2].notification_type, "urn:schemas-upnp-org:device:MediaServer:1");
+        assert_eq!(
+            ads[2].unique_service_name,
+            "uuid:root::urn:schemas-upnp-org:device:MediaServer:1"
+        );
+
+        assert_eq!(ads[3].notification_type, "urn:schemas-upnp-org:service:ContentDirectory:1");
+        assert_eq!(
+            ads[3].unique_service_name,
+            "uuid:root::urn:schemas-upnp-org:service:ContentDirectory:1"
+        );
+    }
+
+    #[test]
+    fn test_embedded_devices() {
+        let mut devices = media_server();
+        devices.push(DeviceInfo {
+            device_type: "urn:schemas-upnp-org:device:Embedded:1".to_string(),
+            unique_device_name: "uuid:embedded".to_string(),
+            service_types: vec!["urn:schemas-upnp-org:service:Foo:1".to_string()],
+        });
+
+        let ads = advertisements(&devices);
+        // 3 + 2*1 + (3 + 1)
+        assert_eq!(ads.len(), 9);
+        // Only the root device is announced as upnp:rootdevice
+        assert_eq!(ads.iter().filter(|a| a.notification_type == ROOT_DEVICE).count(), 1);
+        assert!(ads.contains(&Advertisement {
+            notification_type: "uuid:embedded".to_string(),
+            unique_service_name: "uuid:embedded".to_string(),
+        }));
+        assert!(ads.contains(&Advertisement {
+            notification_type: "urn:schemas-upnp-org:service:Foo:1".to_string(),
+            unique_service_name: "uuid:embedded::urn:schemas-upnp-org:service:Foo:1".to_string(),
+        }));
+    }
+
+    #[test]
+    fn test_duplicate_service_types_announced_once() {
+        let devices = vec![DeviceInfo {
+            device_type: "urn:schemas-upnp-org:device:MediaRenderer:1".to_string(),
+            unique_device_name: "uuid:root".to_string(),
+            service_types: vec![
+                "urn:schemas-upnp-org:service:AVTransport:1".to_string(),
+                "urn:schemas-upnp-org:service:AVTransport:1".to_string(),
+            ],
+        }];
+
+        assert_eq!(advertisements(&devices).len(), 4);
+    }
+
+    // ============================================
+    // AdvertisementDiff tests
+    // ============================================
+
+    #[test]
+    fn test_diff_unchanged() {
+        assert!(AdvertisementDiff::between(&media_server(), &media_server()).is_empty());
+    }
+
+    #[test]
+    fn test_diff_new_udn_replaces_everything() {
+        let mut reinstalled = media_server();
+        reinstalled[0].unique_device_name = "uuid:new-root".to_string();
+
+        let diff = AdvertisementDiff::between(&media_server(), &reinstalled);
+        assert_eq!(diff.removed, advertisements(&media_server()));
+        assert_eq!(diff.added, advertisements(&reinstalled));
+    }
+
+    #[test]
+    fn test_diff_service_removed() {
+        let mut current = media_server();
+        current[0].service_types.pop();
+
+        let diff = AdvertisementDiff::between(&media_server(), &current);
+        assert!(diff.added.is_empty());
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(
+            diff.removed[0].notification_type,
+            "urn:microsoft.com:service:X_MS_MediaReceiverRegistrar:1"
+        );
+    }
+
+    #[test]
+    fn test_diff_device_type_changed() {
+        let mut current = media_server();
+        current[0].device_type = "urn:schemas-upnp-org:device:MediaServer:2".to_string();
+
+        let diff = AdvertisementDiff::between(&media_server(), &current);
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].notification_type, "urn:schemas-upnp-org:device:MediaServer:2");
+    }
+
+    // ============================================
+    // search_responses() tests
+    // ============================================
+
+    #[test]
+    fn test_search_ssdp_all() {
+        let responses = search_responses(&media_server(), "ssdp:all");
+        assert_eq!(responses, advertisements(&media_server()));
+    }
+
+    #[test]
+    fn test_search_rootdevice() {
+        let responses = search_responses(&media_server(), "upnp:rootdevice");
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].notification_type, "upnp:rootdevice");
+        assert_eq!(responses[0].unique_service_name, "uuid:root::upnp:rootdevice");
+    }
+
+    #[test]
+    fn test_search_uuid() {
+        let responses = search_responses(&media_server(), "uuid:root");
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].notification_type, "uuid:root");
+        assert_eq!(responses[0].unique_service_name, "uuid:root");
+
+        assert!(search_responses(&media_server(), "uuid:someone-else").is_empty());
+    }
+
+    #[test]
+    fn test_search_device_type() {
+        let responses = search_responses(&media_server(), "urn:schemas-upnp-org:device:MediaServer:1");
+        assert_eq!(responses.len(), 1);
+        assert_eq!(
+            responses[0].unique_service_name,
+            "uuid:root::urn:schemas-upnp-org:device:MediaServer:1"
+        );
+    }
+
+    #[test]
+    fn test_search_service_type() {
+        let responses =
+            search_responses(&media_server(), "urn:schemas-upnp-org:service:ContentDirectory:1");
+        assert_eq!(responses.len(), 1);
+        assert_eq!(
+            responses[0].notification_type,
+            "urn:schemas-upnp-org:service:ContentDirectory:1"
+        );
+        assert_eq!(
+            responses[0].unique_service_name,
+            "uuid:root::urn:schemas-upnp-org:service:ContentDirectory:1"
+        );
+    }
+
+    #[test]
+    fn test_search_higher_version_than_provided() {
+        assert!(search_responses(&media_server(), "urn:schemas-upnp-org:device:MediaServer:2").is_empty());
+    }
+
+    #[test]
+    fn test_search_lower_version_echoes_requested_version() {
+        let devices = vec![DeviceInfo {
+            device_type: "urn:schemas-upnp-org:device:MediaServer:2".to_string(),
+            unique_device_name: "uuid:root".to_string(),
+            service_types: vec![],
+        }];
+
+        let responses = search_responses(&devices, "urn:schemas-upnp-org:device:MediaServer:1");
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].notification_type, "urn:schemas-upnp-org:device:MediaServer:1");
+        assert_eq!(
+            responses[0].unique_service_name,
+            "uuid:root::urn:schemas-upnp-org:device:MediaServer:1"
+        );
+
+        assert_eq!(search_responses(&devices, "urn:schemas-upnp-org:device:MediaServer:2").len(), 1);
+    }
+
+    #[test]
+    fn test_search_unrelated_type() {
+        assert!(search_responses(&media_server(), "urn:schemas-upnp-org:device:MediaRenderer:1").is_empty());
+        assert!(search_responses(&media_server(), "urn:dial-multiscreen-org:service:dial:1").is_empty());
+    }
+
+    #[test]
+    fn test_search_embedded_device_type() {
+        let mut devices = media_server();
+        devices.push(DeviceInfo {
+            device_type: "urn:schemas-upnp-org:device:Embedded:1".to_string(),
+            unique_device_name: "uuid:embedded".to_string(),
+            service_types: vec![],
+        });
+
+        let responses = search_responses(&devices, "urn:schemas-upnp-org:device:Embedded:1");
+        assert_eq!(responses.len(), 1);
+        assert_eq!(
+            responses[0].unique_service_name,
+            "uuid:embedded::urn:schemas-upnp-org:device:Embedded:1"
+        );
+    }
+}
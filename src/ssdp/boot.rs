@@ -0,0 +1,125 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+
+/// BOOTID.UPNP.ORG must fit in 31 bits.
+const MAX_BOOT_ID: u32 = (1 << 31) - 1;
+
+/// BOOTID.UPNP.ORG value, bumped on every start and whenever we re-join the network.
+///
+/// With a state file the counter survives restarts; otherwise it starts from the
+/// current Unix time, which also increases from one run to the next.
+pub struct BootId {
+    value: AtomicU32,
+    state_file: Option<PathBuf>,
+}
+
+impl BootId {
+    pub fn load(state_file: Option<PathBuf>) -> Result<Self> {
+        let value = match &state_file {
+            Some(path) => {
+                let previous = fs::read_to_string(path)
+                    .ok()
+                    .and_then(|s| s.trim().parse::<u32>().ok())
+                    .unwrap_or(0);
+
+                let value = next_boot_id(previous);
+                persist(path, value)?;
+                value
+            }
+            None => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(1);
+                (now % u64::from(MAX_BOOT_ID)) as u32
+            }
+        };
+
+        debug!(target: "dlnaproxy", "BOOTID.UPNP.ORG is {}", value);
+
+        Ok(BootId {
+            value: AtomicU32::new(value),
+            state_file,
+        })
+    }
+
+    pub fn current(&self) -> u32 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    /// The value the next call to `advance()` will switch to (NEXTBOOTID.UPNP.ORG).
+    pub fn next(&self) -> u32 {
+        next_boot_id(self.current())
+    }
+
+    pub fn advance(&self) -> u32 {
+        let value = self.next();
+        self.value.store(value, Ordering::Relaxed);
+
+        if let Some(path) = &self.state_file {
+            if let Err(e) = persist(path, value) {
+                warn!(target: "dlnaproxy", "{}", e);
+            }
+        }
+
+        debug!(target: "dlnaproxy", "BOOTID.UPNP.ORG is now {}", value);
+        value
+    }
+}
+
+fn next_boot_id(current: u32) -> u32 {
+    if current >= MAX_BOOT_ID {
+        1
+    } else {
+        current + 1
+    }
+}
+
+fn persist(path: &PathBuf, value: u32) -> Result<()> {
+    fs::write(path, value.to_string())
+        .with_context(|| format!("Failed to write boot id to {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_boot_id_wraps() {
+        assert_eq!(next_boot_id(1), 2);
+        assert_eq!(next_boot_id(MAX_BOOT_ID), 1);
+    }
+
+    #[test]
+    fn test_without_state_file() {
+        let boot_id = BootId::load(None).unwrap();
+        assert!(boot_id.current() <= MAX_BOOT_ID);
+
+        let next = boot_id.next();
+        assert_eq!(boot_id.advance(), next);
+        assert_eq!(boot_id.current(), next);
+    }
+
+    #[test]
+    fn test_state_file_is_incremented_and_persisted() {
+        let path = std::env::temp_dir().join(format!("dlnaproxy-bootid-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(BootId::load(Some(path.clone())).unwrap().current(), 1);
+
+        let boot_id = BootId::load(Some(path.clone())).unwrap();
+        assert_eq!(boot_id.current(), 2);
+
+        boot_id.advance();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "3");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
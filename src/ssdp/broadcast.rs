@@ -1,48 +1,113 @@
 use log::{debug, info, warn};
-use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
 use tokio::{signal, time};
 
 #[cfg(unix)]
 use tokio::signal::unix::{signal as unix_signal, SignalKind};
 
-use std::borrow::Borrow as _;
 use std::time::Duration;
 use std::{process, sync::Arc};
 
 use anyhow::Result;
 
-use crate::ssdp::utils::InteractiveSSDP;
-use crate::ssdp::SSDP_ADDRESS;
+use crate::ssdp::boot::BootId;
+use crate::ssdp::discovery::Rediscovery;
+use crate::ssdp::health::{OriginState, Transition};
+use crate::ssdp::utils::{EndpointInfo, InteractiveSSDP};
+use crate::ssdp::netwatch::NetworkWatcher;
+use crate::ssdp::network::{lan_addresses, InterfaceScope, SSDPNetwork};
+use crate::ssdp::SSDPChannel;
+
+/// Number of ssdp:alive rounds sent when the origin comes back, since UDP is lossy.
+const ALIVE_BURST_COUNT: usize = 3;
+
+const ALIVE_BURST_SPACING: Duration = Duration::from_millis(200);
 
 pub struct SSDPBroadcast {
-    ssdp_socket: Arc<UdpSocket>,
+    network: Arc<SSDPNetwork>,
     ssdp_helper: Arc<InteractiveSSDP>,
+    rediscovery: Option<Rediscovery>,
+    scope: InterfaceScope,
 }
 
 impl SSDPBroadcast {
-    pub fn new(ssdp_socket: Arc<UdpSocket>, ssdp_helper: Arc<InteractiveSSDP>) -> Self {
+    pub fn new(
+        network: Arc<SSDPNetwork>,
+        ssdp_helper: Arc<InteractiveSSDP>,
+        rediscovery: Option<Rediscovery>,
+        scope: InterfaceScope,
+    ) -> Self {
         SSDPBroadcast {
-            ssdp_socket,
+            network,
             ssdp_helper,
+            rediscovery,
+            scope,
         }
     }
 
+    /// Channels this server is announced on.
+    pub fn channels(&self) -> Vec<SSDPChannel> {
+        self.scope.filter(self.network.channels())
+    }
+
     pub async fn do_ssdp_alive(&self) -> Result<()> {
+        self.ssdp_helper.send_alive(&self.channels()).await
+    }
+
+    pub async fn do_ssdp_byebye(&self) -> Result<()> {
+        self.ssdp_helper.send_byebye(&self.channels()).await
+    }
+
+    async fn do_reannounce_if_changed(&self, info: Arc<EndpointInfo>) -> Result<bool> {
         self.ssdp_helper
-            .send_alive(self.ssdp_socket.borrow(), SSDP_ADDRESS)
+            .reannounce_if_changed(&self.channels(), info)
             .await
     }
+
+    /// Look for a discovered origin again after a failed check. Returns whether it moved.
+    async fn do_rediscover(&self) -> bool {
+        let Some(rediscovery) = &self.rediscovery else {
+            return false;
+        };
+
+        match rediscovery.rediscover(&self.ssdp_helper).await {
+            Ok(true) => {
+                for address in lan_addresses(self.network.ip_version()) {
+                    self.ssdp_helper.relocate(address);
+                }
+                true
+            }
+            Ok(false) => false,
+            Err(e) => {
+                warn!(target: "dlnaproxy", "Couldn't re-discover the remote server at {}: {}", rediscovery.target(), e);
+                false
+            }
+        }
+    }
+
+    async fn do_alive_burst(&self) -> Result<()> {
+        for i in 0..ALIVE_BURST_COUNT {
+            if i > 0 {
+                time::sleep(ALIVE_BURST_SPACING).await;
+            }
+            self.do_ssdp_alive().await?;
+        }
+        Ok(())
+    }
 }
 
 pub async fn broadcast_task(broadcaster: Arc<SSDPBroadcast>, period: Duration) {
-    let _handle = tokio::spawn(shutdown_handler(broadcaster.clone()));
-
     debug!(target: "dlnaproxy", "About to schedule broadcast every {}s", period.as_secs());
 
     let mut interval = time::interval(period);
 
     loop {
-        if let Err(msg) = broadcaster.do_ssdp_alive().await {
+        // The first announcement is made by the monitor task once the origin is up.
+        let state = broadcaster.ssdp_helper.origin_state();
+
+        if state != OriginState::Up {
+            debug!(target: "dlnaproxy", "Origin {} is {}, not broadcasting.", broadcaster.ssdp_helper.description_url(), state);
+        } else if let Err(msg) = broadcaster.do_ssdp_alive().await {
             warn!(target: "dlnaproxy", "Couldn't send ssdp:alive: {}. Will retry next interval.", msg);
             // Continue instead of break - origin may come back online
         } else {
@@ -53,6 +118,145 @@ pub async fn broadcast_task(broadcaster: Arc<SSDPBroadcast>, period: Duration) {
     }
 }
 
+/// Polls the origin, keeping the cached description fresh and tracking its health.
+///
+/// When the origin is declared down, clients are told with an ssdp:byebye so they don't
+/// keep a ghost entry until max-age expires; when it comes back, an alive burst
+/// re-announces it right away instead of waiting for the next broadcast.
+///
+/// A discovered origin is looked for again whenever a check fails, and checked
+/// right away at its new address when it moved.
+pub async fn monitor_task(broadcaster: Arc<SSDPBroadcast>) {
+    let helper = broadcaster.ssdp_helper.clone();
+    let poll_interval = helper.poll_interval();
+
+    debug!(target: "dlnaproxy", "Checking {} every {}s", helper.description_url(), poll_interval.as_secs());
+
+    let mut moved = false;
+
+    loop {
+        let result = helper.refresh_endpoint_info().await;
+
+        match &result {
+            Ok(info) if helper.origin_state() == OriginState::Up => {
+                if let Err(e) = broadcaster.do_reannounce_if_changed(info.clone()).await {
+                    warn!(target: "dlnaproxy", "Failed to re-announce {}: {}", helper.description_url(), e);
+                }
+
+                // Clients have to learn the new LOCATION.
+                if moved {
+                    if let Err(e) = broadcaster.do_alive_burst().await {
+                        warn!(target: "dlnaproxy", "Failed to send ssdp:alive: {}", e);
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!(target: "dlnaproxy", "Couldn't revalidate {}'s description: {}", helper.description_url(), e);
+            }
+        }
+
+        match helper.record_health_check(result.is_ok()) {
+            Some(Transition::WentDown) => {
+                warn!(target: "dlnaproxy", "Origin {} is down, sending ssdp:byebye.", helper.description_url());
+
+                if let Err(e) = broadcaster.do_ssdp_byebye().await {
+                    warn!(target: "dlnaproxy", "Failed to send ssdp:byebye: {}", e);
+                }
+            }
+            Some(Transition::WentUp) => {
+                info!(target: "dlnaproxy", "Origin {} is up, announcing it.", helper.description_url());
+
+                if let Err(e) = broadcaster.do_alive_burst().await {
+                    warn!(target: "dlnaproxy", "Failed to send ssdp:alive: {}", e);
+                }
+            }
+            None => {}
+        }
+
+        moved = result.is_err() && broadcaster.do_rediscover().await;
+        if moved {
+            continue;
+        }
+
+        tokio::select! {
+            _ = time::sleep(poll_interval) => {}
+            _ = helper.revalidation_requested() => {}
+        }
+    }
+}
+
+/// Follows network changes: interfaces and addresses coming and going, and our
+/// LAN address for proxies listening on every address.
+///
+/// Clients are told with an ssdp:byebye about interfaces that went away. When we can
+/// be reached somewhere new, UPnP 1.1 requires an ssdp:update announcing the next
+/// BOOTID before everything is re-announced with it.
+pub async fn network_task(network: Arc<SSDPNetwork>, broadcasters: Vec<Arc<SSDPBroadcast>>, boot_id: Arc<BootId>) {
+    let mut watcher = NetworkWatcher::new();
+
+    loop {
+        watcher.changed().await;
+
+        let change = network.refresh();
+
+        // BOOTID is shared by every server, so all of them have to be re-announced.
+        let announced: Vec<_> = broadcasters
+            .iter()
+            .filter(|b| b.ssdp_helper.origin_state() == OriginState::Up)
+            .collect();
+
+        if !change.removed.is_empty() {
+            for broadcaster in &announced {
+                if let Err(e) = broadcaster.ssdp_helper.withdraw(&broadcaster.scope.filter(change.removed.clone())).await {
+                    debug!(target: "dlnaproxy", "Couldn't send ssdp:byebye on a removed interface: {}", e);
+                }
+            }
+        }
+
+        let mut relocated = false;
+
+        if network.uses_default_route() && broadcasters.iter().any(|b| b.ssdp_helper.is_relocatable()) {
+            let addresses = lan_addresses(network.ip_version());
+
+            if addresses.is_empty() {
+                debug!(target: "dlnaproxy", "No route to the SSDP multicast groups, keeping LOCATION as is.");
+            }
+
+            for address in &addresses {
+                for broadcaster in &broadcasters {
+                    relocated |= broadcaster.ssdp_helper.relocate(*address);
+                }
+            }
+
+            if relocated {
+                info!(target: "dlnaproxy", "LAN address(es) now {:?}, re-announcing.", addresses);
+            }
+        }
+
+        if change.added.is_empty() && !relocated {
+            continue;
+        }
+
+        let next_boot_id = boot_id.next();
+        for broadcaster in &announced {
+            let kept = broadcaster.scope.filter(change.kept.clone());
+
+            if let Err(e) = broadcaster.ssdp_helper.send_update(&kept, next_boot_id).await {
+                warn!(target: "dlnaproxy", "Failed to send ssdp:update: {}", e);
+            }
+        }
+
+        boot_id.advance();
+
+        for broadcaster in &announced {
+            if let Err(e) = broadcaster.do_alive_burst().await {
+                warn!(target: "dlnaproxy", "Failed to send ssdp:alive: {}", e);
+            }
+        }
+    }
+}
+
 /// Waits for a shutdown signal (SIGINT or SIGTERM on Unix, Ctrl+C on Windows)
 async fn wait_for_shutdown_signal() -> Result<&'static str> {
     #[cfg(unix)]
@@ -79,7 +283,7 @@ async fn wait_for_shutdown_signal() -> Result<&'static str> {
     }
 }
 
-pub async fn shutdown_handler(broadcaster: Arc<SSDPBroadcast>) -> Result<()> {
+pub async fn shutdown_handler(broadcasters: Vec<Arc<SSDPBroadcast>>) -> Result<()> {
     debug!(target:"dlnaproxy", "Shutdown handler waiting for SIGINT/SIGTERM...");
 
     let signal_name = match wait_for_shutdown_signal().await {
@@ -92,17 +296,33 @@ pub async fn shutdown_handler(broadcaster: Arc<SSDPBroadcast>) -> Result<()> {
         }
     };
 
-    let socket = broadcaster.ssdp_socket.clone();
-    let helper = broadcaster.ssdp_helper.clone();
-
     debug!(target:"dlnaproxy", "{} received, sending ssdp:byebye!", signal_name);
 
-    // Use a timeout for the byebye message to ensure we exit promptly
-    let byebye_future = helper.send_byebye(&socket, SSDP_ADDRESS);
-    match time::timeout(Duration::from_secs(2), byebye_future).await {
-        Ok(Ok(())) => {}
-        Ok(Err(msg)) => warn!(target: "dlnaproxy", "Failed to send ssdp:byebye: {}", msg),
-        Err(_) => warn!(target: "dlnaproxy", "Timeout sending ssdp:byebye"),
+    // Say goodbye on behalf of every server concurrently, so one slow origin
+    // doesn't eat the whole shutdown budget of the others.
+    let mut byebyes = JoinSet::new();
+    for broadcaster in broadcasters {
+        byebyes.spawn(async move {
+            broadcaster
+                .ssdp_helper
+                .send_byebye(&broadcaster.channels())
+                .await
+        });
+    }
+
+    // Use a timeout for the byebye messages to ensure we exit promptly
+    let all_byebyes = async {
+        while let Some(result) = byebyes.join_next().await {
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(msg)) => warn!(target: "dlnaproxy", "Failed to send ssdp:byebye: {}", msg),
+                Err(e) => warn!(target: "dlnaproxy", "ssdp:byebye task failed: {}", e),
+            }
+        }
+    };
+
+    if time::timeout(Duration::from_secs(2), all_byebyes).await.is_err() {
+        warn!(target: "dlnaproxy", "Timeout sending ssdp:byebye");
     }
 
     info!(target: "dlnaproxy", "Exiting!");
@@ -0,0 +1,238 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use crate::ssdp::utils::EndpointInfo;
+
+/// CONFIGID.UPNP.ORG must fit in 24 bits.
+const MAX_CONFIG_ID: u32 = (1 << 24) - 1;
+
+/// How usable a cached description still is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    /// Younger than the TTL, serve as is.
+    Fresh,
+    /// Past the TTL but within the stale window: serve it, but revalidate in the background.
+    Stale,
+    /// Too old to be served.
+    Expired,
+}
+
+struct CachedDescription {
+    info: Arc<EndpointInfo>,
+    validated_at: Instant,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// Hash of the description body, to tell whether it actually changed.
+    fingerprint: u64,
+    config_id: u32,
+}
+
+/// Validators to send along a conditional GET.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Last description fetched from the origin, along with its HTTP validators.
+pub struct DescriptionCache {
+    ttl: Duration,
+    max_stale: Duration,
+    entry: RwLock<Option<CachedDescription>>,
+}
+
+impl DescriptionCache {
+    pub fn new(ttl: Duration, max_stale: Duration) -> Self {
+        DescriptionCache {
+            ttl,
+            max_stale,
+            entry: RwLock::new(None),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Cached description and how fresh it is, if we ever fetched one.
+    pub fn get(&self) -> Option<(Arc<EndpointInfo>, Freshness)> {
+        self.get_at(Instant::now())
+    }
+
+    fn get_at(&self, now: Instant) -> Option<(Arc<EndpointInfo>, Freshness)> {
+        let entry = self.entry.read().unwrap();
+
+        entry.as_ref().map(|cached| {
+            let age = now.saturating_duration_since(cached.validated_at);
+
+            let freshness = if age < self.ttl {
+                Freshness::Fresh
+            } else if age < self.ttl + self.max_stale {
+                Freshness::Stale
+            } else {
+                Freshness::Expired
+            };
+
+            (cached.info.clone(), freshness)
+        })
+    }
+
+    pub fn validators(&self) -> Validators {
+        self.entry
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|cached| Validators {
+                etag: cached.etag.clone(),
+                last_modified: cached.last_modified.clone(),
+            })
+            .unwrap_or_default()
+    }
+
+    /// Drop the validators, which only hold for the URL they came from, keeping the
+    /// description until one is fetched from the new URL.
+    pub fn forget_validators(&self) {
+        if let Some(cached) = self.entry.write().unwrap().as_mut() {
+            cached.etag = None;
+            cached.last_modified = None;
+        }
+    }
+
+    /// CONFIGID.UPNP.ORG of the cached description: starts at 1 and is bumped
+    /// every time a description with a different body gets stored.
+    pub fn config_id(&self) -> u32 {
+        self.entry
+            .read()
+            .unwrap()
+            .as_ref()
+            .map_or(1, |cached| cached.config_id)
+    }
+
+    /// Store a freshly fetched description, `fingerprint` being a hash of its body.
+    pub fn store(&self, info: EndpointInfo, validators: Validators, fingerprint: u64) -> Arc<EndpointInfo> {
+        let info = Arc::new(info);
+        let mut entry = self.entry.write().unwrap();
+
+        let config_id = match entry.as_ref() {
+            None => 1,
+            Some(cached) if cached.fingerprint == fingerprint => cached.config_id,
+            Some(cached) if cached.config_id >= MAX_CONFIG_ID => 0,
+            Some(cached) => cached.config_id + 1,
+        };
+
+        *entry = Some(CachedDescription {
+            info: info.clone(),
+            validated_at: Instant::now(),
+            etag: validators.etag,
+            last_modified: validators.last_modified,
+            fingerprint,
+            config_id,
+        });
+
+        info
+    }
+
+    /// The origin confirmed (304 Not Modified) that our copy is still current.
+    pub fn revalidated(&self) -> Option<Arc<EndpointInfo>> {
+        let mut entry = self.entry.write().unwrap();
+
+        entry.as_mut().map(|cached| {
+            cached.validated_at = Instant::now();
+            cached.info.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssdp::utils::DeviceInfo;
+
+    fn endpoint_info() -> EndpointInfo {
+        EndpointInfo {
+            devices: vec![DeviceInfo {
+                device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+                unique_device_name: "uuid:test".to_string(),
+                service_types: vec![],
+            }],
+            server: "Test/1.0".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_empty_cache() {
+        let cache = DescriptionCache::new(Duration::from_secs(60), Duration::from_secs(600));
+        assert!(cache.get().is_none());
+        assert_eq!(cache.validators(), Validators::default());
+        assert!(cache.revalidated().is_none());
+    }
+
+    #[test]
+    fn test_freshness_over_time() {
+        let cache = DescriptionCache::new(Duration::from_secs(60), Duration::from_secs(600));
+        cache.store(endpoint_info(), Validators::default(), 0);
+
+        let now = Instant::now();
+        assert_eq!(cache.get_at(now).unwrap().1, Freshness::Fresh);
+        assert_eq!(cache.get_at(now + Duration::from_secs(61)).unwrap().1, Freshness::Stale);
+        assert_eq!(cache.get_at(now + Duration::from_secs(661)).unwrap().1, Freshness::Expired);
+    }
+
+    #[test]
+    fn test_validators_are_kept() {
+        let cache = DescriptionCache::new(Duration::from_secs(60), Duration::from_secs(600));
+        let validators = Validators {
+            etag: Some("\"abc\"".to_string()),
+            last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+        };
+
+        cache.store(endpoint_info(), validators.clone(), 0);
+        assert_eq!(cache.validators(), validators);
+    }
+
+    #[test]
+    fn test_forget_validators() {
+        let cache = DescriptionCache::new(Duration::from_secs(60), Duration::from_secs(600));
+        let validators = Validators {
+            etag: Some("\"abc\"".to_string()),
+            last_modified: None,
+        };
+
+        cache.store(endpoint_info(), validators, 1234);
+        cache.forget_validators();
+
+        assert_eq!(cache.validators(), Validators::default());
+        assert!(cache.get().is_some());
+        assert_eq!(cache.config_id(), 1);
+    }
+
+    #[test]
+    fn test_revalidated_returns_cached_info() {
+        let cache = DescriptionCache::new(Duration::ZERO, Duration::from_secs(600));
+        cache.store(endpoint_info(), Validators::default(), 0);
+        assert_eq!(cache.get().unwrap().1, Freshness::Stale);
+
+        let info = cache.revalidated().unwrap();
+        assert_eq!(info.devices[0].unique_device_name, "uuid:test");
+    }
+
+    #[test]
+    fn test_config_id_bumped_on_change_only() {
+        let cache = DescriptionCache::new(Duration::from_secs(60), Duration::from_secs(600));
+        assert_eq!(cache.config_id(), 1);
+
+        cache.store(endpoint_info(), Validators::default(), 1234);
+        assert_eq!(cache.config_id(), 1);
+
+        cache.store(endpoint_info(), Validators::default(), 1234);
+        assert_eq!(cache.config_id(), 1);
+
+        cache.store(endpoint_info(), Validators::default(), 5678);
+        assert_eq!(cache.config_id(), 2);
+
+        cache.revalidated();
+        assert_eq!(cache.config_id(), 2);
+    }
+}
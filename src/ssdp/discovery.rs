@@ -0,0 +1,351 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use httparse::{Response, EMPTY_HEADER};
+use log::{debug, info, trace};
+use reqwest::Url;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time;
+
+use crate::config::sockaddr_from_url;
+use crate::ssdp::unicast::parse_target;
+use crate::ssdp::utils::{endpoint_urls, DLNADescription, InteractiveSSDP};
+use crate::tcp_proxy::OriginAddr;
+
+const MEDIA_SERVER: &str = "urn:schemas-upnp-org:device:MediaServer:1";
+
+/// How long responses to our M-SEARCH are collected.
+const RESPONSE_WINDOW: Duration = Duration::from_secs(2);
+
+/// Finds a device, a MediaServer unless told otherwise, by sending unicast
+/// M-SEARCH queries, for links that carry unicast but not multicast (e.g. a VPN).
+#[derive(Debug, Clone)]
+pub struct Discovery {
+    /// Where to send the queries, a single host or every host of a range.
+    target: String,
+    hosts: Vec<IpAddr>,
+    device_type: String,
+    friendly_name: Option<String>,
+    udn: Option<String>,
+}
+
+impl Discovery {
+    /// `target` is an IP address or a CIDR range, `device_type` a full device type
+    /// URN (MediaServer:1 by default), `friendly_name` and `udn` pick a device when
+    /// several answer.
+    pub fn new(
+        target: &str,
+        device_type: Option<String>,
+        friendly_name: Option<String>,
+        udn: Option<String>,
+    ) -> Result<Self> {
+        let device_type = device_type.unwrap_or_else(|| MEDIA_SERVER.to_string());
+
+        if device_type.split(':').count() != 5 || !device_type.starts_with("urn:") {
+            bail!("Invalid device type {}: expected e.g. {}", device_type, MEDIA_SERVER);
+        }
+
+        Ok(Discovery {
+            target: target.to_string(),
+            hosts: parse_target(target)?,
+            device_type,
+            friendly_name,
+            udn,
+        })
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// Description URL of the first device that answers and matches the filters.
+    pub async fn discover(&self, client: &reqwest::Client) -> Result<Url> {
+        let locations = self.search().await?;
+
+        if locations.is_empty() {
+            bail!("No {} answered at {}", self.device_type, self.target);
+        }
+
+        for location in locations {
+            match self.matches(client, &location).await {
+                Ok(true) => {
+                    info!(target: "dlnaproxy", "Discovered {} at {}", self.device_type, location);
+                    return Ok(location);
+                }
+                Ok(false) => debug!(target: "dlnaproxy", "Skipping device at {}: no match.", location),
+                Err(e) => debug!(target: "dlnaproxy", "Skipping device at {}: {}", location, e),
+            }
+        }
+
+        bail!("No {} at {} matches the configured friendly_name/udn", self.device_type, self.target)
+    }
+
+    /// LOCATIONs of the devices answering, in the order they did.
+    async fn search(&self) -> Result<Vec<Url>> {
+        let mut sockets = Vec::new();
+
+        for ipv6 in [false, true] {
+            let hosts: Vec<_> = self.hosts.iter().filter(|h| h.is_ipv6() == ipv6).collect();
+            if hosts.is_empty() {
+                continue;
+            }
+
+            let unspecified: IpAddr = if ipv6 {
+                Ipv6Addr::UNSPECIFIED.into()
+            } else {
+                Ipv4Addr::UNSPECIFIED.into()
+            };
+
+            let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0))
+                .await
+                .context("Failed to bind discovery socket")?;
+
+            for host in hosts {
+                let dest = SocketAddr::new(*host, 1900);
+
+                if let Err(e) = socket.send_to(m_search(dest, &self.device_type).as_bytes(), dest).await {
+                    debug!(target: "dlnaproxy", "Couldn't send M-SEARCH to {}: {}", dest, e);
+                }
+            }
+
+            sockets.push(socket);
+        }
+
+        // Each socket gets its own receiving task, they are all aborted with the JoinSet.
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut receivers = JoinSet::new();
+
+        for socket in sockets {
+            let tx = tx.clone();
+
+            receivers.spawn(async move {
+                let mut buffer = [0u8; 2048];
+
+                while let Ok((len, src)) = socket.recv_from(&mut buffer).await {
+                    if tx.send((src, buffer[..len].to_vec())).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        let mut locations = Vec::new();
+        let deadline = time::sleep(RESPONSE_WINDOW);
+        tokio::pin!(deadline);
+
+        loop {
+            let (src, packet) = tokio::select! {
+                _ = &mut deadline => break,
+                Some(received) = rx.recv() => received,
+            };
+
+            trace!(target: "dlnaproxy", "Discovery response from {}", src);
+
+            match parse_location(&packet, &self.device_type) {
+                Ok(location) if !locations.contains(&location) => locations.push(location),
+                Ok(_) => {}
+                Err(e) => debug!(target: "dlnaproxy", "Ignoring discovery response from {}: {}", src, e),
+            }
+        }
+
+        Ok(locations)
+    }
+
+    async fn matches(&self, client: &reqwest::Client, location: &Url) -> Result<bool> {
+        if self.friendly_name.is_none() && self.udn.is_none() {
+            return Ok(true);
+        }
+
+        let body = client
+            .get(location.clone())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context("Failed to get description")?
+            .text()
+            .await
+            .context("Failed to read description")?;
+
+        let description: DLNADescription =
+            quick_xml::de::from_str(&body).context("Failed to parse description")?;
+
+        Ok(self.matches_device(&description.device.friendly_name, &description.device.unique_device_name))
+    }
+
+    fn matches_device(&self, friendly_name: &str, udn: &str) -> bool {
+        let name_matches = self
+            .friendly_name
+            .as_deref()
+            .is_none_or(|wanted| wanted.trim() == friendly_name.trim());
+
+        // "uuid:" is optional in the configuration.
+        let udn_matches = self.udn.as_deref().is_none_or(|wanted| {
+            let strip = |udn: &str| udn.trim().trim_start_matches("uuid:").to_ascii_lowercase();
+            strip(wanted) == strip(udn)
+        });
+
+        name_matches && udn_matches
+    }
+}
+
+/// Looks for a discovered server again once its description can't be fetched
+/// anymore, e.g. because it restarted on another port.
+pub struct Rediscovery {
+    discovery: Discovery,
+    http_client: reqwest::Client,
+    /// Proxy address and the origin it forwards to, when the server is proxied.
+    proxy: Option<(SocketAddr, OriginAddr)>,
+    origin_url: std::sync::Mutex<Url>,
+}
+
+impl Rediscovery {
+    pub fn new(
+        discovery: Discovery,
+        http_client: reqwest::Client,
+        origin_url: Url,
+        proxy: Option<(SocketAddr, OriginAddr)>,
+    ) -> Self {
+        Rediscovery {
+            discovery,
+            http_client,
+            proxy,
+            origin_url: std::sync::Mutex::new(origin_url),
+        }
+    }
+
+    pub fn target(&self) -> &str {
+        self.discovery.target()
+    }
+
+    /// Discover the server again and point `ssdp_helper` and the proxy at it.
+    /// Returns whether it moved.
+    pub async fn rediscover(&self, ssdp_helper: &InteractiveSSDP) -> Result<bool> {
+        let origin_url = self.discovery.discover(&self.http_client).await?;
+
+        if *self.origin_url.lock().unwrap() == origin_url {
+            return Ok(false);
+        }
+
+        if let Some((_, origin)) = &self.proxy {
+            origin.set(sockaddr_from_url(&origin_url)?);
+        }
+
+        let (url, location) = endpoint_urls(&origin_url, self.proxy.as_ref().map(|(addr, _)| *addr));
+        ssdp_helper.retarget(url.as_str(), location);
+
+        info!(target: "dlnaproxy", "Remote server moved to {}", origin_url);
+        *self.origin_url.lock().unwrap() = origin_url;
+
+        Ok(true)
+    }
+}
+
+fn m_search(dest: SocketAddr, device_type: &str) -> String {
+    format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+        HOST: {}\r\n\
+        MAN: \"ssdp:discover\"\r\n\
+        ST: {}\r\n\
+        \r\n",
+        dest, device_type
+    )
+}
+
+/// LOCATION of an M-SEARCH response for a device of `device_type`, in any version.
+fn parse_location(buffer: &[u8], device_type: &str) -> Result<Url> {
+    let mut headers = [EMPTY_HEADER; 16];
+    let mut response = Response::new(&mut headers);
+
+    response.parse(buffer).context("Not an HTTP response")?;
+
+    if response.code != Some(200) {
+        bail!("status {:?}", response.code);
+    }
+
+    let header = |name: &str| {
+        response
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| String::from_utf8_lossy(h.value).trim().to_string())
+    };
+
+    let without_version = device_type.rsplit_once(':').map_or(device_type, |(urn, _)| urn);
+
+    if header("ST").is_some_and(|st| st.rsplit_once(':').map_or(st.as_str(), |(urn, _)| urn) != without_version) {
+        bail!("not a {}", without_version);
+    }
+
+    let location = header("LOCATION").ok_or(anyhow!("no LOCATION"))?;
+
+    Url::parse(&location).context("bad LOCATION")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_location() {
+        let response = b"HTTP/1.1 200 OK\r\n\
+            CACHE-CONTROL: max-age=1800\r\n\
+            LOCATION: http://10.8.0.5:8200/rootDesc.xml\r\n\
+            ST: urn:schemas-upnp-org:device:MediaServer:1\r\n\
+            USN: uuid:4d696e69-444c-164e-9d41-b827eb5a31b6::urn:schemas-upnp-org:device:MediaServer:1\r\n\
+            \r\n";
+
+        assert_eq!(
+            parse_location(response, MEDIA_SERVER).unwrap().as_str(),
+            "http://10.8.0.5:8200/rootDesc.xml"
+        );
+    }
+
+    #[test]
+    fn test_parse_location_other_device() {
+        let response = b"HTTP/1.1 200 OK\r\n\
+            LOCATION: http://10.8.0.1:5000/igd.xml\r\n\
+            ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+            \r\n";
+
+        assert!(parse_location(response, MEDIA_SERVER).is_err());
+    }
+
+    #[test]
+    fn test_parse_location_renderer() {
+        let response = b"HTTP/1.1 200 OK\r\n\
+            LOCATION: http://192.168.1.30:49152/description.xml\r\n\
+            ST: urn:schemas-upnp-org:device:MediaRenderer:1\r\n\
+            \r\n";
+
+        let renderer = "urn:schemas-upnp-org:device:MediaRenderer:2";
+
+        assert!(parse_location(response, renderer).is_ok());
+        assert!(parse_location(response, MEDIA_SERVER).is_err());
+    }
+
+    #[test]
+    fn test_matches_device() {
+        let discovery = Discovery::new(
+            "10.8.0.5",
+            None,
+            Some("NAS".into()),
+            Some("4D696E69-444C-164E-9D41-B827EB5A31B6".into()),
+        )
+        .unwrap();
+
+        assert!(discovery.matches_device("NAS", "uuid:4d696e69-444c-164e-9d41-b827eb5a31b6"));
+        assert!(!discovery.matches_device("NAS", "uuid:00000000-0000-0000-0000-000000000000"));
+        assert!(!discovery.matches_device("Laptop", "uuid:4d696e69-444c-164e-9d41-b827eb5a31b6"));
+    }
+
+    #[test]
+    fn test_m_search() {
+        let query = m_search("10.8.0.5:1900".parse().unwrap(), MEDIA_SERVER);
+
+        assert!(query.starts_with("M-SEARCH * HTTP/1.1\r\nHOST: 10.8.0.5:1900\r\n"));
+        assert!(query.contains("ST: urn:schemas-upnp-org:device:MediaServer:1\r\n"));
+    }
+}
@@ -0,0 +1,145 @@
+use std::fmt;
+
+/// What we currently believe about the remote server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OriginState {
+    /// Not enough checks yet to tell (e.g. right after startup).
+    Unknown,
+    Up,
+    Down,
+}
+
+impl fmt::Display for OriginState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OriginState::Unknown => write!(f, "unknown"),
+            OriginState::Up => write!(f, "up"),
+            OriginState::Down => write!(f, "down"),
+        }
+    }
+}
+
+/// State change resulting from a health check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    WentUp,
+    WentDown,
+}
+
+/// Debounced origin state: it takes `down_after` consecutive failed checks to
+/// declare the origin down, and `up_after` consecutive successful ones to
+/// declare it up again.
+pub struct OriginHealth {
+    state: OriginState,
+    consecutive_failures: u32,
+    consecutive_successes: u32,
+    down_after: u32,
+    up_after: u32,
+}
+
+impl OriginHealth {
+    pub fn new(down_after: u32, up_after: u32) -> Self {
+        OriginHealth {
+            state: OriginState::Unknown,
+            consecutive_failures: 0,
+            consecutive_successes: 0,
+            down_after: down_after.max(1),
+            up_after: up_after.max(1),
+        }
+    }
+
+    pub fn state(&self) -> OriginState {
+        self.state
+    }
+
+    pub fn record_success(&mut self) -> Option<Transition> {
+        self.consecutive_failures = 0;
+        self.consecutive_successes = self.consecutive_successes.saturating_add(1);
+
+        if self.state != OriginState::Up && self.consecutive_successes >= self.up_after {
+            self.state = OriginState::Up;
+            return Some(Transition::WentUp);
+        }
+
+        None
+    }
+
+    pub fn record_failure(&mut self) -> Option<Transition> {
+        self.consecutive_successes = 0;
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+
+        if self.state != OriginState::Down && self.consecutive_failures >= self.down_after {
+            self.state = OriginState::Down;
+            return Some(Transition::WentDown);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_starts_unknown() {
+        assert_eq!(OriginHealth::new(3, 1).state(), OriginState::Unknown);
+    }
+
+    #[test]
+    fn test_first_success_goes_up() {
+        let mut health = OriginHealth::new(3, 1);
+        assert_eq!(health.record_success(), Some(Transition::WentUp));
+        assert_eq!(health.state(), OriginState::Up);
+        assert_eq!(health.record_success(), None);
+    }
+
+    #[test]
+    fn test_goes_down_after_threshold() {
+        let mut health = OriginHealth::new(3, 1);
+        health.record_success();
+
+        assert_eq!(health.record_failure(), None);
+        assert_eq!(health.record_failure(), None);
+        assert_eq!(health.state(), OriginState::Up);
+        assert_eq!(health.record_failure(), Some(Transition::WentDown));
+        assert_eq!(health.state(), OriginState::Down);
+        assert_eq!(health.record_failure(), None);
+    }
+
+    #[test]
+    fn test_success_resets_failure_count() {
+        let mut health = OriginHealth::new(2, 1);
+        health.record_success();
+
+        health.record_failure();
+        health.record_success();
+        assert_eq!(health.record_failure(), None);
+        assert_eq!(health.state(), OriginState::Up);
+    }
+
+    #[test]
+    fn test_recovery_threshold() {
+        let mut health = OriginHealth::new(1, 2);
+        assert_eq!(health.record_failure(), Some(Transition::WentDown));
+
+        assert_eq!(health.record_success(), None);
+        assert_eq!(health.state(), OriginState::Down);
+        assert_eq!(health.record_success(), Some(Transition::WentUp));
+    }
+
+    #[test]
+    fn test_unknown_can_go_down() {
+        let mut health = OriginHealth::new(2, 1);
+        health.record_failure();
+        assert_eq!(health.state(), OriginState::Unknown);
+        assert_eq!(health.record_failure(), Some(Transition::WentDown));
+    }
+
+    #[test]
+    fn test_zero_thresholds_behave_as_one() {
+        let mut health = OriginHealth::new(0, 0);
+        assert_eq!(health.record_failure(), Some(Transition::WentDown));
+        assert_eq!(health.record_success(), Some(Transition::WentUp));
+    }
+}
@@ -0,0 +1,216 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::Result;
+
+/// Which network interfaces SSDP runs on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum InterfaceSelection {
+    /// No interface binding, the routing table decides.
+    #[default]
+    Default,
+    /// Only the listed interfaces, given by name or by one of their IP addresses.
+    Only(Vec<String>),
+    /// Every multicast-capable interface but the listed ones.
+    AllExcept(Vec<String>),
+}
+
+/// A network interface SSDP runs on, with its addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub index: u32,
+    pub addresses: Vec<IpAddr>,
+}
+
+impl NetworkInterface {
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        self.addresses.iter().find_map(|addr| match addr {
+            IpAddr::V4(v4) => Some(*v4),
+            IpAddr::V6(_) => None,
+        })
+    }
+
+    /// Preferred IPv6 address: a routable one over a link-local one, which
+    /// would need a zone id to be usable in a URL.
+    pub fn ipv6(&self) -> Option<Ipv6Addr> {
+        let mut v6 = self.addresses.iter().filter_map(|addr| match addr {
+            IpAddr::V6(v6) => Some(*v6),
+            IpAddr::V4(_) => None,
+        });
+
+        let first = v6.next()?;
+        if !is_link_local(&first) {
+            return Some(first);
+        }
+
+        Some(v6.find(|addr| !is_link_local(addr)).unwrap_or(first))
+    }
+}
+
+fn is_link_local(addr: &Ipv6Addr) -> bool {
+    (addr.segments()[0] & 0xffc0) == 0xfe80
+}
+
+/// One address of an interface, as reported by the OS.
+struct InterfaceEntry {
+    name: String,
+    up: bool,
+    multicast: bool,
+    loopback: bool,
+    address: Option<IpAddr>,
+}
+
+/// Group the addresses of the interfaces matching `selection`, in the order the OS lists them.
+///
+/// Interfaces that are missing or have no address yet are left out, they may come up later.
+fn select(selection: &InterfaceSelection, entries: impl IntoIterator<Item = InterfaceEntry>) -> Vec<(String, Vec<IpAddr>)> {
+    let mut selected: Vec<(String, Vec<IpAddr>)> = Vec::new();
+
+    for entry in entries {
+        let wanted = match selection {
+            InterfaceSelection::Default => false,
+            InterfaceSelection::Only(names) => names.iter().any(|name| {
+                // An IP address only selects itself, not the other addresses of its interface.
+                *name == entry.name || name.parse::<IpAddr>().ok() == entry.address
+            }),
+            InterfaceSelection::AllExcept(names) => {
+                entry.up && entry.multicast && !entry.loopback && !names.contains(&entry.name)
+            }
+        };
+
+        let Some(address) = entry.address.filter(|_| wanted) else {
+            continue;
+        };
+
+        match selected.iter_mut().find(|(name, _)| *name == entry.name) {
+            Some((_, addresses)) => addresses.push(address),
+            None => selected.push((entry.name, vec![address])),
+        }
+    }
+
+    selected
+}
+
+/// Interfaces matching `selection`, which must not be `InterfaceSelection::Default`.
+#[cfg(unix)]
+pub fn network_interfaces(selection: &InterfaceSelection) -> Result<Vec<NetworkInterface>> {
+    use anyhow::Context;
+    use nix::ifaddrs::getifaddrs;
+    use nix::net::if_::{if_nametoindex, InterfaceFlags};
+
+    let entries = getifaddrs()
+        .context("Failed to list network interfaces")?
+        .map(|ifaddr| InterfaceEntry {
+            up: ifaddr.flags.contains(InterfaceFlags::IFF_UP),
+            multicast: ifaddr.flags.contains(InterfaceFlags::IFF_MULTICAST),
+            loopback: ifaddr.flags.contains(InterfaceFlags::IFF_LOOPBACK),
+            address: ifaddr.address.and_then(|addr| {
+                addr.as_sockaddr_in()
+                    .map(|v4| IpAddr::V4(v4.ip()))
+                    .or_else(|| addr.as_sockaddr_in6().map(|v6| IpAddr::V6(v6.ip())))
+            }),
+            name: ifaddr.interface_name,
+        });
+
+    select(selection, entries)
+        .into_iter()
+        .map(|(name, addresses)| {
+            let index = if_nametoindex(name.as_str())
+                .with_context(|| format!("Failed to get index of interface {}", name))?;
+
+            Ok(NetworkInterface { name, index, addresses })
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn network_interfaces(_selection: &InterfaceSelection) -> Result<Vec<NetworkInterface>> {
+    anyhow::bail!("Selecting network interfaces is not supported on this platform")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, address: &str) -> InterfaceEntry {
+        InterfaceEntry {
+            name: name.to_string(),
+            up: true,
+            multicast: true,
+            loopback: name == "lo",
+            address: Some(address.parse().unwrap()),
+        }
+    }
+
+    fn entries() -> Vec<InterfaceEntry> {
+        vec![
+            entry("lo", "127.0.0.1"),
+            entry("eth0", "192.168.1.50"),
+            entry("eth0", "fd00::50"),
+            entry("wlan0", "10.0.0.2"),
+            entry("docker0", "172.17.0.1"),
+        ]
+    }
+
+    fn names(selected: &[(String, Vec<IpAddr>)]) -> Vec<&str> {
+        selected.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_select_only() {
+        let selection = InterfaceSelection::Only(vec!["wlan0".into(), "eth0".into()]);
+        let selected = select(&selection, entries());
+
+        assert_eq!(names(&selected), ["eth0", "wlan0"]);
+        assert_eq!(selected[0].1.len(), 2);
+    }
+
+    #[test]
+    fn test_select_only_by_address() {
+        let selection = InterfaceSelection::Only(vec!["fd00::50".into(), "10.0.0.2".into()]);
+        let selected = select(&selection, entries());
+
+        assert_eq!(names(&selected), ["eth0", "wlan0"]);
+        assert_eq!(selected[0].1, ["fd00::50".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn test_select_only_missing_interface() {
+        let selection = InterfaceSelection::Only(vec!["eth1".into()]);
+        assert!(select(&selection, entries()).is_empty());
+    }
+
+    #[test]
+    fn test_select_all_except() {
+        let selection = InterfaceSelection::AllExcept(vec!["docker0".into()]);
+        let selected = select(&selection, entries());
+
+        // Loopback is never picked implicitly.
+        assert_eq!(names(&selected), ["eth0", "wlan0"]);
+    }
+
+    #[test]
+    fn test_select_all_except_skips_down_interfaces() {
+        let mut entries = entries();
+        entries[3].up = false;
+
+        let selected = select(&InterfaceSelection::AllExcept(vec![]), entries);
+        assert_eq!(names(&selected), ["eth0", "docker0"]);
+    }
+
+    #[test]
+    fn test_preferred_ipv6() {
+        let iface = NetworkInterface {
+            name: "eth0".into(),
+            index: 2,
+            addresses: vec![
+                "fe80::1".parse().unwrap(),
+                "192.168.1.50".parse().unwrap(),
+                "fd00::50".parse().unwrap(),
+            ],
+        };
+
+        assert_eq!(iface.ipv4(), Some(Ipv4Addr::new(192, 168, 1, 50)));
+        assert_eq!(iface.ipv6(), Some("fd00::50".parse().unwrap()));
+    }
+}
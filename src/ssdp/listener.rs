@@ -1,22 +1,39 @@
 use log::{debug, error, info, trace, warn};
 
+use rand::Rng as _;
 use std::borrow::Cow;
-use std::{collections::HashMap, sync::Arc};
-use tokio::net::UdpSocket;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{net::UdpSocket, sync::Semaphore, time};
 
-use httparse::{Request, EMPTY_HEADER};
+use httparse::{Header, Request, Response, EMPTY_HEADER};
 
 use anyhow::Context;
 use anyhow::Result;
 
-use crate::ssdp::utils::InteractiveSSDP;
+use crate::ssdp::health::OriginState;
+use crate::ssdp::network::Responders;
+use crate::ssdp::unicast::UnicastTargets;
 
 /*
     SSDP RFC for reference: https://tools.ietf.org/html/draft-cai-ssdp-v1-03
 */
 
+/// UDA 1.1: devices should treat an MX greater than 5 as 5.
+pub(crate) const MAX_MX_SECONDS: u64 = 5;
+
+/// M-SEARCH queries waiting to be answered at the same time, on one socket.
+const MAX_PENDING_REPLIES: usize = 32;
+
+/// Most SSDP packets have about ten headers, some devices add quite a few of their own.
+const MAX_HEADERS: usize = 32;
+
 pub(crate) fn parse_ssdp(buffer: &[u8]) -> Result<(String, HashMap<String, Cow<'_, str>>)> {
-    let mut headers = [EMPTY_HEADER; 16];
+    let mut headers = [EMPTY_HEADER; MAX_HEADERS];
     let mut req = Request::new(&mut headers);
 
     req.parse(buffer)
@@ -27,22 +44,84 @@ pub(crate) fn parse_ssdp(buffer: &[u8]) -> Result<(String, HashMap<String, Cow<'
         .map(String::from)
         .ok_or(super::error::Error::NoSSDPMethod)?;
 
-    let mut header_map: HashMap<String, Cow<'_, str>> = HashMap::with_capacity(headers.len());
-    let mut i = 0;
-    while !headers[i].name.is_empty() {
-        let name = String::from(headers[i].name).to_uppercase();
-        let value = String::from_utf8_lossy(headers[i].value);
+    Ok((method, header_map(req.headers)))
+}
+
+/// Headers of an M-SEARCH response, which is an HTTP response rather than a request.
+pub(crate) fn parse_ssdp_response(buffer: &[u8]) -> Result<HashMap<String, Cow<'_, str>>> {
+    let mut headers = [EMPTY_HEADER; MAX_HEADERS];
+    let mut response = Response::new(&mut headers);
+
+    response
+        .parse(buffer)
+        .context("Failed to parse packet as SSDP response.")?;
+
+    if response.code != Some(200) {
+        anyhow::bail!("Unexpected SSDP response status {:?}", response.code);
+    }
+
+    Ok(header_map(response.headers))
+}
+
+fn header_map<'b>(headers: &[Header<'b>]) -> HashMap<String, Cow<'b, str>> {
+    headers
+        .iter()
+        .map(|header| (header.name.to_uppercase(), String::from_utf8_lossy(header.value)))
+        .collect()
+}
+
+/// Whether a packet was sent to an SSDP multicast group, going by its HOST header:
+/// 239.255.255.250:1900, [ff02::c]:1900 or [ff05::c]:1900.
+pub(crate) fn is_multicast(headers: &HashMap<String, Cow<'_, str>>) -> bool {
+    headers.get("HOST").is_some_and(|host| {
+        host.trim()
+            .parse::<SocketAddr>()
+            .is_ok_and(|addr| addr.ip().is_multicast())
+    })
+}
+
+/// How long to wait before answering an M-SEARCH, or why it must be ignored.
+///
+/// Multicast searches must carry `MAN: "ssdp:discover"` and a valid `MX`; responses are
+/// spread randomly over `[0, MX]` seconds (capped at 5) so that every device on the
+/// network doesn't answer at once. Unicast searches have no `MX` and are answered
+/// immediately.
+pub(crate) fn response_delay(headers: &HashMap<String, Cow<'_, str>>) -> Result<Duration, &'static str> {
+    let is_discover = headers
+        .get("MAN")
+        .is_some_and(|man| man.trim().trim_matches('"') == "ssdp:discover");
+
+    if !headers.contains_key("MX") && !is_multicast(headers) {
+        return Ok(Duration::ZERO);
+    }
+
+    if !is_discover {
+        return Err("missing or invalid MAN header");
+    }
+
+    let mx: u64 = headers
+        .get("MX")
+        .ok_or("missing MX header")?
+        .trim()
+        .parse()
+        .map_err(|_| "invalid MX header")?;
 
-        header_map.insert(name, value);
-        i += 1;
+    if mx == 0 {
+        return Err("MX must be at least 1");
     }
 
-    Ok((method, header_map))
+    let max_delay_ms = mx.min(MAX_MX_SECONDS) * 1000;
+
+    Ok(Duration::from_millis(rand::rng().random_range(0..=max_delay_ms)))
 }
 
-pub async fn listen_task(ssdp_socket: Arc<UdpSocket>, ssdp_helper: Arc<InteractiveSSDP>) {
+/// Answer M-SEARCH queries received on `ssdp_socket` for the servers `responders` picks,
+/// learning their senders as unicast targets.
+pub(super) async fn listen_task(ssdp_socket: Arc<UdpSocket>, responders: Responders, unicast: Arc<UnicastTargets>) {
     debug!(target: "dlnaproxy", "Listen task up and running!");
 
+    let replies = Arc::new(Semaphore::new(MAX_PENDING_REPLIES));
+
     loop {
         let mut buffer: [u8; 1024] = [0; 1024];
 
@@ -64,30 +143,59 @@ pub async fn listen_task(ssdp_socket: Arc<UdpSocket>, ssdp_helper: Arc<Interacti
             }
         };
 
-        let st_header = ssdp_headers.get("ST");
-        let _man_header = ssdp_headers.get("MAN");
-
-        //We have a valid ssdp:discover request, although the rfc is soooooo vague it hurts.
-        if let Some(header) = st_header {
-            // Respond to M-SEARCH requests for:
-            // - MediaServer:1 (specific device type)
-            // - ssdp:all (discover all devices)
-            // - upnp:rootdevice (discover all root devices)
-            let should_respond = ssdp_method == "M-SEARCH"
-                && (header == "urn:schemas-upnp-org:device:MediaServer:1"
-                    || header == "ssdp:all"
-                    || header == "upnp:rootdevice");
-
-            if should_respond {
-                info!(target: "dlnaproxy", "Responding to M-SEARCH request (ST: {st}) from {sender}.", st=header, sender=src_addr);
-
-                if let Err(msg) = ssdp_helper.send_ok(&ssdp_socket, src_addr).await {
-                    warn!(target: "dlnaproxy", "Couldn't send ssdp:alive: {}", msg);
-                } else {
-                    info!(target: "dlnaproxy", "Sent ssdp:ok on local SSDP channel!");
+        if ssdp_method != "M-SEARCH" {
+            continue;
+        }
+
+        let Some(search_target) = ssdp_headers.get("ST").map(|st| st.to_string()) else {
+            continue;
+        };
+
+        let delay = match response_delay(&ssdp_headers) {
+            Ok(delay) => delay,
+            Err(reason) => {
+                debug!(target: "dlnaproxy", "Ignoring M-SEARCH (ST: {st}) from {sender}: {reason}.", st=search_target, sender=src_addr, reason=reason);
+                continue;
+            }
+        };
+
+        trace!(target: "dlnaproxy", "M-SEARCH request (ST: {st}) from {sender}, replying in {delay}ms.", st=search_target, sender=src_addr, delay=delay.as_millis());
+
+        unicast.learn(src_addr.ip());
+
+        let Ok(permit) = replies.clone().try_acquire_owned() else {
+            debug!(target: "dlnaproxy", "Too many M-SEARCH queries waiting for a reply, dropping the one from {}.", src_addr);
+            continue;
+        };
+
+        let (interface_address, ssdp_helpers) = responders.answering(src_addr.ip(), !is_multicast(&ssdp_headers));
+
+        // Responses are scheduled on their own task so that a long MX doesn't hold up
+        // the other searches.
+        let ssdp_socket = ssdp_socket.clone();
+
+        tokio::spawn(async move {
+            let _permit = permit;
+            time::sleep(delay).await;
+
+            for ssdp_helper in ssdp_helpers.iter() {
+                // Don't advertise an origin we already said goodbye for.
+                if ssdp_helper.origin_state() == OriginState::Down {
+                    continue;
+                }
+
+                match ssdp_helper
+                    .send_ok(&ssdp_socket, src_addr, &search_target, interface_address)
+                    .await
+                {
+                    Ok(0) => {}
+                    Ok(count) => {
+                        info!(target: "dlnaproxy", "Responded to M-SEARCH request (ST: {st}) from {sender} with {count} ssdp:ok.", st=search_target, sender=src_addr, count=count);
+                    }
+                    Err(msg) => warn!(target: "dlnaproxy", "Couldn't send ssdp:ok: {}", msg),
                 }
             }
-        }
+        });
     }
 }
 
@@ -217,6 +325,36 @@ mod tests {
         assert!(headers.contains_key("ST"));
     }
 
+    #[test]
+    fn test_parse_ssdp_many_headers() {
+        let mut packet = String::from("NOTIFY * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\n");
+        for i in 0..20 {
+            packet.push_str(&format!("X-VENDOR-{}: {}\r\n", i, i));
+        }
+        packet.push_str("\r\n");
+
+        let (method, headers) = parse_ssdp(packet.as_bytes()).unwrap();
+        assert_eq!(method, "NOTIFY");
+        assert_eq!(headers.len(), 21);
+        assert!(is_multicast(&headers));
+    }
+
+    #[test]
+    fn test_parse_ssdp_response() {
+        let packet = b"HTTP/1.1 200 OK\r\n\
+            CACHE-CONTROL: max-age=1800\r\n\
+            LOCATION: http://192.168.10.20:49152/description.xml\r\n\
+            ST: upnp:rootdevice\r\n\
+            USN: uuid:tv::upnp:rootdevice\r\n\
+            \r\n";
+
+        let headers = parse_ssdp_response(packet).unwrap();
+        assert_eq!(headers.get("ST").map(|s| s.as_ref()), Some("upnp:rootdevice"));
+
+        assert!(parse_ssdp_response(b"HTTP/1.1 404 Not Found\r\n\r\n").is_err());
+        assert!(parse_ssdp_response(b"M-SEARCH * HTTP/1.1\r\n\r\n").is_err());
+    }
+
     // ============================================
     // Malformed input tests
     // ============================================
@@ -244,4 +382,112 @@ mod tests {
         // The important thing is it doesn't panic
         let _ = result;
     }
+
+    // ============================================
+    // response_delay() tests
+    // ============================================
+
+    #[test]
+    fn test_response_delay_within_mx() {
+        let packet = b"M-SEARCH * HTTP/1.1\r\n\
+            HOST: 239.255.255.250:1900\r\n\
+            MAN: \"ssdp:discover\"\r\n\
+            MX: 2\r\n\
+            ST: ssdp:all\r\n\
+            \r\n";
+
+        let (_, headers) = parse_ssdp(packet).unwrap();
+        for _ in 0..50 {
+            assert!(response_delay(&headers).unwrap() <= Duration::from_secs(2));
+        }
+    }
+
+    #[test]
+    fn test_response_delay_caps_mx() {
+        let packet = b"M-SEARCH * HTTP/1.1\r\n\
+            HOST: 239.255.255.250:1900\r\n\
+            MAN: \"ssdp:discover\"\r\n\
+            MX: 120\r\n\
+            ST: ssdp:all\r\n\
+            \r\n";
+
+        let (_, headers) = parse_ssdp(packet).unwrap();
+        for _ in 0..50 {
+            assert!(response_delay(&headers).unwrap() <= Duration::from_secs(MAX_MX_SECONDS));
+        }
+    }
+
+    #[test]
+    fn test_response_delay_multicast_without_man() {
+        let packet = b"M-SEARCH * HTTP/1.1\r\n\
+            HOST: 239.255.255.250:1900\r\n\
+            MX: 3\r\n\
+            ST: ssdp:all\r\n\
+            \r\n";
+
+        let (_, headers) = parse_ssdp(packet).unwrap();
+        assert!(response_delay(&headers).is_err());
+    }
+
+    #[test]
+    fn test_response_delay_multicast_wrong_man() {
+        let packet = b"M-SEARCH * HTTP/1.1\r\n\
+            HOST: 239.255.255.250:1900\r\n\
+            MAN: \"ssdp:something\"\r\n\
+            MX: 3\r\n\
+            ST: ssdp:all\r\n\
+            \r\n";
+
+        let (_, headers) = parse_ssdp(packet).unwrap();
+        assert!(response_delay(&headers).is_err());
+    }
+
+    #[test]
+    fn test_response_delay_multicast_without_mx() {
+        let packet = b"M-SEARCH * HTTP/1.1\r\n\
+            HOST: 239.255.255.250:1900\r\n\
+            MAN: \"ssdp:discover\"\r\n\
+            ST: ssdp:all\r\n\
+            \r\n";
+
+        let (_, headers) = parse_ssdp(packet).unwrap();
+        assert!(response_delay(&headers).is_err());
+    }
+
+    #[test]
+    fn test_response_delay_invalid_mx() {
+        let packet = b"M-SEARCH * HTTP/1.1\r\n\
+            HOST: 239.255.255.250:1900\r\n\
+            MAN: \"ssdp:discover\"\r\n\
+            MX: soon\r\n\
+            ST: ssdp:all\r\n\
+            \r\n";
+
+        let (_, headers) = parse_ssdp(packet).unwrap();
+        assert!(response_delay(&headers).is_err());
+    }
+
+    #[test]
+    fn test_response_delay_unicast_is_immediate() {
+        let packet = b"M-SEARCH * HTTP/1.1\r\n\
+            HOST: 192.168.1.50:1900\r\n\
+            MAN: \"ssdp:discover\"\r\n\
+            ST: ssdp:all\r\n\
+            \r\n";
+
+        let (_, headers) = parse_ssdp(packet).unwrap();
+        assert_eq!(response_delay(&headers).unwrap(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_response_delay_ipv6_multicast_without_mx() {
+        let packet = b"M-SEARCH * HTTP/1.1\r\n\
+            HOST: [FF02::C]:1900\r\n\
+            MAN: \"ssdp:discover\"\r\n\
+            ST: ssdp:all\r\n\
+            \r\n";
+
+        let (_, headers) = parse_ssdp(packet).unwrap();
+        assert!(response_delay(&headers).is_err());
+    }
 }
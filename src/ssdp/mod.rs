@@ -1,57 +1,129 @@
-use socket2::{Domain, Protocol, Socket, Type};
+use clap::ValueEnum;
+use serde::Deserialize;
 use std::{
-    net::{Ipv4Addr, SocketAddrV4},
+    net::{Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
     sync::Arc,
     time::Duration,
 };
-use tokio::net::UdpSocket;
 
 use anyhow::{Context, Result};
 
 use log::{info, warn};
 
-#[cfg(any(target_os = "android", target_os = "linux"))]
-use std::os::fd::AsFd as _;
-
-#[cfg(any(target_os = "android", target_os = "linux"))]
-use nix::sys::socket::{self, sockopt::BindToDevice};
-
-use broadcast::broadcast_task;
-use listener::listen_task;
+use broadcast::{broadcast_task, monitor_task, network_task, shutdown_handler};
 
+use crate::ssdp::boot::BootId;
 use crate::ssdp::broadcast::SSDPBroadcast;
-use crate::ssdp::utils::InteractiveSSDP;
-
+use crate::ssdp::cache::DescriptionCache;
+use crate::ssdp::discovery::Rediscovery;
+use crate::ssdp::health::OriginHealth;
+use crate::ssdp::interfaces::InterfaceSelection;
+use crate::ssdp::network::{lan_addresses, SSDPNetwork};
+use crate::ssdp::unicast::UnicastTargets;
+use crate::ssdp::utils::{InteractiveSSDP, Location};
+
+pub use network::{InterfaceScope, SSDPChannel};
+
+pub mod advertisement;
+pub mod boot;
 pub mod broadcast;
+pub mod cache;
+pub mod discovery;
+pub mod health;
+pub mod interfaces;
 mod error;
 pub mod listener;
+pub mod netwatch;
+pub mod network;
 pub mod packet;
+pub mod reflector;
+pub mod unicast;
 pub mod utils;
 
 // Listen socket binds to port 1900 to receive M-SEARCH queries
 pub static LISTEN_ADDRESS: (Ipv4Addr, u16) = (Ipv4Addr::new(0, 0, 0, 0), 1900);
+pub static LISTEN_ADDRESS_V6: (Ipv6Addr, u16) = (Ipv6Addr::UNSPECIFIED, 1900);
 
 // Broadcast socket uses ephemeral port - some clients/network equipment
 // ignore NOTIFY packets originating from port 1900
 pub static BROADCAST_ADDRESS: (Ipv4Addr, u16) = (Ipv4Addr::new(0, 0, 0, 0), 0);
+pub static BROADCAST_ADDRESS_V6: (Ipv6Addr, u16) = (Ipv6Addr::UNSPECIFIED, 0);
 
 pub static SSDP_ADDRESS: (Ipv4Addr, u16) = (Ipv4Addr::new(239, 255, 255, 250), 1900);
 
+// IPv6 SSDP groups: link-local and site-local scopes
+pub static SSDP_LINK_LOCAL_ADDRESS: (Ipv6Addr, u16) = (Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xc), 1900);
+pub static SSDP_SITE_LOCAL_ADDRESS: (Ipv6Addr, u16) = (Ipv6Addr::new(0xff05, 0, 0, 0, 0, 0, 0, 0xc), 1900);
+
+/// IP versions SSDP runs over.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IpVersion {
+    #[default]
+    V4,
+    V6,
+    Dual,
+}
+
+impl IpVersion {
+    pub fn has_v4(self) -> bool {
+        self != IpVersion::V6
+    }
+
+    pub fn has_v6(self) -> bool {
+        self != IpVersion::V4
+    }
+}
+
 pub struct SSDPManager {
+    network: Arc<SSDPNetwork>,
+    boot_id: Arc<BootId>,
+    servers: Vec<SSDPServer>,
+}
+
+/// Where a server's description is fetched from, and what is advertised for it.
+pub struct Origin {
+    pub description_url: String,
+    pub location: Location,
+    /// Set for discovered servers, to find them again when they move.
+    pub rediscovery: Option<Rediscovery>,
+}
+
+/// A remote server announced through the shared SSDP sockets.
+struct SSDPServer {
     broadcast_period: Duration,
-    listen_socket: Arc<UdpSocket>,
-    broadcast_socket: Arc<UdpSocket>,
     interactive_ssdp: Arc<InteractiveSSDP>,
     broadcaster: Arc<SSDPBroadcast>,
+    /// Interfaces the server is announced on.
+    scope: InterfaceScope,
 }
 
 impl SSDPManager {
     pub async fn new(
-        endpoint_desc_url: &str,
+        interfaces: InterfaceSelection,
+        ip_version: IpVersion,
+        bind_to_device: bool,
+        unicast: UnicastTargets,
+        boot_id_file: Option<PathBuf>,
+    ) -> Result<Self> {
+        Ok(SSDPManager {
+            network: Arc::new(SSDPNetwork::open(interfaces, ip_version, bind_to_device, unicast)?),
+            boot_id: Arc::new(BootId::load(boot_id_file)?),
+            servers: Vec::new(),
+        })
+    }
+
+    /// Register a remote server to be announced and to answer M-SEARCH queries for.
+    pub fn add_server(
+        &mut self,
+        origin: Origin,
         broadcast_period: Duration,
         connect_timeout: Option<Duration>,
-        broadcast_iface: Option<String>,
-    ) -> Result<Self> {
+        description_cache: DescriptionCache,
+        health: OriginHealth,
+        scope: InterfaceScope,
+    ) -> Result<()> {
         let mut http_client = reqwest::Client::builder();
 
         if let Some(timeout) = connect_timeout {
@@ -60,8 +132,6 @@ impl SSDPManager {
 
         let http_client = http_client.build().context("Failed to build HTTP client")?;
 
-        let (listen_socket, broadcast_socket) = ssdp_sockets(broadcast_iface).await?;
-
         let cache_max_age = match broadcast_period.as_secs() {
             n if n < 20 => 20,
             n => n * 2,
@@ -69,138 +139,70 @@ impl SSDPManager {
 
         let interactive_ssdp = Arc::new(InteractiveSSDP::new(
             http_client,
-            endpoint_desc_url,
+            &origin.description_url,
+            origin.location,
             cache_max_age,
+            description_cache,
+            health,
+            self.boot_id.clone(),
         ));
 
+        for address in lan_addresses(self.network.ip_version()) {
+            interactive_ssdp.relocate(address);
+        }
+
         let broadcaster = Arc::new(SSDPBroadcast::new(
-            broadcast_socket.clone(),
+            self.network.clone(),
             interactive_ssdp.clone(),
+            origin.rediscovery,
+            scope.clone(),
         ));
 
-        Ok(SSDPManager {
+        self.servers.push(SSDPServer {
             broadcast_period,
-            listen_socket,
-            broadcast_socket,
             interactive_ssdp,
             broadcaster,
-        })
+            scope,
+        });
+
+        Ok(())
     }
 }
 
-async fn ssdp_sockets(broadcast_iface: Option<String>) -> Result<(Arc<UdpSocket>, Arc<UdpSocket>)> {
-    // Create listen socket using socket2 to set SO_REUSEADDR/SO_REUSEPORT BEFORE binding
-    let listen_socket = {
-        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
-            .context("Failed to create listen socket")?;
-
-        // Set SO_REUSEADDR before binding - allows multiple processes to bind to the same port
-        socket
-            .set_reuse_address(true)
-            .context("Failed to set SO_REUSEADDR on listen socket")?;
-
-        // On Linux, also set SO_REUSEPORT for multicast
-        #[cfg(target_os = "linux")]
-        socket
-            .set_reuse_port(true)
-            .context("Failed to set SO_REUSEPORT on listen socket")?;
-
-        // Bind to port 1900 for M-SEARCH queries
-        let addr = SocketAddrV4::new(LISTEN_ADDRESS.0, LISTEN_ADDRESS.1);
-        socket
-            .bind(&addr.into())
-            .context("Failed to bind SSDP listen socket")?;
-
-        socket
-            .set_nonblocking(true)
-            .context("Failed to set non-blocking on listen socket")?;
-
-        // Convert to tokio UdpSocket
-        let std_socket: std::net::UdpSocket = socket.into();
-        UdpSocket::from_std(std_socket).context("Failed to convert listen socket to tokio")?
-    };
-
-    // Create broadcast socket using socket2
-    let broadcast_socket = {
-        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
-            .context("Failed to create broadcast socket")?;
-
-        socket
-            .set_reuse_address(true)
-            .context("Failed to set SO_REUSEADDR on broadcast socket")?;
-
-        #[cfg(target_os = "linux")]
-        socket
-            .set_reuse_port(true)
-            .context("Failed to set SO_REUSEPORT on broadcast socket")?;
-
-        // Bind to ephemeral port for NOTIFY announcements
-        let addr = SocketAddrV4::new(BROADCAST_ADDRESS.0, BROADCAST_ADDRESS.1);
-        socket
-            .bind(&addr.into())
-            .context("Failed to bind SSDP broadcast socket")?;
-
-        socket
-            .set_nonblocking(true)
-            .context("Failed to set non-blocking on broadcast socket")?;
-
-        let std_socket: std::net::UdpSocket = socket.into();
-        UdpSocket::from_std(std_socket).context("Failed to convert broadcast socket to tokio")?
-    };
-
-    if let Some(_iface) = broadcast_iface {
-        #[cfg(any(target_os = "android", target_os = "linux"))]
-        {
-            let iface: std::ffi::OsString = std::ffi::OsString::from(_iface);
-
-            socket::setsockopt(&listen_socket.as_fd(), BindToDevice, &iface)
-                .context("Failed to set SO_BINDTODEVICE on listen socket.")?;
-
-            socket::setsockopt(&broadcast_socket.as_fd(), BindToDevice, &iface)
-                .context("Failed to set SO_BINDTODEVICE on broadcast socket.")?;
+pub async fn main_task(ssdp: SSDPManager, wait_mode: bool) -> Result<()> {
+    info!(target: "dlnaproxy", "Launched main task for {} server(s)...", ssdp.servers.len());
+
+    for server in &ssdp.servers {
+        // Send initial byebye to clear any cache on listening devices.
+        // Skip this in wait mode since server may not be available yet -
+        // the broadcast loop will handle retries.
+        if wait_mode {
+            info!(target: "dlnaproxy", "Wait mode enabled, skipping initial ssdp:byebye");
+        } else if let Err(e) = server.interactive_ssdp.send_byebye(&server.broadcaster.channels()).await {
+            warn!(target: "dlnaproxy", "Failed to send initial ssdp:byebye: {}", e);
         }
 
-        #[cfg(target_os = "macos")]
-        panic!("Cannot set broadcast address on MacOS (yet)")
-    }
-
-    listen_socket
-        .join_multicast_v4(SSDP_ADDRESS.0, Ipv4Addr::UNSPECIFIED)
-        .context("Failed to join SSDP multicast group on listen socket.")?;
-
-    broadcast_socket
-        .join_multicast_v4(SSDP_ADDRESS.0, Ipv4Addr::UNSPECIFIED)
-        .context("Failed to join SSDP multicast group on broadcast socket.")?;
-
-    Ok((Arc::new(listen_socket), Arc::new(broadcast_socket)))
-}
+        tokio::task::spawn(monitor_task(server.broadcaster.clone()));
 
-pub async fn main_task(ssdp: SSDPManager, wait_mode: bool) -> Result<()> {
-    info!(target: "dlnaproxy", "Launched main task...");
-
-    // Send initial byebye to clear any cache on listening devices.
-    // Skip this in wait mode since server may not be available yet -
-    // the broadcast loop will handle retries.
-    if wait_mode {
-        info!(target: "dlnaproxy", "Wait mode enabled, skipping initial ssdp:byebye");
-    } else if let Err(e) = ssdp
-        .interactive_ssdp
-        .send_byebye(&ssdp.broadcast_socket, SSDP_ADDRESS)
-        .await
-    {
-        warn!(target: "dlnaproxy", "Failed to send initial ssdp:byebye: {}", e);
+        tokio::task::spawn(broadcast_task(
+            server.broadcaster.clone(),
+            server.broadcast_period,
+        ));
     }
 
-    let _broadcast_handle =
-        tokio::task::spawn(broadcast_task(ssdp.broadcaster, ssdp.broadcast_period));
+    let broadcasters: Vec<_> = ssdp.servers.iter().map(|s| s.broadcaster.clone()).collect();
+
+    let _shutdown_handle = tokio::task::spawn(shutdown_handler(broadcasters.clone()));
 
-    // Listen task uses the socket bound to port 1900 to receive M-SEARCH queries
-    let _listener_handle = tokio::task::spawn(listen_task(
-        ssdp.listen_socket,
-        ssdp.interactive_ssdp.clone(),
-    ));
+    // Listen tasks use the sockets bound to port 1900 to receive M-SEARCH queries
+    ssdp.network.start_listeners(
+        ssdp.servers
+            .iter()
+            .map(|s| (s.interactive_ssdp.clone(), s.scope.clone()))
+            .collect(),
+    );
 
-    let _ = _listener_handle.await;
+    network_task(ssdp.network, broadcasters, ssdp.boot_id).await;
 
     Ok(())
 }
@@ -0,0 +1,151 @@
+use std::time::Duration;
+
+use log::{debug, warn};
+use tokio::time::{self, Interval, MissedTickBehavior};
+
+/// How often the network configuration is re-read when change notifications aren't available.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Addresses usually come and go in bursts (DHCP renewal, interface restart...):
+/// wait for things to settle before acting on a change.
+const SETTLE_DELAY: Duration = Duration::from_secs(1);
+
+/// Tells when network interfaces or their addresses may have changed.
+///
+/// On Linux, this listens to rtnetlink link and address notifications. Elsewhere,
+/// or if the netlink socket can't be opened, it falls back to polling.
+pub struct NetworkWatcher {
+    watch: Watch,
+}
+
+enum Watch {
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    Netlink(netlink::NetlinkSocket),
+    Poll(Interval),
+}
+
+impl NetworkWatcher {
+    pub fn new() -> Self {
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        match netlink::NetlinkSocket::open() {
+            Ok(socket) => {
+                debug!(target: "dlnaproxy", "Watching network changes through rtnetlink.");
+                return NetworkWatcher {
+                    watch: Watch::Netlink(socket),
+                };
+            }
+            Err(e) => {
+                warn!(target: "dlnaproxy", "Couldn't watch network changes ({}), checking every {}s instead.", e, POLL_INTERVAL.as_secs());
+            }
+        }
+
+        NetworkWatcher {
+            watch: Watch::Poll(poll_interval()),
+        }
+    }
+
+    /// Wait for the next (possible) change of the network configuration.
+    pub async fn changed(&mut self) {
+        match &mut self.watch {
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            Watch::Netlink(socket) => match socket.wait_for_event().await {
+                Ok(()) => {
+                    time::sleep(SETTLE_DELAY).await;
+                    socket.drain();
+                }
+                Err(e) => {
+                    warn!(target: "dlnaproxy", "Stopped watching network changes ({}), checking every {}s instead.", e, POLL_INTERVAL.as_secs());
+                    self.watch = Watch::Poll(poll_interval());
+                }
+            },
+            Watch::Poll(interval) => {
+                interval.tick().await;
+            }
+        }
+    }
+}
+
+fn poll_interval() -> Interval {
+    let mut interval = time::interval_at(time::Instant::now() + POLL_INTERVAL, POLL_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod netlink {
+    use std::os::fd::{AsRawFd as _, OwnedFd};
+
+    use anyhow::{Context, Result};
+    use log::debug;
+    use nix::errno::Errno;
+    use nix::sys::socket::{
+        bind, recv, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType,
+    };
+    use tokio::io::unix::AsyncFd;
+
+    // Multicast groups from <linux/rtnetlink.h>
+    const RTMGRP_LINK: u32 = 0x1;
+    const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+    const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+
+    pub struct NetlinkSocket {
+        fd: AsyncFd<OwnedFd>,
+    }
+
+    impl NetlinkSocket {
+        pub fn open() -> Result<Self> {
+            let fd = socket(
+                AddressFamily::Netlink,
+                SockType::Raw,
+                SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+                SockProtocol::NetlinkRoute,
+            )
+            .context("Failed to create netlink socket")?;
+
+            let groups = RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR;
+
+            bind(fd.as_raw_fd(), &NetlinkAddr::new(0, groups))
+                .context("Failed to subscribe to rtnetlink notifications")?;
+
+            Ok(NetlinkSocket {
+                fd: AsyncFd::new(fd).context("Failed to register netlink socket")?,
+            })
+        }
+
+        /// Wait until at least one notification came in.
+        ///
+        /// We only subscribed to link and address groups, so any message is
+        /// worth a look; there's no need to parse it.
+        pub async fn wait_for_event(&self) -> Result<()> {
+            loop {
+                let mut guard = self.fd.readable().await.context("Netlink socket failed")?;
+
+                if self.read().context("Failed to read from netlink socket")? {
+                    return Ok(());
+                }
+                guard.clear_ready();
+            }
+        }
+
+        /// Discard queued notifications, they're covered by the upcoming refresh.
+        pub fn drain(&self) {
+            while let Ok(true) = self.read() {}
+        }
+
+        /// Read one message, telling whether there was one.
+        fn read(&self) -> Result<bool, Errno> {
+            let mut buffer = [0u8; 8192];
+
+            match recv(self.fd.as_raw_fd(), &mut buffer, MsgFlags::MSG_DONTWAIT) {
+                Ok(_) => Ok(true),
+                Err(Errno::EAGAIN) => Ok(false),
+                Err(Errno::ENOBUFS) => {
+                    // Notifications were lost: something changed anyway.
+                    debug!(target: "dlnaproxy", "Netlink receive buffer overflowed.");
+                    Ok(true)
+                }
+                Err(e) => Err(e),
+            }
+        }
+    }
+}
@@ -0,0 +1,746 @@
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+use anyhow::{Context, Result};
+
+use log::{info, warn};
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::os::fd::AsFd as _;
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use nix::sys::socket::{self, sockopt::BindToDevice};
+
+use crate::ssdp::interfaces::{network_interfaces, InterfaceSelection};
+use crate::ssdp::listener::listen_task;
+use crate::ssdp::unicast::UnicastTargets;
+use crate::ssdp::utils::InteractiveSSDP;
+use crate::ssdp::{
+    IpVersion, BROADCAST_ADDRESS, BROADCAST_ADDRESS_V6, LISTEN_ADDRESS, LISTEN_ADDRESS_V6, SSDP_ADDRESS,
+    SSDP_LINK_LOCAL_ADDRESS, SSDP_SITE_LOCAL_ADDRESS,
+};
+
+/// A multicast group we announce on, along with the socket used to reach it.
+///
+/// Unicast channels send the very same packets straight to a host instead.
+#[derive(Clone)]
+pub struct SSDPChannel {
+    socket: Arc<UdpSocket>,
+    group: SocketAddr,
+    destination: SocketAddr,
+    /// Name and address of the interface the socket is bound to, if any.
+    interface: Option<String>,
+    address: Option<IpAddr>,
+}
+
+impl SSDPChannel {
+    fn new(socket: Arc<UdpSocket>, group: SocketAddr, binding: Option<&Binding>) -> Self {
+        SSDPChannel {
+            socket,
+            group,
+            destination: group,
+            interface: binding.map(|b| b.name.clone()),
+            address: binding.map(|b| b.address),
+        }
+    }
+
+    /// This channel, but sending to `host` rather than to the group.
+    fn unicast(&self, host: IpAddr) -> Self {
+        SSDPChannel {
+            destination: SocketAddr::new(host, self.group.port()),
+            ..self.clone()
+        }
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    pub fn destination(&self) -> SocketAddr {
+        self.destination
+    }
+
+    pub fn is_unicast(&self) -> bool {
+        self.destination != self.group
+    }
+
+    /// HOST header of the packets sent on this channel, e.g. "[ff02::c]:1900".
+    pub fn host(&self) -> String {
+        self.group.to_string()
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.group.is_ipv6()
+    }
+
+    pub fn address(&self) -> Option<IpAddr> {
+        self.address
+    }
+}
+
+/// Interfaces a server is announced on, by name or IP address: every interface
+/// SSDP runs on when empty.
+///
+/// A local device can this way be announced on the other side only, e.g. a
+/// living-room renderer on the VPN interface but not on the LAN it lives on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterfaceScope(Vec<String>);
+
+impl InterfaceScope {
+    pub fn new(interfaces: Vec<String>) -> Self {
+        InterfaceScope(interfaces)
+    }
+
+    pub fn is_everywhere(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn includes(&self, interface: Option<&str>, address: Option<IpAddr>) -> bool {
+        self.is_everywhere()
+            || self.0.iter().any(|wanted| {
+                interface == Some(wanted.as_str()) || wanted.parse::<IpAddr>().ok().is_some_and(|w| Some(w) == address)
+            })
+    }
+
+    fn includes_binding(&self, binding: Option<&Binding>) -> bool {
+        self.includes(binding.map(|b| b.name.as_str()), binding.map(|b| b.address))
+    }
+
+    /// The channels going out of the interfaces in scope, unicast ones included.
+    pub fn filter(&self, channels: Vec<SSDPChannel>) -> Vec<SSDPChannel> {
+        channels
+            .into_iter()
+            .filter(|channel| self.includes(channel.interface.as_deref(), channel.address))
+            .collect()
+    }
+}
+
+/// A server answering M-SEARCH queries, with the interfaces it is announced on.
+pub type ScopedHelper = (Arc<InteractiveSSDP>, InterfaceScope);
+
+/// Interface address a socket pair is bound to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Binding {
+    pub name: String,
+    pub index: u32,
+    pub address: IpAddr,
+}
+
+/// Picks the interface address and the servers an M-SEARCH received on a listen socket is answered for.
+///
+/// Every listen socket is bound to port 1900 with SO_REUSEPORT, so a unicast query lands on
+/// whichever of them the kernel picks: it is answered for the interface the reply leaves
+/// through instead, going by the routing table.
+pub(super) struct Responders {
+    binding: Option<Binding>,
+    ssdp_helpers: Vec<ScopedHelper>,
+    /// Every binding SSDP runs on, kept up to date by the network.
+    bindings: Arc<Mutex<Vec<Binding>>>,
+}
+
+impl Responders {
+    /// Interface address to put in LOCATION and servers to answer a query from `sender` with.
+    pub(super) fn answering(&self, sender: IpAddr, unicast: bool) -> (Option<IpAddr>, Vec<Arc<InteractiveSSDP>>) {
+        let binding = match &self.binding {
+            Some(own) if unicast => {
+                let routed = route_source(sender);
+                let bindings = self.bindings.lock().unwrap();
+                Some(routed_binding(own, routed, &bindings).clone())
+            }
+            _ => self.binding.clone(),
+        };
+
+        let ssdp_helpers = self
+            .ssdp_helpers
+            .iter()
+            .filter(|(_, scope)| scope.includes_binding(binding.as_ref()))
+            .map(|(ssdp_helper, _)| ssdp_helper.clone())
+            .collect();
+
+        (binding.map(|b| b.address), ssdp_helpers)
+    }
+}
+
+/// The binding with our address `routed` replies leave from, or `own` if it isn't one of ours.
+fn routed_binding<'a>(own: &'a Binding, routed: Option<IpAddr>, bindings: &'a [Binding]) -> &'a Binding {
+    routed
+        .and_then(|address| bindings.iter().find(|b| b.address == address))
+        .unwrap_or(own)
+}
+
+/// Sockets for one IP version, on one interface address or on whatever
+/// interface the routing table picks when `binding` is `None`.
+struct Endpoint {
+    binding: Option<Binding>,
+    ipv6: bool,
+    listen_socket: Arc<UdpSocket>,
+    broadcast_socket: Arc<UdpSocket>,
+    channels: Vec<SSDPChannel>,
+    listen_task: Option<JoinHandle<()>>,
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        if let Some(task) = &self.listen_task {
+            task.abort();
+        }
+    }
+}
+
+impl Endpoint {
+    fn open(binding: Option<Binding>, ipv6: bool, pin_to_device: bool) -> Result<Self> {
+        let family = if ipv6 { "IPv6 " } else { "" };
+        let (listen_addr, broadcast_addr) = if ipv6 {
+            (SocketAddr::from(LISTEN_ADDRESS_V6), SocketAddr::from(BROADCAST_ADDRESS_V6))
+        } else {
+            (SocketAddr::from(LISTEN_ADDRESS), SocketAddr::from(BROADCAST_ADDRESS))
+        };
+
+        // Bind to port 1900 for M-SEARCH queries, and to an ephemeral port for NOTIFY announcements
+        let listen_socket = bind_udp(listen_addr, &format!("{}listen", family))?;
+        let broadcast_socket = bind_udp(broadcast_addr, &format!("{}broadcast", family))?;
+
+        for (socket, what) in [(&listen_socket, "listen"), (&broadcast_socket, "broadcast")] {
+            let what = format!("{}{}", family, what);
+
+            receive_joined_groups_only(socket, &what)?;
+
+            if let Some(binding) = &binding {
+                if pin_to_device {
+                    bind_to_device(socket, &binding.name, &what)?;
+                }
+                select_interface(socket, binding, &what)?;
+            }
+        }
+
+        let mut endpoint = Endpoint {
+            ipv6,
+            listen_socket: Arc::new(listen_socket),
+            broadcast_socket: Arc::new(broadcast_socket),
+            channels: Vec::new(),
+            listen_task: None,
+            binding,
+        };
+
+        endpoint.join_groups()?;
+
+        endpoint.channels = endpoint
+            .groups()
+            .into_iter()
+            .map(|group| SSDPChannel::new(endpoint.broadcast_socket.clone(), group, endpoint.binding.as_ref()))
+            .collect();
+
+        Ok(endpoint)
+    }
+
+    fn groups(&self) -> Vec<SocketAddr> {
+        if self.ipv6 {
+            vec![SSDP_LINK_LOCAL_ADDRESS.into(), SSDP_SITE_LOCAL_ADDRESS.into()]
+        } else {
+            vec![SSDP_ADDRESS.into()]
+        }
+    }
+
+    fn join_groups(&self) -> Result<()> {
+        for group in self.groups() {
+            match group.ip() {
+                IpAddr::V4(group) => {
+                    let interface = match self.binding.as_ref().map(|b| b.address) {
+                        Some(IpAddr::V4(addr)) => addr,
+                        _ => Ipv4Addr::UNSPECIFIED,
+                    };
+
+                    self.listen_socket
+                        .join_multicast_v4(group, interface)
+                        .context("Failed to join SSDP multicast group on listen socket.")?;
+
+                    self.broadcast_socket
+                        .join_multicast_v4(group, interface)
+                        .context("Failed to join SSDP multicast group on broadcast socket.")?;
+                }
+                IpAddr::V6(group) => {
+                    let index = self.binding.as_ref().map_or(0, |b| b.index);
+
+                    self.listen_socket
+                        .join_multicast_v6(&group, index)
+                        .with_context(|| format!("Failed to join SSDP multicast group {} on IPv6 listen socket.", group))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Join the groups again on whatever interface is now the default one.
+    fn rejoin_groups(&self) {
+        for group in self.groups() {
+            // Leaving fails if the interface we joined on is gone, which is fine.
+            let _ = match group.ip() {
+                IpAddr::V4(group) => {
+                    let _ = self.broadcast_socket.leave_multicast_v4(group, Ipv4Addr::UNSPECIFIED);
+                    self.listen_socket.leave_multicast_v4(group, Ipv4Addr::UNSPECIFIED)
+                }
+                IpAddr::V6(group) => self.listen_socket.leave_multicast_v6(&group, 0),
+            };
+        }
+
+        if let Err(e) = self.join_groups() {
+            warn!(target: "dlnaproxy", "{}", e);
+        }
+    }
+
+    /// Answer M-SEARCH queries for the servers announced on this endpoint's interface,
+    /// or on the one unicast replies leave through.
+    fn spawn_listener(
+        &mut self,
+        ssdp_helpers: &[ScopedHelper],
+        bindings: &Arc<Mutex<Vec<Binding>>>,
+        unicast: &Arc<UnicastTargets>,
+    ) {
+        if self.listen_task.is_none() {
+            let responders = Responders {
+                binding: self.binding.clone(),
+                ssdp_helpers: ssdp_helpers.to_vec(),
+                bindings: bindings.clone(),
+            };
+
+            self.listen_task = Some(tokio::task::spawn(listen_task(
+                self.listen_socket.clone(),
+                responders,
+                unicast.clone(),
+            )));
+        }
+    }
+}
+
+/// Channels affected by a change of the network configuration.
+#[derive(Default)]
+pub struct NetworkChange {
+    /// Channels that are gone; their sockets can still be used to say goodbye.
+    pub removed: Vec<SSDPChannel>,
+    /// Channels that were already there and still are, unicast targets included.
+    pub kept: Vec<SSDPChannel>,
+    pub added: Vec<SSDPChannel>,
+}
+
+/// The SSDP sockets in use, re-created as interfaces and addresses come and go.
+pub struct SSDPNetwork {
+    interfaces: InterfaceSelection,
+    ip_version: IpVersion,
+    /// Pin sockets to their interface with SO_BINDTODEVICE, on top of IP_MULTICAST_IF.
+    bind_to_device: bool,
+    unicast: Arc<UnicastTargets>,
+    endpoints: Mutex<Vec<Endpoint>>,
+    /// Bindings of the endpoints, for listeners to find the interface unicast replies leave through.
+    bindings: Arc<Mutex<Vec<Binding>>>,
+    /// Servers M-SEARCH queries are answered for, and where, once listening started.
+    ssdp_helpers: Mutex<Option<Vec<ScopedHelper>>>,
+}
+
+impl SSDPNetwork {
+    pub fn open(
+        interfaces: InterfaceSelection,
+        ip_version: IpVersion,
+        bind_to_device: bool,
+        unicast: UnicastTargets,
+    ) -> Result<Self> {
+        let mut endpoints = Vec::new();
+
+        // One socket pair per interface address, so that every announcement
+        // carries the address of the interface it leaves on.
+        for (binding, ipv6) in bindings(&interfaces, ip_version) {
+            if let Some(binding) = &binding {
+                info!(target: "dlnaproxy", "Announcing on {} ({})", binding.name, binding.address);
+            }
+            endpoints.push(Endpoint::open(binding, ipv6, bind_to_device)?);
+        }
+
+        if let InterfaceSelection::Only(names) = &interfaces {
+            for name in names {
+                let found = endpoints
+                    .iter()
+                    .filter_map(|endpoint| endpoint.binding.as_ref())
+                    .any(|binding| binding.name == *name || binding.address.to_string() == *name);
+
+                if !found {
+                    warn!(target: "dlnaproxy", "Interface {} not found or has no {:?} address, waiting for it to come up.", name, ip_version);
+                }
+            }
+        }
+
+        if endpoints.is_empty() {
+            warn!(target: "dlnaproxy", "None of the selected interfaces has an address to announce on yet.");
+        }
+
+        Ok(SSDPNetwork {
+            interfaces,
+            ip_version,
+            bind_to_device,
+            unicast: Arc::new(unicast),
+            bindings: Arc::new(Mutex::new(endpoint_bindings(&endpoints))),
+            endpoints: Mutex::new(endpoints),
+            ssdp_helpers: Mutex::new(None),
+        })
+    }
+
+    pub fn ip_version(&self) -> IpVersion {
+        self.ip_version
+    }
+
+    /// Whether the routing table picks the interface, rather than the configuration.
+    pub fn uses_default_route(&self) -> bool {
+        self.interfaces == InterfaceSelection::Default
+    }
+
+    /// Channels to announce on: every multicast group, then every unicast target.
+    pub fn channels(&self) -> Vec<SSDPChannel> {
+        let endpoints = self.endpoints.lock().unwrap();
+
+        let mut channels: Vec<SSDPChannel> = endpoints
+            .iter()
+            .flat_map(|endpoint| endpoint.channels.clone())
+            .collect();
+
+        channels.extend(self.unicast_channels(&endpoints));
+        channels
+    }
+
+    fn unicast_channels(&self, endpoints: &[Endpoint]) -> Vec<SSDPChannel> {
+        self.unicast
+            .hosts()
+            .into_iter()
+            .filter_map(|host| {
+                let endpoints: Vec<_> = endpoints.iter().filter(|e| e.ipv6 == host.is_ipv6()).collect();
+
+                // Unicast goes wherever the routing table says: use the endpoint of the interface
+                // it leaves from, so that LOCATION and interface scopes are right.
+                let routed = match endpoints.as_slice() {
+                    [_] | [] => None,
+                    _ => route_source(host).and_then(|source| {
+                        endpoints
+                            .iter()
+                            .find(|e| e.binding.as_ref().is_some_and(|b| b.address == source))
+                    }),
+                };
+
+                routed
+                    .or(endpoints.first())
+                    .and_then(|endpoint| endpoint.channels.first())
+                    .map(|channel| channel.unicast(host))
+            })
+            .collect()
+    }
+
+    /// Answer M-SEARCH queries for `ssdp_helpers` on the interfaces of their
+    /// scope, now and on sockets opened later.
+    pub fn start_listeners(&self, ssdp_helpers: Vec<ScopedHelper>) {
+        for endpoint in self.endpoints.lock().unwrap().iter_mut() {
+            endpoint.spawn_listener(&ssdp_helpers, &self.bindings, &self.unicast);
+        }
+
+        *self.ssdp_helpers.lock().unwrap() = Some(ssdp_helpers);
+    }
+
+    /// Re-read the network configuration, opening sockets for new interface
+    /// addresses and closing the ones of addresses that are gone.
+    pub fn refresh(&self) -> NetworkChange {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let mut change = NetworkChange::default();
+
+        if self.uses_default_route() {
+            for endpoint in endpoints.iter() {
+                endpoint.rejoin_groups();
+                change.kept.extend(endpoint.channels.clone());
+            }
+            change.kept.extend(self.unicast_channels(&endpoints));
+
+            return change;
+        }
+
+        let wanted = bindings(&self.interfaces, self.ip_version);
+
+        let (kept, removed): (Vec<_>, Vec<_>) = endpoints
+            .drain(..)
+            .partition(|endpoint| wanted.iter().any(|(binding, _)| *binding == endpoint.binding));
+
+        for endpoint in removed {
+            if let Some(binding) = &endpoint.binding {
+                info!(target: "dlnaproxy", "No longer announcing on {} ({}), closing its sockets.", binding.name, binding.address);
+            }
+            change.removed.extend(endpoint.channels.clone());
+        }
+
+        for endpoint in &kept {
+            change.kept.extend(endpoint.channels.clone());
+        }
+        change.kept.extend(self.unicast_channels(&kept));
+        *endpoints = kept;
+
+        let ssdp_helpers = self.ssdp_helpers.lock().unwrap();
+
+        for (binding, ipv6) in wanted {
+            if endpoints.iter().any(|endpoint| endpoint.binding == binding) {
+                continue;
+            }
+
+            let Some(name) = binding.as_ref().map(|b| format!("{} ({})", b.name, b.address)) else {
+                continue;
+            };
+
+            match Endpoint::open(binding, ipv6, self.bind_to_device) {
+                Ok(mut endpoint) => {
+                    info!(target: "dlnaproxy", "Announcing on {}", name);
+
+                    if let Some(ssdp_helpers) = ssdp_helpers.as_ref() {
+                        endpoint.spawn_listener(ssdp_helpers, &self.bindings, &self.unicast);
+                    }
+                    change.added.extend(endpoint.channels.clone());
+                    endpoints.push(endpoint);
+                }
+                Err(e) => warn!(target: "dlnaproxy", "Couldn't open SSDP sockets on {}: {}", name, e),
+            }
+        }
+
+        *self.bindings.lock().unwrap() = endpoint_bindings(&endpoints);
+
+        change
+    }
+}
+
+fn endpoint_bindings(endpoints: &[Endpoint]) -> Vec<Binding> {
+    endpoints.iter().filter_map(|endpoint| endpoint.binding.clone()).collect()
+}
+
+/// Socket pairs wanted for `interfaces`: one per IP version and interface, or
+/// a single unbound one per IP version when the routing table decides.
+pub(super) fn bindings(interfaces: &InterfaceSelection, ip_version: IpVersion) -> Vec<(Option<Binding>, bool)> {
+    let mut bindings = Vec::new();
+
+    if *interfaces == InterfaceSelection::Default {
+        if ip_version.has_v4() {
+            bindings.push((None, false));
+        }
+        if ip_version.has_v6() {
+            bindings.push((None, true));
+        }
+        return bindings;
+    }
+
+    let selected = match network_interfaces(interfaces) {
+        Ok(selected) => selected,
+        Err(e) => {
+            warn!(target: "dlnaproxy", "{}", e);
+            return bindings;
+        }
+    };
+
+    for iface in selected {
+        let v4 = iface.ipv4().filter(|_| ip_version.has_v4()).map(IpAddr::from);
+        let v6 = iface.ipv6().filter(|_| ip_version.has_v6()).map(IpAddr::from);
+
+        for address in v4.into_iter().chain(v6) {
+            let binding = Binding {
+                name: iface.name.clone(),
+                index: iface.index,
+                address,
+            };
+            bindings.push((Some(binding), address.is_ipv6()));
+        }
+    }
+
+    bindings
+}
+
+/// Our addresses on the LAN, i.e. the ones multicast SSDP traffic leaves from.
+pub fn lan_addresses(ip_version: IpVersion) -> Vec<IpAddr> {
+    let mut groups = Vec::new();
+
+    if ip_version.has_v4() {
+        groups.push(SocketAddr::from(SSDP_ADDRESS));
+    }
+    if ip_version.has_v6() {
+        // Link-local groups can't be routed without a scope id.
+        groups.push(SocketAddr::from(SSDP_SITE_LOCAL_ADDRESS));
+    }
+
+    groups.into_iter().filter_map(|group| route_source(group.ip())).collect()
+}
+
+/// Our address packets to `host` leave from, according to the routing table.
+fn route_source(host: IpAddr) -> Option<IpAddr> {
+    let unspecified = match host {
+        IpAddr::V4(_) => SocketAddr::from(BROADCAST_ADDRESS),
+        IpAddr::V6(_) => SocketAddr::from(BROADCAST_ADDRESS_V6),
+    };
+
+    // Connecting a UDP socket sends nothing, it only picks a route.
+    let socket = std::net::UdpSocket::bind(unspecified).ok()?;
+    socket.connect(SocketAddr::new(host, 1900)).ok()?;
+
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+/// Create a UDP socket for `addr`, with SO_REUSEADDR/SO_REUSEPORT set BEFORE binding.
+pub(super) fn bind_udp(addr: SocketAddr, what: &str) -> Result<UdpSocket> {
+    let domain = Domain::for_address(addr);
+
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))
+        .with_context(|| format!("Failed to create {} socket", what))?;
+
+    // Set SO_REUSEADDR before binding - allows multiple processes to bind to the same port
+    socket
+        .set_reuse_address(true)
+        .with_context(|| format!("Failed to set SO_REUSEADDR on {} socket", what))?;
+
+    // On Linux, also set SO_REUSEPORT for multicast
+    #[cfg(target_os = "linux")]
+    socket
+        .set_reuse_port(true)
+        .with_context(|| format!("Failed to set SO_REUSEPORT on {} socket", what))?;
+
+    // IPv4 is handled by its own sockets.
+    if domain == Domain::IPV6 {
+        socket
+            .set_only_v6(true)
+            .with_context(|| format!("Failed to set IPV6_V6ONLY on {} socket", what))?;
+    }
+
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("Failed to bind SSDP {} socket", what))?;
+
+    socket
+        .set_nonblocking(true)
+        .with_context(|| format!("Failed to set non-blocking on {} socket", what))?;
+
+    // Convert to tokio UdpSocket
+    let std_socket: std::net::UdpSocket = socket.into();
+    UdpSocket::from_std(std_socket).with_context(|| format!("Failed to convert {} socket to tokio", what))
+}
+
+/// Send multicast through the interface of `binding` rather than the default route's.
+///
+/// Unlike SO_BINDTODEVICE, this needs no privilege.
+pub(super) fn select_interface(socket: &UdpSocket, binding: &Binding, what: &str) -> Result<()> {
+    let socket = SockRef::from(socket);
+
+    match binding.address {
+        IpAddr::V4(address) => socket
+            .set_multicast_if_v4(&address)
+            .with_context(|| format!("Failed to set IP_MULTICAST_IF on {} socket", what)),
+        IpAddr::V6(_) => socket
+            .set_multicast_if_v6(binding.index)
+            .with_context(|| format!("Failed to set IPV6_MULTICAST_IF on {} socket", what)),
+    }
+}
+
+/// Only deliver multicast from the groups joined on this very socket, i.e. on its
+/// interface: Linux otherwise hands every socket on port 1900 the queries of all interfaces.
+pub(super) fn receive_joined_groups_only(socket: &UdpSocket, what: &str) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let socket = SockRef::from(socket);
+
+        if socket.local_addr()?.is_ipv6() {
+            // Only available since Linux 4.20.
+            if let Err(e) = socket.set_multicast_all_v6(false) {
+                log::debug!(target: "dlnaproxy", "Failed to unset IPV6_MULTICAST_ALL on {} socket: {}", what, e);
+            }
+        } else {
+            socket
+                .set_multicast_all_v4(false)
+                .with_context(|| format!("Failed to unset IP_MULTICAST_ALL on {} socket", what))?;
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    let _ = (socket, what);
+
+    Ok(())
+}
+
+pub(super) fn bind_to_device(socket: &UdpSocket, iface: &str, what: &str) -> Result<()> {
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    {
+        let iface: std::ffi::OsString = std::ffi::OsString::from(iface);
+
+        socket::setsockopt(&socket.as_fd(), BindToDevice, &iface)
+            .with_context(|| format!("Failed to set SO_BINDTODEVICE on {} socket (requires root or CAP_NET_RAW).", what))?;
+
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "linux")))]
+    {
+        let _ = (socket, iface);
+        anyhow::bail!("Cannot bind {} socket to a device: SO_BINDTODEVICE is only available on Linux.", what)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_bindings() {
+        let bindings = bindings(&InterfaceSelection::Default, IpVersion::Dual);
+        assert_eq!(bindings, [(None, false), (None, true)]);
+
+        let bindings = super::bindings(&InterfaceSelection::Default, IpVersion::V6);
+        assert_eq!(bindings, [(None, true)]);
+    }
+
+    #[test]
+    fn test_interface_scope() {
+        let lan = Binding {
+            name: "eth0".to_string(),
+            index: 2,
+            address: "192.168.1.50".parse().unwrap(),
+        };
+        let vpn = Binding {
+            name: "tun0".to_string(),
+            index: 5,
+            address: "10.8.0.1".parse().unwrap(),
+        };
+
+        let everywhere = InterfaceScope::default();
+        assert!(everywhere.includes_binding(Some(&lan)));
+        assert!(everywhere.includes_binding(None));
+
+        let by_name = InterfaceScope::new(vec!["tun0".to_string()]);
+        assert!(by_name.includes_binding(Some(&vpn)));
+        assert!(!by_name.includes_binding(Some(&lan)));
+        assert!(!by_name.includes_binding(None));
+
+        let by_address = InterfaceScope::new(vec!["192.168.1.50".to_string()]);
+        assert!(by_address.includes_binding(Some(&lan)));
+        assert!(!by_address.includes_binding(Some(&vpn)));
+    }
+
+    #[test]
+    fn test_routed_binding() {
+        let lan = Binding {
+            name: "eth0".to_string(),
+            index: 2,
+            address: "192.168.1.50".parse().unwrap(),
+        };
+        let vpn = Binding {
+            name: "tun0".to_string(),
+            index: 5,
+            address: "10.8.0.1".parse().unwrap(),
+        };
+        let bindings = [lan.clone(), vpn.clone()];
+
+        // A VPN client's query landed on the LAN socket.
+        assert_eq!(routed_binding(&lan, Some(vpn.address), &bindings), &vpn);
+        assert_eq!(routed_binding(&lan, Some(lan.address), &bindings), &lan);
+
+        // Not one of the interfaces SSDP runs on, or no route at all.
+        assert_eq!(routed_binding(&vpn, Some("172.17.0.1".parse().unwrap()), &bindings), &vpn);
+        assert_eq!(routed_binding(&vpn, None, &bindings), &vpn);
+    }
+}
@@ -4,24 +4,49 @@ use tokio::net::{ToSocketAddrs, UdpSocket};
 
 use anyhow::{Context, Result};
 
+/// UPnP 1.1 headers letting control points notice restarts and description changes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BootInfo {
+    /// BOOTID.UPNP.ORG, increased whenever the device (re)joins the network.
+    pub boot_id: u32,
+    /// CONFIGID.UPNP.ORG, increased whenever the description changes.
+    pub config_id: u32,
+    /// SEARCHPORT.UPNP.ORG, only sent when we don't answer M-SEARCH on port 1900.
+    pub search_port: Option<u16>,
+}
+
 pub enum SSDPPacket {
     Alive {
+        host: String,
         desc_url: String,
         server_ua: String,
-        unique_device_name: String,
-        device_type: String,
+        notification_type: String,
+        unique_service_name: String,
         cache_max_age: usize,
+        boot: BootInfo,
     },
     Ok {
         desc_url: String,
         server_ua: String,
-        unique_device_name: String,
-        device_type: String,
+        search_target: String,
+        unique_service_name: String,
         cache_max_age: usize,
+        boot: BootInfo,
     },
     ByeBye {
-        unique_device_name: String,
-        device_type: String,
+        host: String,
+        notification_type: String,
+        unique_service_name: String,
+        boot: BootInfo,
+    },
+    /// Announces that BOOTID is about to become `next_boot_id`.
+    Update {
+        host: String,
+        desc_url: String,
+        notification_type: String,
+        unique_service_name: String,
+        boot: BootInfo,
+        next_boot_id: u32,
     },
 }
 
@@ -36,42 +61,63 @@ impl SSDPPacket {
     }
 }
 
+fn write_boot_headers(f: &mut fmt::Formatter<'_>, boot: &BootInfo, with_search_port: bool) -> fmt::Result {
+    write!(
+        f,
+        "\
+BOOTID.UPNP.ORG:{boot_id}\r\n\
+CONFIGID.UPNP.ORG:{config_id}\r\n",
+        boot_id = boot.boot_id,
+        config_id = boot.config_id
+    )?;
+
+    match boot.search_port {
+        Some(port) if with_search_port => write!(f, "SEARCHPORT.UPNP.ORG:{}\r\n", port),
+        _ => Ok(()),
+    }
+}
+
 impl fmt::Display for SSDPPacket {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             SSDPPacket::Alive {
+                host,
                 desc_url,
                 server_ua,
-                unique_device_name,
-                device_type,
+                notification_type,
+                unique_service_name,
                 cache_max_age,
+                boot,
             } => {
                 write!(
                     f,
                     "\
 NOTIFY * HTTP/1.1\r\n\
-HOST:239.255.255.250:1900\r\n\
+HOST:{host}\r\n\
 CACHE-CONTROL:max-age={cache_max_age}\r\n\
 LOCATION:{location}\r\n\
 SERVER: {server_ua}\r\n\
-NT:{device_type}\r\n\
-USN:{udn}::{device_type}\r\n\
-NTS:ssdp:alive\r\n\
-\r\n",
+NT:{nt}\r\n\
+USN:{usn}\r\n\
+NTS:ssdp:alive\r\n",
+                    host = host,
                     cache_max_age = cache_max_age,
                     location = desc_url,
                     server_ua = server_ua,
-                    device_type = device_type,
-                    udn = unique_device_name
-                )
+                    nt = notification_type,
+                    usn = unique_service_name
+                )?;
+                write_boot_headers(f, boot, true)?;
+                f.write_str("\r\n")
             }
 
             SSDPPacket::Ok {
                 desc_url,
                 server_ua,
-                unique_device_name,
-                device_type,
+                search_target,
+                unique_service_name,
                 cache_max_age,
+                boot,
             } => {
                 let now = Utc::now().to_rfc2822().replace("+0000", "GMT");
 
@@ -81,38 +127,71 @@ NTS:ssdp:alive\r\n\
 HTTP/1.1 200 OK\r\n\
 CACHE-CONTROL:max-age={cache_max_age}\r\n\
 DATE: {date}\r\n\
-ST: {device_type}\r\n\
-USN:{udn}::{device_type}\r\n\
+ST: {st}\r\n\
+USN:{usn}\r\n\
 EXT:\r\n\
 SERVER: {server_ua}\r\n\
 LOCATION:{location}\r\n\
-Content-Length: 0\r\n\
-\r\n",
+Content-Length: 0\r\n",
                     cache_max_age = cache_max_age,
                     location = desc_url,
                     server_ua = server_ua,
-                    device_type = device_type,
-                    udn = unique_device_name,
+                    st = search_target,
+                    usn = unique_service_name,
                     date = now
-                )
+                )?;
+                write_boot_headers(f, boot, true)?;
+                f.write_str("\r\n")
             }
 
             SSDPPacket::ByeBye {
-                unique_device_name,
-                device_type,
+                host,
+                notification_type,
+                unique_service_name,
+                boot,
             } => {
                 write!(
                     f,
                     "\
 NOTIFY * HTTP/1.1\r\n\
-HOST:239.255.255.250:1900\r\n\
-NT:{device_type}\r\n\
-USN:{udn}::{device_type}\r\n\
-NTS:ssdp:byebye\r\n\
-\r\n",
-                    device_type = device_type,
-                    udn = unique_device_name
-                )
+HOST:{host}\r\n\
+NT:{nt}\r\n\
+USN:{usn}\r\n\
+NTS:ssdp:byebye\r\n",
+                    host = host,
+                    nt = notification_type,
+                    usn = unique_service_name
+                )?;
+                write_boot_headers(f, boot, false)?;
+                f.write_str("\r\n")
+            }
+
+            SSDPPacket::Update {
+                host,
+                desc_url,
+                notification_type,
+                unique_service_name,
+                boot,
+                next_boot_id,
+            } => {
+                write!(
+                    f,
+                    "\
+NOTIFY * HTTP/1.1\r\n\
+HOST:{host}\r\n\
+LOCATION:{location}\r\n\
+NT:{nt}\r\n\
+USN:{usn}\r\n\
+NTS:ssdp:update\r\n\
+NEXTBOOTID.UPNP.ORG:{next_boot_id}\r\n",
+                    host = host,
+                    location = desc_url,
+                    nt = notification_type,
+                    usn = unique_service_name,
+                    next_boot_id = next_boot_id
+                )?;
+                write_boot_headers(f, boot, true)?;
+                f.write_str("\r\n")
             }
         }
     }
@@ -129,11 +208,13 @@ mod tests {
     #[test]
     fn test_alive_starts_with_notify() {
         let packet = SSDPPacket::Alive {
+            host: "239.255.255.250:1900".to_string(),
             desc_url: "http://192.168.1.1:8080/desc.xml".to_string(),
             server_ua: "Test/1.0".to_string(),
-            unique_device_name: "uuid:test-device".to_string(),
-            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            notification_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_service_name: "uuid:test-device::urn:schemas-upnp-org:device:MediaServer:1".to_string(),
             cache_max_age: 1800,
+            boot: BootInfo::default(),
         };
         let output = packet.to_string();
         assert!(output.starts_with("NOTIFY * HTTP/1.1\r\n"));
@@ -142,24 +223,44 @@ mod tests {
     #[test]
     fn test_alive_has_host_header() {
         let packet = SSDPPacket::Alive {
+            host: "239.255.255.250:1900".to_string(),
             desc_url: "http://192.168.1.1:8080/desc.xml".to_string(),
             server_ua: "Test/1.0".to_string(),
-            unique_device_name: "uuid:test-device".to_string(),
-            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            notification_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_service_name: "uuid:test-device::urn:schemas-upnp-org:device:MediaServer:1".to_string(),
             cache_max_age: 1800,
+            boot: BootInfo::default(),
         };
         let output = packet.to_string();
         assert!(output.contains("HOST:239.255.255.250:1900\r\n"));
     }
 
+    #[test]
+    fn test_alive_ipv6() {
+        let packet = SSDPPacket::Alive {
+            host: "[ff05::c]:1900".to_string(),
+            desc_url: "http://[fd00::1]:8080/desc.xml".to_string(),
+            server_ua: "Test/1.0".to_string(),
+            notification_type: "upnp:rootdevice".to_string(),
+            unique_service_name: "uuid:test-device::upnp:rootdevice".to_string(),
+            cache_max_age: 1800,
+            boot: BootInfo::default(),
+        };
+        let output = packet.to_string();
+        assert!(output.contains("HOST:[ff05::c]:1900\r\n"));
+        assert!(output.contains("LOCATION:http://[fd00::1]:8080/desc.xml\r\n"));
+    }
+
     #[test]
     fn test_alive_has_cache_control() {
         let packet = SSDPPacket::Alive {
+            host: "239.255.255.250:1900".to_string(),
             desc_url: "http://192.168.1.1:8080/desc.xml".to_string(),
             server_ua: "Test/1.0".to_string(),
-            unique_device_name: "uuid:test-device".to_string(),
-            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            notification_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_service_name: "uuid:test-device::urn:schemas-upnp-org:device:MediaServer:1".to_string(),
             cache_max_age: 1800,
+            boot: BootInfo::default(),
         };
         let output = packet.to_string();
         assert!(output.contains("CACHE-CONTROL:max-age=1800\r\n"));
@@ -168,11 +269,13 @@ mod tests {
     #[test]
     fn test_alive_has_location() {
         let packet = SSDPPacket::Alive {
+            host: "239.255.255.250:1900".to_string(),
             desc_url: "http://192.168.1.1:8080/desc.xml".to_string(),
             server_ua: "Test/1.0".to_string(),
-            unique_device_name: "uuid:test-device".to_string(),
-            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            notification_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_service_name: "uuid:test-device::urn:schemas-upnp-org:device:MediaServer:1".to_string(),
             cache_max_age: 1800,
+            boot: BootInfo::default(),
         };
         let output = packet.to_string();
         assert!(output.contains("LOCATION:http://192.168.1.1:8080/desc.xml\r\n"));
@@ -181,11 +284,13 @@ mod tests {
     #[test]
     fn test_alive_has_server() {
         let packet = SSDPPacket::Alive {
+            host: "239.255.255.250:1900".to_string(),
             desc_url: "http://192.168.1.1:8080/desc.xml".to_string(),
             server_ua: "Test/1.0 UPnP/1.0".to_string(),
-            unique_device_name: "uuid:test-device".to_string(),
-            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            notification_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_service_name: "uuid:test-device::urn:schemas-upnp-org:device:MediaServer:1".to_string(),
             cache_max_age: 1800,
+            boot: BootInfo::default(),
         };
         let output = packet.to_string();
         assert!(output.contains("SERVER: Test/1.0 UPnP/1.0\r\n"));
@@ -194,11 +299,13 @@ mod tests {
     #[test]
     fn test_alive_has_nt_usn_nts() {
         let packet = SSDPPacket::Alive {
+            host: "239.255.255.250:1900".to_string(),
             desc_url: "http://192.168.1.1:8080/desc.xml".to_string(),
             server_ua: "Test/1.0".to_string(),
-            unique_device_name: "uuid:test-device-123".to_string(),
-            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            notification_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_service_name: "uuid:test-device-123::urn:schemas-upnp-org:device:MediaServer:1".to_string(),
             cache_max_age: 1800,
+            boot: BootInfo::default(),
         };
         let output = packet.to_string();
         assert!(output.contains("NT:urn:schemas-upnp-org:device:MediaServer:1\r\n"));
@@ -206,14 +313,32 @@ mod tests {
         assert!(output.contains("NTS:ssdp:alive\r\n"));
     }
 
+    #[test]
+    fn test_alive_rootdevice() {
+        let packet = SSDPPacket::Alive {
+            host: "239.255.255.250:1900".to_string(),
+            desc_url: "http://192.168.1.1:8080/desc.xml".to_string(),
+            server_ua: "Test/1.0".to_string(),
+            notification_type: "upnp:rootdevice".to_string(),
+            unique_service_name: "uuid:test-device::upnp:rootdevice".to_string(),
+            cache_max_age: 1800,
+            boot: BootInfo::default(),
+        };
+        let output = packet.to_string();
+        assert!(output.contains("NT:upnp:rootdevice\r\n"));
+        assert!(output.contains("USN:uuid:test-device::upnp:rootdevice\r\n"));
+    }
+
     #[test]
     fn test_alive_ends_with_empty_line() {
         let packet = SSDPPacket::Alive {
+            host: "239.255.255.250:1900".to_string(),
             desc_url: "http://192.168.1.1:8080/desc.xml".to_string(),
             server_ua: "Test/1.0".to_string(),
-            unique_device_name: "uuid:test-device".to_string(),
-            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            notification_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_service_name: "uuid:test-device::urn:schemas-upnp-org:device:MediaServer:1".to_string(),
             cache_max_age: 1800,
+            boot: BootInfo::default(),
         };
         let output = packet.to_string();
         assert!(output.ends_with("\r\n\r\n"));
@@ -228,9 +353,10 @@ mod tests {
         let packet = SSDPPacket::Ok {
             desc_url: "http://192.168.1.1:8080/desc.xml".to_string(),
             server_ua: "Test/1.0".to_string(),
-            unique_device_name: "uuid:test-device".to_string(),
-            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            search_target: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_service_name: "uuid:test-device::urn:schemas-upnp-org:device:MediaServer:1".to_string(),
             cache_max_age: 1800,
+            boot: BootInfo::default(),
         };
         let output = packet.to_string();
         assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
@@ -241,9 +367,10 @@ mod tests {
         let packet = SSDPPacket::Ok {
             desc_url: "http://192.168.1.1:8080/desc.xml".to_string(),
             server_ua: "Test/1.0".to_string(),
-            unique_device_name: "uuid:test-device".to_string(),
-            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            search_target: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_service_name: "uuid:test-device::urn:schemas-upnp-org:device:MediaServer:1".to_string(),
             cache_max_age: 1800,
+            boot: BootInfo::default(),
         };
         let output = packet.to_string();
         // DATE header should be present with GMT suffix (RFC 2822 format)
@@ -256,22 +383,39 @@ mod tests {
         let packet = SSDPPacket::Ok {
             desc_url: "http://192.168.1.1:8080/desc.xml".to_string(),
             server_ua: "Test/1.0".to_string(),
-            unique_device_name: "uuid:test-device".to_string(),
-            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            search_target: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_service_name: "uuid:test-device::urn:schemas-upnp-org:device:MediaServer:1".to_string(),
             cache_max_age: 1800,
+            boot: BootInfo::default(),
         };
         let output = packet.to_string();
         assert!(output.contains("ST: urn:schemas-upnp-org:device:MediaServer:1\r\n"));
     }
 
+    #[test]
+    fn test_ok_has_usn_header() {
+        let packet = SSDPPacket::Ok {
+            desc_url: "http://192.168.1.1:8080/desc.xml".to_string(),
+            server_ua: "Test/1.0".to_string(),
+            search_target: "uuid:test-device".to_string(),
+            unique_service_name: "uuid:test-device".to_string(),
+            cache_max_age: 1800,
+            boot: BootInfo::default(),
+        };
+        let output = packet.to_string();
+        assert!(output.contains("ST: uuid:test-device\r\n"));
+        assert!(output.contains("USN:uuid:test-device\r\n"));
+    }
+
     #[test]
     fn test_ok_has_ext_header() {
         let packet = SSDPPacket::Ok {
             desc_url: "http://192.168.1.1:8080/desc.xml".to_string(),
             server_ua: "Test/1.0".to_string(),
-            unique_device_name: "uuid:test-device".to_string(),
-            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            search_target: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_service_name: "uuid:test-device::urn:schemas-upnp-org:device:MediaServer:1".to_string(),
             cache_max_age: 1800,
+            boot: BootInfo::default(),
         };
         let output = packet.to_string();
         assert!(output.contains("EXT:\r\n"));
@@ -282,9 +426,10 @@ mod tests {
         let packet = SSDPPacket::Ok {
             desc_url: "http://192.168.1.1:8080/desc.xml".to_string(),
             server_ua: "Test/1.0".to_string(),
-            unique_device_name: "uuid:test-device".to_string(),
-            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            search_target: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_service_name: "uuid:test-device::urn:schemas-upnp-org:device:MediaServer:1".to_string(),
             cache_max_age: 1800,
+            boot: BootInfo::default(),
         };
         let output = packet.to_string();
         assert!(output.contains("Content-Length: 0\r\n"));
@@ -297,8 +442,10 @@ mod tests {
     #[test]
     fn test_byebye_starts_with_notify() {
         let packet = SSDPPacket::ByeBye {
-            unique_device_name: "uuid:test-device".to_string(),
-            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            host: "239.255.255.250:1900".to_string(),
+            notification_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_service_name: "uuid:test-device::urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            boot: BootInfo::default(),
         };
         let output = packet.to_string();
         assert!(output.starts_with("NOTIFY * HTTP/1.1\r\n"));
@@ -307,8 +454,10 @@ mod tests {
     #[test]
     fn test_byebye_has_host() {
         let packet = SSDPPacket::ByeBye {
-            unique_device_name: "uuid:test-device".to_string(),
-            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            host: "239.255.255.250:1900".to_string(),
+            notification_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_service_name: "uuid:test-device::urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            boot: BootInfo::default(),
         };
         let output = packet.to_string();
         assert!(output.contains("HOST:239.255.255.250:1900\r\n"));
@@ -317,18 +466,35 @@ mod tests {
     #[test]
     fn test_byebye_has_nts_byebye() {
         let packet = SSDPPacket::ByeBye {
-            unique_device_name: "uuid:test-device".to_string(),
-            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            host: "239.255.255.250:1900".to_string(),
+            notification_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_service_name: "uuid:test-device::urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            boot: BootInfo::default(),
         };
         let output = packet.to_string();
         assert!(output.contains("NTS:ssdp:byebye\r\n"));
     }
 
+    #[test]
+    fn test_byebye_uuid_usn() {
+        let packet = SSDPPacket::ByeBye {
+            host: "239.255.255.250:1900".to_string(),
+            notification_type: "uuid:test-device".to_string(),
+            unique_service_name: "uuid:test-device".to_string(),
+            boot: BootInfo::default(),
+        };
+        let output = packet.to_string();
+        assert!(output.contains("NT:uuid:test-device\r\n"));
+        assert!(output.contains("USN:uuid:test-device\r\n"));
+    }
+
     #[test]
     fn test_byebye_no_cache_control() {
         let packet = SSDPPacket::ByeBye {
-            unique_device_name: "uuid:test-device".to_string(),
-            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            host: "239.255.255.250:1900".to_string(),
+            notification_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_service_name: "uuid:test-device::urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            boot: BootInfo::default(),
         };
         let output = packet.to_string();
         assert!(!output.contains("CACHE-CONTROL"));
@@ -337,8 +503,10 @@ mod tests {
     #[test]
     fn test_byebye_no_location() {
         let packet = SSDPPacket::ByeBye {
-            unique_device_name: "uuid:test-device".to_string(),
-            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            host: "239.255.255.250:1900".to_string(),
+            notification_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_service_name: "uuid:test-device::urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            boot: BootInfo::default(),
         };
         let output = packet.to_string();
         assert!(!output.contains("LOCATION"));
@@ -347,10 +515,98 @@ mod tests {
     #[test]
     fn test_byebye_no_server() {
         let packet = SSDPPacket::ByeBye {
-            unique_device_name: "uuid:test-device".to_string(),
-            device_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            host: "239.255.255.250:1900".to_string(),
+            notification_type: "urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            unique_service_name: "uuid:test-device::urn:schemas-upnp-org:device:MediaServer:1".to_string(),
+            boot: BootInfo::default(),
         };
         let output = packet.to_string();
         assert!(!output.contains("SERVER"));
     }
+
+    // ============================================
+    // UPnP 1.1 headers
+    // ============================================
+
+    fn boot() -> BootInfo {
+        BootInfo {
+            boot_id: 42,
+            config_id: 7,
+            search_port: None,
+        }
+    }
+
+    #[test]
+    fn test_alive_has_boot_headers() {
+        let packet = SSDPPacket::Alive {
+            host: "239.255.255.250:1900".to_string(),
+            desc_url: "http://192.168.1.1:8080/desc.xml".to_string(),
+            server_ua: "Test/1.0".to_string(),
+            notification_type: "upnp:rootdevice".to_string(),
+            unique_service_name: "uuid:test-device::upnp:rootdevice".to_string(),
+            cache_max_age: 1800,
+            boot: boot(),
+        };
+        let output = packet.to_string();
+        assert!(output.contains("BOOTID.UPNP.ORG:42\r\n"));
+        assert!(output.contains("CONFIGID.UPNP.ORG:7\r\n"));
+        assert!(!output.contains("SEARCHPORT.UPNP.ORG"));
+        assert!(output.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_ok_has_search_port_when_set() {
+        let packet = SSDPPacket::Ok {
+            desc_url: "http://192.168.1.1:8080/desc.xml".to_string(),
+            server_ua: "Test/1.0".to_string(),
+            search_target: "upnp:rootdevice".to_string(),
+            unique_service_name: "uuid:test-device::upnp:rootdevice".to_string(),
+            cache_max_age: 1800,
+            boot: BootInfo {
+                search_port: Some(1901),
+                ..boot()
+            },
+        };
+        let output = packet.to_string();
+        assert!(output.contains("BOOTID.UPNP.ORG:42\r\n"));
+        assert!(output.contains("SEARCHPORT.UPNP.ORG:1901\r\n"));
+    }
+
+    #[test]
+    fn test_byebye_has_boot_headers_but_no_search_port() {
+        let packet = SSDPPacket::ByeBye {
+            host: "239.255.255.250:1900".to_string(),
+            notification_type: "upnp:rootdevice".to_string(),
+            unique_service_name: "uuid:test-device::upnp:rootdevice".to_string(),
+            boot: BootInfo {
+                search_port: Some(1901),
+                ..boot()
+            },
+        };
+        let output = packet.to_string();
+        assert!(output.contains("BOOTID.UPNP.ORG:42\r\n"));
+        assert!(output.contains("CONFIGID.UPNP.ORG:7\r\n"));
+        assert!(!output.contains("SEARCHPORT.UPNP.ORG"));
+    }
+
+    #[test]
+    fn test_update() {
+        let packet = SSDPPacket::Update {
+            host: "[ff02::c]:1900".to_string(),
+            desc_url: "http://192.168.1.2:8080/desc.xml".to_string(),
+            notification_type: "upnp:rootdevice".to_string(),
+            unique_service_name: "uuid:test-device::upnp:rootdevice".to_string(),
+            boot: boot(),
+            next_boot_id: 43,
+        };
+        let output = packet.to_string();
+        assert!(output.starts_with("NOTIFY * HTTP/1.1\r\n"));
+        assert!(output.contains("HOST:[ff02::c]:1900\r\n"));
+        assert!(output.contains("LOCATION:http://192.168.1.2:8080/desc.xml\r\n"));
+        assert!(output.contains("NTS:ssdp:update\r\n"));
+        assert!(output.contains("BOOTID.UPNP.ORG:42\r\n"));
+        assert!(output.contains("NEXTBOOTID.UPNP.ORG:43\r\n"));
+        assert!(!output.contains("CACHE-CONTROL"));
+        assert!(output.ends_with("\r\n\r\n"));
+    }
 }
@@ -0,0 +1,611 @@
+use std::{
+    collections::VecDeque,
+    hash::{DefaultHasher, Hash as _, Hasher as _},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, info, trace, warn};
+use socket2::SockRef;
+use tokio::{
+    net::UdpSocket,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::{JoinHandle, JoinSet},
+    time,
+};
+
+use crate::ssdp::interfaces::InterfaceSelection;
+use crate::ssdp::listener::{is_multicast, parse_ssdp, parse_ssdp_response, response_delay, MAX_MX_SECONDS};
+use crate::ssdp::netwatch::NetworkWatcher;
+use crate::ssdp::network::{bind_to_device, bind_udp, bindings, receive_joined_groups_only, select_interface, Binding};
+use crate::ssdp::{
+    IpVersion, BROADCAST_ADDRESS, BROADCAST_ADDRESS_V6, LISTEN_ADDRESS, LISTEN_ADDRESS_V6, SSDP_ADDRESS,
+    SSDP_LINK_LOCAL_ADDRESS, SSDP_SITE_LOCAL_ADDRESS,
+};
+
+const MAX_PACKET_SIZE: usize = 2048;
+
+/// How long packets reflected onto an interface are remembered, so that they aren't
+/// reflected once more when they come back (from another reflector or through a loop).
+const ECHO_WINDOW: Duration = Duration::from_secs(5);
+
+/// M-SEARCH queries whose responses are relayed at the same time.
+const MAX_PENDING_SEARCHES: usize = 32;
+
+/// Devices remembered as being of a type the filter lets through.
+const MAX_KNOWN_DEVICES: usize = 1024;
+
+/// Forwards SSDP traffic between interfaces, so that UPnP devices on one network
+/// are visible on the others: NOTIFYs are multicast again on every other
+/// interface, and M-SEARCH queries too, with their responses relayed to the searcher.
+pub struct Reflector {
+    interfaces: InterfaceSelection,
+    ip_version: IpVersion,
+    reflection: Arc<Reflection>,
+    tasks: Vec<(Binding, JoinHandle<()>)>,
+}
+
+/// What the receiving tasks of every interface share.
+struct Reflection {
+    ports: RwLock<Vec<Arc<Port>>>,
+    filter: DeviceFilter,
+    searches: Arc<Semaphore>,
+    bind_to_device: bool,
+}
+
+/// The sockets of one interface address traffic is reflected from and to.
+struct Port {
+    binding: Binding,
+    /// Bound to port 1900, receives the multicast traffic of the interface.
+    listen_socket: UdpSocket,
+    /// Sends reflected packets onto the interface, from an ephemeral port.
+    send_socket: UdpSocket,
+    groups: Vec<SocketAddr>,
+    /// Digests of the packets recently reflected onto this interface.
+    reflected: Mutex<VecDeque<(u64, Instant)>>,
+}
+
+impl Reflector {
+    /// Reflect between `interfaces`, given by name or IP address. `device_types`
+    /// restricts reflection to some devices, every device is reflected when empty.
+    pub fn open(
+        interfaces: Vec<String>,
+        device_types: Vec<String>,
+        ip_version: IpVersion,
+        bind_to_device: bool,
+    ) -> Result<Self> {
+        let mut reflector = Reflector {
+            interfaces: InterfaceSelection::Only(interfaces),
+            ip_version,
+            reflection: Arc::new(Reflection {
+                ports: RwLock::new(Vec::new()),
+                filter: DeviceFilter::new(device_types),
+                searches: Arc::new(Semaphore::new(MAX_PENDING_SEARCHES)),
+                bind_to_device,
+            }),
+            tasks: Vec::new(),
+        };
+
+        for binding in reflector.wanted() {
+            reflector.add_port(binding)?;
+        }
+
+        if reflector.tasks.len() < 2 {
+            warn!(target: "dlnaproxy", "Fewer than two of the interfaces to reflect between are up, waiting for them.");
+        }
+
+        Ok(reflector)
+    }
+
+    fn wanted(&self) -> Vec<Binding> {
+        bindings(&self.interfaces, self.ip_version)
+            .into_iter()
+            .filter_map(|(binding, _)| binding)
+            .collect()
+    }
+
+    fn add_port(&mut self, binding: Binding) -> Result<()> {
+        let port = Arc::new(Port::open(binding.clone(), self.reflection.bind_to_device)?);
+
+        info!(target: "dlnaproxy", "Reflecting SSDP on {} ({})", binding.name, binding.address);
+
+        self.reflection.ports.write().unwrap().push(port.clone());
+        self.tasks
+            .push((binding, tokio::task::spawn(reflect_task(self.reflection.clone(), port))));
+
+        Ok(())
+    }
+
+    /// Follow interface addresses as they come and go.
+    fn refresh(&mut self) {
+        let wanted = self.wanted();
+
+        let (kept, removed): (Vec<_>, Vec<_>) = self
+            .tasks
+            .drain(..)
+            .partition(|(binding, _)| wanted.contains(binding));
+
+        self.tasks = kept;
+
+        for (binding, task) in removed {
+            info!(target: "dlnaproxy", "No longer reflecting SSDP on {} ({}), closing its sockets.", binding.name, binding.address);
+
+            task.abort();
+            self.reflection
+                .ports
+                .write()
+                .unwrap()
+                .retain(|port| port.binding != binding);
+        }
+
+        for binding in wanted {
+            if self.tasks.iter().any(|(b, _)| *b == binding) {
+                continue;
+            }
+
+            let name = format!("{} ({})", binding.name, binding.address);
+
+            if let Err(e) = self.add_port(binding) {
+                warn!(target: "dlnaproxy", "Couldn't open SSDP reflector sockets on {}: {}", name, e);
+            }
+        }
+    }
+}
+
+/// Reflect until the process exits, following network changes.
+pub async fn reflector_task(mut reflector: Reflector) {
+    let mut watcher = NetworkWatcher::new();
+
+    loop {
+        watcher.changed().await;
+        reflector.refresh();
+    }
+}
+
+async fn reflect_task(reflection: Arc<Reflection>, port: Arc<Port>) {
+    let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+    loop {
+        let (bytes_read, src_addr) = match port.listen_socket.recv_from(&mut buffer).await {
+            Ok(result) => result,
+            Err(e) => {
+                warn!(target: "dlnaproxy", "Failed to receive SSDP packet on {}: {}. Continuing...", port.binding.name, e);
+                continue;
+            }
+        };
+
+        reflection.reflect(&port, &buffer[..bytes_read], src_addr).await;
+    }
+}
+
+impl Reflection {
+    async fn reflect(self: &Arc<Self>, from: &Arc<Port>, packet: &[u8], src_addr: SocketAddr) {
+        // Our own announcements are sent on every interface already.
+        if self.is_own_address(src_addr.ip()) {
+            return;
+        }
+
+        let digest = digest(packet);
+
+        if from.reflected_recently(digest) {
+            trace!(target: "dlnaproxy", "Not reflecting packet from {} again, it was reflected onto {} already.", src_addr, from.binding.name);
+            return;
+        }
+
+        let (method, headers) = match parse_ssdp(packet) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                trace!(target: "dlnaproxy", "Not reflecting packet from {}: {}", src_addr, e);
+                return;
+            }
+        };
+
+        // Unicast queries are meant for this host, not for the other networks.
+        if !is_multicast(&headers) {
+            return;
+        }
+
+        let host: Option<SocketAddr> = headers.get("HOST").and_then(|host| host.trim().parse().ok());
+        let header = |name: &str| headers.get(name).map(|value| value.trim()).unwrap_or_default();
+
+        let targets = self.other_ports(from);
+        if targets.is_empty() {
+            return;
+        }
+
+        match method.as_str() {
+            "NOTIFY" => {
+                if !self.filter.allows(header("NT"), header("USN")) {
+                    trace!(target: "dlnaproxy", "Not reflecting {} (NT: {}) from {}: filtered out.", header("NTS"), header("NT"), src_addr);
+                    return;
+                }
+
+                trace!(target: "dlnaproxy", "Reflecting {} (NT: {}) from {} on {}.", header("NTS"), header("NT"), src_addr, from.binding.name);
+
+                for port in targets {
+                    port.send(packet, host, digest).await;
+                }
+            }
+            "M-SEARCH" => {
+                let search_target = header("ST");
+
+                if let Err(reason) = response_delay(&headers) {
+                    debug!(target: "dlnaproxy", "Not reflecting M-SEARCH (ST: {}) from {}: {}.", search_target, src_addr, reason);
+                    return;
+                }
+
+                if !self.filter.allows_search(search_target) {
+                    trace!(target: "dlnaproxy", "Not reflecting M-SEARCH (ST: {}) from {}: filtered out.", search_target, src_addr);
+                    return;
+                }
+
+                let Ok(permit) = self.searches.clone().try_acquire_owned() else {
+                    debug!(target: "dlnaproxy", "Too many M-SEARCH queries being reflected, dropping the one from {}.", src_addr);
+                    return;
+                };
+
+                debug!(target: "dlnaproxy", "Reflecting M-SEARCH (ST: {}) from {} on {}.", search_target, src_addr, from.binding.name);
+
+                // Answers come within MX seconds, give them some time to travel.
+                let mx = header("MX").parse::<u64>().unwrap_or(1).min(MAX_MX_SECONDS);
+                let window = Duration::from_secs(mx + 1);
+
+                tokio::task::spawn(self.clone().relay_search(
+                    from.clone(),
+                    targets,
+                    packet.to_vec(),
+                    host,
+                    src_addr,
+                    window,
+                    permit,
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    fn is_own_address(&self, address: IpAddr) -> bool {
+        self.ports
+            .read()
+            .unwrap()
+            .iter()
+            .any(|port| port.binding.address == address)
+    }
+
+    /// Ports of the same IP version on the other interfaces.
+    fn other_ports(&self, from: &Port) -> Vec<Arc<Port>> {
+        self.ports
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|port| {
+                port.binding.name != from.binding.name
+                    && port.binding.address.is_ipv6() == from.binding.address.is_ipv6()
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Send `query` on every port of `targets`, relaying the responses to `searcher` through `from`.
+    #[allow(clippy::too_many_arguments)]
+    async fn relay_search(
+        self: Arc<Self>,
+        from: Arc<Port>,
+        targets: Vec<Arc<Port>>,
+        query: Vec<u8>,
+        host: Option<SocketAddr>,
+        searcher: SocketAddr,
+        window: Duration,
+        _permit: OwnedSemaphorePermit,
+    ) {
+        let mut searches = JoinSet::new();
+
+        for port in targets {
+            let reflection = self.clone();
+            let from = from.clone();
+            let query = query.clone();
+
+            searches.spawn(async move {
+                if let Err(e) = reflection.search(&port, &from, &query, host, searcher, window).await {
+                    debug!(target: "dlnaproxy", "Couldn't reflect M-SEARCH from {} on {}: {}", searcher, port.binding.name, e);
+                }
+            });
+        }
+
+        while searches.join_next().await.is_some() {}
+    }
+
+    async fn search(
+        &self,
+        port: &Port,
+        from: &Port,
+        query: &[u8],
+        host: Option<SocketAddr>,
+        searcher: SocketAddr,
+        window: Duration,
+    ) -> Result<()> {
+        // A socket of its own, so that responses can be told apart from other searches'.
+        let socket = open_send_socket(&port.binding, self.bind_to_device, "reflector search")?;
+
+        port.remember(digest(query));
+        socket
+            .send_to(query, port.destination(host))
+            .await
+            .context("Failed to send M-SEARCH")?;
+
+        let deadline = time::sleep(window);
+        tokio::pin!(deadline);
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        loop {
+            let (bytes_read, src_addr) = tokio::select! {
+                _ = &mut deadline => return Ok(()),
+                received = socket.recv_from(&mut buffer) => received.context("Failed to receive M-SEARCH response")?,
+            };
+
+            let response = &buffer[..bytes_read];
+
+            let headers = match parse_ssdp_response(response) {
+                Ok(headers) => headers,
+                Err(e) => {
+                    trace!(target: "dlnaproxy", "Not relaying response from {}: {}", src_addr, e);
+                    continue;
+                }
+            };
+
+            let header = |name: &str| headers.get(name).map(|value| value.trim()).unwrap_or_default();
+
+            if !self.filter.allows(header("ST"), header("USN")) {
+                trace!(target: "dlnaproxy", "Not relaying response (ST: {}) from {}: filtered out.", header("ST"), src_addr);
+                continue;
+            }
+
+            trace!(target: "dlnaproxy", "Relaying response (ST: {}) from {} to {}.", header("ST"), src_addr, searcher);
+
+            if let Err(e) = from.send_socket.send_to(response, searcher).await {
+                debug!(target: "dlnaproxy", "Couldn't relay M-SEARCH response to {}: {}", searcher, e);
+            }
+        }
+    }
+}
+
+impl Port {
+    fn open(binding: Binding, pin_to_device: bool) -> Result<Self> {
+        let what = format!("{} reflector", binding.name);
+
+        let (listen_addr, groups): (SocketAddr, Vec<SocketAddr>) = if binding.address.is_ipv6() {
+            (
+                SocketAddr::from(LISTEN_ADDRESS_V6),
+                vec![SSDP_LINK_LOCAL_ADDRESS.into(), SSDP_SITE_LOCAL_ADDRESS.into()],
+            )
+        } else {
+            (SocketAddr::from(LISTEN_ADDRESS), vec![SSDP_ADDRESS.into()])
+        };
+
+        let listen_socket = bind_udp(listen_addr, &format!("{} listen", what))?;
+
+        receive_joined_groups_only(&listen_socket, &what)?;
+        if pin_to_device {
+            bind_to_device(&listen_socket, &binding.name, &what)?;
+        }
+
+        for group in &groups {
+            match (group.ip(), binding.address) {
+                (IpAddr::V4(group), IpAddr::V4(address)) => listen_socket
+                    .join_multicast_v4(group, address)
+                    .with_context(|| format!("Failed to join SSDP multicast group on {} listen socket.", what))?,
+                (IpAddr::V6(group), _) => listen_socket
+                    .join_multicast_v6(&group, binding.index)
+                    .with_context(|| format!("Failed to join SSDP multicast group {} on {} listen socket.", group, what))?,
+                _ => unreachable!("groups are of the binding's IP version"),
+            }
+        }
+
+        Ok(Port {
+            send_socket: open_send_socket(&binding, pin_to_device, &what)?,
+            listen_socket,
+            groups,
+            binding,
+            reflected: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    /// The group a packet sent to `host` goes to on this interface.
+    fn destination(&self, host: Option<SocketAddr>) -> SocketAddr {
+        host.filter(|host| self.groups.contains(host))
+            .unwrap_or(self.groups[0])
+    }
+
+    async fn send(&self, packet: &[u8], host: Option<SocketAddr>, digest: u64) {
+        self.remember(digest);
+
+        if let Err(e) = self.send_socket.send_to(packet, self.destination(host)).await {
+            debug!(target: "dlnaproxy", "Couldn't reflect packet onto {}: {}", self.binding.name, e);
+        }
+    }
+
+    fn remember(&self, digest: u64) {
+        let mut reflected = self.reflected.lock().unwrap();
+
+        forget_expired(&mut reflected);
+        reflected.push_back((digest, Instant::now()));
+    }
+
+    fn reflected_recently(&self, digest: u64) -> bool {
+        let mut reflected = self.reflected.lock().unwrap();
+
+        forget_expired(&mut reflected);
+        reflected.iter().any(|(d, _)| *d == digest)
+    }
+}
+
+fn forget_expired(reflected: &mut VecDeque<(u64, Instant)>) {
+    while reflected.front().is_some_and(|(_, at)| at.elapsed() > ECHO_WINDOW) {
+        reflected.pop_front();
+    }
+}
+
+/// A socket sending multicast out of `binding`'s interface from an ephemeral
+/// port, without looping it back to our own sockets.
+fn open_send_socket(binding: &Binding, pin_to_device: bool, what: &str) -> Result<UdpSocket> {
+    let (addr, ipv6) = match binding.address {
+        IpAddr::V4(_) => (SocketAddr::from(BROADCAST_ADDRESS), false),
+        IpAddr::V6(_) => (SocketAddr::from(BROADCAST_ADDRESS_V6), true),
+    };
+
+    let socket = bind_udp(addr, &format!("{} send", what))?;
+
+    if pin_to_device {
+        bind_to_device(&socket, &binding.name, what)?;
+    }
+    select_interface(&socket, binding, what)?;
+
+    let sock_ref = SockRef::from(&socket);
+    let no_loop = if ipv6 {
+        sock_ref.set_multicast_loop_v6(false)
+    } else {
+        sock_ref.set_multicast_loop_v4(false)
+    };
+    no_loop.with_context(|| format!("Failed to disable multicast loopback on {} send socket", what))?;
+
+    Ok(socket)
+}
+
+fn digest(packet: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    packet.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Restricts reflection to devices of some types, e.g. MediaServer and MediaRenderer.
+///
+/// Only the announcements for the device type itself say what a device is: the
+/// UUIDs of the devices seen with a wanted type are remembered, so that their
+/// `upnp:rootdevice`, `uuid:` and service announcements get through as well.
+struct DeviceFilter {
+    /// Short names ("MediaServer") or full URNs, whose version is ignored.
+    types: Vec<String>,
+    known: Mutex<VecDeque<String>>,
+}
+
+impl DeviceFilter {
+    fn new(types: Vec<String>) -> Self {
+        DeviceFilter {
+            types,
+            known: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Whether an announcement or M-SEARCH response for `target` (its NT or ST) may be reflected.
+    fn allows(&self, target: &str, usn: &str) -> bool {
+        if self.types.is_empty() {
+            return true;
+        }
+
+        let uuid = usn.split("::").next().unwrap_or(usn);
+        let mut known = self.known.lock().unwrap();
+
+        if self.is_wanted_type(target) {
+            if !known.iter().any(|known| known == uuid) {
+                if known.len() == MAX_KNOWN_DEVICES {
+                    known.pop_front();
+                }
+                known.push_back(uuid.to_string());
+            }
+            return true;
+        }
+
+        known.iter().any(|known| known == uuid)
+    }
+
+    /// Whether an M-SEARCH for `search_target` may be reflected: searches for
+    /// other device types are useless, the rest may be answered by wanted devices.
+    fn allows_search(&self, search_target: &str) -> bool {
+        self.types.is_empty() || !is_device_type(search_target) || self.is_wanted_type(search_target)
+    }
+
+    fn is_wanted_type(&self, target: &str) -> bool {
+        if !is_device_type(target) {
+            return false;
+        }
+
+        let target_type = target.split(':').nth(3).unwrap_or_default();
+
+        self.types.iter().any(|wanted| {
+            if wanted.contains(':') {
+                without_version(wanted).eq_ignore_ascii_case(without_version(target))
+            } else {
+                wanted.eq_ignore_ascii_case(target_type)
+            }
+        })
+    }
+}
+
+/// "urn:<domain>:device:<type>:<version>"
+fn is_device_type(target: &str) -> bool {
+    let parts: Vec<_> = target.split(':').collect();
+
+    parts.len() == 5 && parts[0] == "urn" && parts[2] == "device"
+}
+
+fn without_version(urn: &str) -> &str {
+    urn.rsplit_once(':').map_or(urn, |(without, _)| without)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RENDERER: &str = "urn:schemas-upnp-org:device:MediaRenderer:1";
+    const PRINTER: &str = "urn:schemas-upnp-org:device:Printer:1";
+
+    #[test]
+    fn test_filter_disabled() {
+        let filter = DeviceFilter::new(Vec::new());
+
+        assert!(filter.allows(PRINTER, "uuid:printer::urn:schemas-upnp-org:device:Printer:1"));
+        assert!(filter.allows_search(PRINTER));
+    }
+
+    #[test]
+    fn test_filter_device_types() {
+        let filter = DeviceFilter::new(vec![
+            "MediaRenderer".to_string(),
+            "urn:schemas-upnp-org:device:MediaServer:2".to_string(),
+        ]);
+
+        assert!(filter.is_wanted_type(RENDERER));
+        assert!(filter.is_wanted_type("urn:schemas-upnp-org:device:MediaServer:1"));
+        assert!(!filter.is_wanted_type(PRINTER));
+        assert!(!filter.is_wanted_type("urn:schemas-upnp-org:service:RenderingControl:1"));
+    }
+
+    #[test]
+    fn test_filter_learns_devices() {
+        let filter = DeviceFilter::new(vec!["MediaRenderer".to_string()]);
+
+        // Not known yet.
+        assert!(!filter.allows("upnp:rootdevice", "uuid:tv::upnp:rootdevice"));
+
+        assert!(filter.allows(RENDERER, "uuid:tv::urn:schemas-upnp-org:device:MediaRenderer:1"));
+        assert!(filter.allows("upnp:rootdevice", "uuid:tv::upnp:rootdevice"));
+        assert!(filter.allows("uuid:tv", "uuid:tv"));
+
+        assert!(!filter.allows(PRINTER, "uuid:printer::urn:schemas-upnp-org:device:Printer:1"));
+        assert!(!filter.allows("upnp:rootdevice", "uuid:printer::upnp:rootdevice"));
+    }
+
+    #[test]
+    fn test_filter_searches() {
+        let filter = DeviceFilter::new(vec!["MediaRenderer".to_string()]);
+
+        assert!(filter.allows_search("ssdp:all"));
+        assert!(filter.allows_search("upnp:rootdevice"));
+        assert!(filter.allows_search("urn:schemas-upnp-org:service:AVTransport:1"));
+        assert!(filter.allows_search(RENDERER));
+        assert!(!filter.allows_search(PRINTER));
+    }
+}
@@ -0,0 +1,141 @@
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+use ipnet::IpNet;
+use log::info;
+
+/// Largest CIDR range we agree to sweep, so that a typo doesn't flood the network.
+const MAX_SWEEP_HOSTS: usize = 1024;
+
+/// How many hosts learned from M-SEARCH queries are remembered.
+const MAX_LEARNED_HOSTS: usize = 256;
+
+/// Hosts that get every NOTIFY unicast to them, on networks that filter multicast
+/// between clients.
+#[derive(Default)]
+pub struct UnicastTargets {
+    configured: Vec<IpAddr>,
+    /// Whether hosts sending us M-SEARCH queries are added to the targets.
+    learn: bool,
+    learned: Mutex<Vec<IpAddr>>,
+}
+
+impl UnicastTargets {
+    /// `targets` are IP addresses or CIDR ranges, e.g. "192.168.1.20" or "10.0.0.0/28".
+    pub fn new(targets: &[String], learn: bool) -> Result<Self> {
+        let mut configured = Vec::new();
+
+        for target in targets {
+            for host in parse_target(target)? {
+                if !configured.contains(&host) {
+                    configured.push(host);
+                }
+            }
+        }
+
+        Ok(UnicastTargets {
+            configured,
+            learn,
+            learned: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Every target, configured ones first.
+    pub fn hosts(&self) -> Vec<IpAddr> {
+        let learned = self.learned.lock().unwrap();
+
+        self.configured.iter().chain(learned.iter()).copied().collect()
+    }
+
+    /// Add `host`, which sent us an M-SEARCH, to the targets if learning is enabled.
+    pub fn learn(&self, host: IpAddr) {
+        if !self.learn || host.is_loopback() || host.is_multicast() || host.is_unspecified() {
+            return;
+        }
+
+        if self.configured.contains(&host) {
+            return;
+        }
+
+        let mut learned = self.learned.lock().unwrap();
+
+        if learned.contains(&host) {
+            return;
+        }
+
+        // Forget the oldest host rather than growing forever.
+        if learned.len() == MAX_LEARNED_HOSTS {
+            learned.remove(0);
+        }
+
+        info!(target: "dlnaproxy", "Learned unicast target {} from its M-SEARCH.", host);
+        learned.push(host);
+    }
+}
+
+/// Hosts of `target`, a single IP address or a CIDR range.
+pub fn parse_target(target: &str) -> Result<Vec<IpAddr>> {
+    let target = target.trim();
+
+    if let Ok(host) = target.parse::<IpAddr>() {
+        return Ok(vec![host]);
+    }
+
+    let range: IpNet = target
+        .parse()
+        .with_context(|| format!("Invalid unicast target {}: expected an IP address or a CIDR range", target))?;
+
+    let hosts: Vec<IpAddr> = range.hosts().take(MAX_SWEEP_HOSTS + 1).collect();
+
+    if hosts.len() > MAX_SWEEP_HOSTS {
+        bail!("Unicast target {} is too large, at most {} hosts can be swept", target, MAX_SWEEP_HOSTS);
+    }
+
+    Ok(hosts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(parse_target("192.168.1.20").unwrap(), [ip("192.168.1.20")]);
+        assert_eq!(parse_target("fd00::20").unwrap(), [ip("fd00::20")]);
+
+        // Network and broadcast addresses are left out.
+        assert_eq!(
+            parse_target("10.0.0.0/30").unwrap(),
+            [ip("10.0.0.1"), ip("10.0.0.2")]
+        );
+
+        assert!(parse_target("10.0.0.0/8").is_err());
+        assert!(parse_target("tv.local").is_err());
+    }
+
+    #[test]
+    fn test_learn() {
+        let targets = UnicastTargets::new(&["192.168.1.20".to_string()], true).unwrap();
+
+        targets.learn(ip("192.168.1.30"));
+        targets.learn(ip("192.168.1.30"));
+        targets.learn(ip("192.168.1.20"));
+        targets.learn(ip("127.0.0.1"));
+
+        assert_eq!(targets.hosts(), [ip("192.168.1.20"), ip("192.168.1.30")]);
+    }
+
+    #[test]
+    fn test_learn_disabled() {
+        let targets = UnicastTargets::new(&[], false).unwrap();
+
+        targets.learn(ip("192.168.1.30"));
+
+        assert!(targets.hosts().is_empty());
+    }
+}
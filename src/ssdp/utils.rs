@@ -1,13 +1,41 @@
-use log::{debug, trace};
+use log::{debug, info, trace};
+use std::hash::{DefaultHasher, Hash as _, Hasher as _};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
 use tokio::net::ToSocketAddrs;
 use tokio::net::UdpSocket;
+use tokio::sync::{Mutex, Notify};
 
 use anyhow::Context;
 use anyhow::Result;
-use reqwest::header::SERVER;
+use reqwest::header::{HeaderName, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, SERVER};
+use reqwest::{StatusCode, Url};
 use serde::Deserialize;
 
-use crate::ssdp::packet::SSDPPacket;
+use crate::ssdp::advertisement::{advertisements, search_responses, Advertisement, AdvertisementDiff};
+use crate::ssdp::boot::BootId;
+use crate::ssdp::cache::{DescriptionCache, Freshness, Validators};
+use crate::ssdp::health::{OriginHealth, OriginState, Transition};
+use crate::ssdp::packet::{BootInfo, SSDPPacket};
+use crate::ssdp::{SSDPChannel, LISTEN_ADDRESS, SSDP_ADDRESS};
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct DLNAService {
+    #[serde(rename = "serviceType")]
+    pub(crate) service_type: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct DLNAServiceList {
+    #[serde(rename = "service", default)]
+    pub(crate) services: Vec<DLNAService>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct DLNADeviceList {
+    #[serde(rename = "device", default)]
+    pub(crate) devices: Vec<DLNADevice>,
+}
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct DLNADevice {
@@ -16,6 +44,15 @@ pub(crate) struct DLNADevice {
 
     #[serde(rename = "UDN")]
     pub(crate) unique_device_name: String,
+
+    #[serde(rename = "friendlyName", default)]
+    pub(crate) friendly_name: String,
+
+    #[serde(rename = "serviceList", default)]
+    pub(crate) service_list: DLNAServiceList,
+
+    #[serde(rename = "deviceList", default)]
+    pub(crate) device_list: DLNADeviceList,
 }
 
 #[derive(Debug, Deserialize)]
@@ -23,42 +60,346 @@ pub(crate) struct DLNADescription {
     pub(crate) device: DLNADevice,
 }
 
-pub struct EndpointInfo {
+/// A device (root or embedded) as far as SSDP is concerned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
     pub device_type: String,
     pub unique_device_name: String,
+    pub service_types: Vec<String>,
+}
+
+pub struct EndpointInfo {
+    /// Root device first, followed by every embedded device (depth-first).
+    pub devices: Vec<DeviceInfo>,
     pub server: String,
 }
 
+impl DLNADescription {
+    /// Flatten the device tree, root device first.
+    pub(crate) fn devices(&self) -> Vec<DeviceInfo> {
+        fn walk(device: &DLNADevice, devices: &mut Vec<DeviceInfo>) {
+            devices.push(DeviceInfo {
+                device_type: device.device_type.trim().to_string(),
+                unique_device_name: device.unique_device_name.trim().to_string(),
+                service_types: device
+                    .service_list
+                    .services
+                    .iter()
+                    .map(|s| s.service_type.trim().to_string())
+                    .collect(),
+            });
+
+            for embedded in &device.device_list.devices {
+                walk(embedded, devices);
+            }
+        }
+
+        let mut devices = Vec::new();
+        walk(&self.device, &mut devices);
+        devices
+    }
+}
+
+/// What we advertise as LOCATION.
+pub enum Location {
+    /// Advertised as is.
+    Fixed(String),
+    /// The proxy listens on every address: LOCATION follows our current LAN address.
+    LanAddress(Url),
+}
+
+impl Location {
+    fn split(self) -> (Option<Url>, String) {
+        match self {
+            Location::Fixed(location) => (None, location),
+            Location::LanAddress(url) => (Some(url.clone()), url.into()),
+        }
+    }
+}
+
+/// URL the description is fetched from and LOCATION advertised for the origin's
+/// description at `origin_url`, when it is proxied through `proxy` or not.
+pub fn endpoint_urls(origin_url: &Url, proxy: Option<SocketAddr>) -> (Url, Location) {
+    let Some(proxy_addr) = proxy else {
+        return (origin_url.clone(), Location::Fixed(origin_url.to_string()));
+    };
+
+    let mut url = origin_url.clone();
+    url.set_port(Some(proxy_addr.port())).unwrap();
+
+    if proxy_addr.ip().is_unspecified() {
+        // Reachable on every address: fetch the description through loopback,
+        // but advertise our LAN address(es) and follow them if they change.
+        let loopback: IpAddr = match proxy_addr {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        };
+        url.set_ip_host(loopback).unwrap();
+
+        (url.clone(), Location::LanAddress(url))
+    } else {
+        url.set_ip_host(proxy_addr.ip()).unwrap();
+
+        (url.clone(), Location::Fixed(url.to_string()))
+    }
+}
+
+/// A description as last announced to clients.
+#[derive(Clone)]
+struct Announcement {
+    info: Arc<EndpointInfo>,
+    config_id: u32,
+}
+
 pub struct InteractiveSSDP {
     http_client: reqwest::Client,
-    remote_desc_url: String,
+    /// Changes when the origin is re-discovered.
+    remote_desc_url: std::sync::RwLock<String>,
+    /// Set when LOCATION has to follow our LAN address.
+    location_template: std::sync::RwLock<Option<Url>>,
+    location: std::sync::RwLock<String>,
+    /// LOCATION advertised over IPv6, when it differs from the IPv4 one.
+    location_v6: std::sync::RwLock<Option<String>>,
     cache_max_age: usize,
+    description_cache: DescriptionCache,
+    /// Serializes fetches so that concurrent cache misses hit the origin only once.
+    fetch_lock: Mutex<()>,
+    /// Wakes up the monitor task when a stale description gets served.
+    revalidate: Notify,
+    health: std::sync::Mutex<OriginHealth>,
+    /// What clients on the LAN currently believe about the remote server.
+    announced: std::sync::Mutex<Option<Announcement>>,
+    boot_id: Arc<BootId>,
 }
 
 impl InteractiveSSDP {
-    pub fn new(client: reqwest::Client, url: &str, cache_max_age: usize) -> Self {
+    pub fn new(
+        client: reqwest::Client,
+        url: &str,
+        location: Location,
+        cache_max_age: usize,
+        description_cache: DescriptionCache,
+        health: OriginHealth,
+        boot_id: Arc<BootId>,
+    ) -> Self {
+        let (location_template, location) = location.split();
+
         InteractiveSSDP {
             http_client: client,
-            remote_desc_url: url.into(),
+            remote_desc_url: std::sync::RwLock::new(url.into()),
+            location_template: std::sync::RwLock::new(location_template),
+            location: std::sync::RwLock::new(location),
+            location_v6: std::sync::RwLock::new(None),
             cache_max_age,
+            description_cache,
+            fetch_lock: Mutex::new(()),
+            revalidate: Notify::new(),
+            health: std::sync::Mutex::new(health),
+            announced: std::sync::Mutex::new(None),
+            boot_id,
+        }
+    }
+
+    pub fn description_url(&self) -> String {
+        self.remote_desc_url.read().unwrap().clone()
+    }
+
+    /// Fetch the description from `url` and advertise `location` from now on,
+    /// e.g. once the origin has been re-discovered elsewhere.
+    ///
+    /// LOCATION is only valid again once relocated if it follows our LAN address.
+    /// The cached description's validators are dropped, as a different server could
+    /// happen to match them and have the old description pass for its own.
+    pub fn retarget(&self, url: &str, location: Location) {
+        let (location_template, location) = location.split();
+
+        *self.remote_desc_url.write().unwrap() = url.into();
+        *self.location_template.write().unwrap() = location_template;
+        *self.location.write().unwrap() = location;
+        *self.location_v6.write().unwrap() = None;
+
+        self.description_cache.forget_validators();
+    }
+
+    /// LOCATION advertised on an interface with `interface_address`, or over
+    /// whatever route the OS picks when it is `None`.
+    fn location(&self, ipv6: bool, interface_address: Option<IpAddr>) -> String {
+        if let Some(url) = interface_address.and_then(|address| self.location_at(address)) {
+            return url.into();
+        }
+
+        if ipv6 {
+            if let Some(location) = self.location_v6.read().unwrap().as_ref() {
+                return location.clone();
+            }
+        }
+
+        self.location.read().unwrap().clone()
+    }
+
+    pub fn is_relocatable(&self) -> bool {
+        self.location_template.read().unwrap().is_some()
+    }
+
+    /// Point LOCATION at `address` if it follows our LAN address. Returns whether it changed.
+    ///
+    /// IPv6 addresses get their own LOCATION, and only when the proxy listens on IPv6.
+    pub fn relocate(&self, address: IpAddr) -> bool {
+        let Some(url) = self.location_at(address) else {
+            return false;
+        };
+
+        if address.is_ipv6() {
+            let mut location = self.location_v6.write().unwrap();
+            if location.as_deref() == Some(url.as_str()) {
+                return false;
+            }
+            *location = Some(url.to_string());
+        } else {
+            let mut location = self.location.write().unwrap();
+            if *location == url.as_str() {
+                return false;
+            }
+            *location = url.to_string();
         }
+
+        debug!(target: "dlnaproxy", "{}: LOCATION is now {}", self.description_url(), url);
+        true
     }
 
-    async fn fetch_endpoint_info(&self) -> Result<EndpointInfo> {
+    /// LOCATION pointing at `address`, if it follows our LAN address and the proxy
+    /// can be reached there.
+    fn location_at(&self, address: IpAddr) -> Option<Url> {
+        let template = self.location_template.read().unwrap().clone()?;
+
+        let listens_on_v6 = template.host_str().is_some_and(|host| host.starts_with('['));
+        if address.is_ipv6() && !listens_on_v6 {
+            return None;
+        }
+
+        let mut url = template;
+        url.set_ip_host(address).ok()?;
+
+        Some(url)
+    }
+
+    fn boot_info(&self, config_id: u32) -> BootInfo {
+        BootInfo {
+            boot_id: self.boot_id.current(),
+            config_id,
+            search_port: (LISTEN_ADDRESS.1 != SSDP_ADDRESS.1).then_some(LISTEN_ADDRESS.1),
+        }
+    }
+
+    pub fn origin_state(&self) -> OriginState {
+        self.health.lock().unwrap().state()
+    }
+
+    /// Feed the outcome of a health check into the origin state machine.
+    pub fn record_health_check(&self, success: bool) -> Option<Transition> {
+        let mut health = self.health.lock().unwrap();
+
+        if success {
+            health.record_success()
+        } else {
+            health.record_failure()
+        }
+    }
+
+    /// Interval at which the origin should be polled.
+    pub fn poll_interval(&self) -> std::time::Duration {
+        self.description_cache.ttl()
+    }
+
+    /// Resolves when a stale description was served and should be revalidated early.
+    pub async fn revalidation_requested(&self) {
+        self.revalidate.notified().await
+    }
+
+    /// Remote server's info, served from the cache whenever possible.
+    ///
+    /// A stale description is returned as is and revalidated in the background;
+    /// only a missing or expired one is fetched before returning.
+    pub async fn endpoint_info(&self) -> Result<Arc<EndpointInfo>> {
+        match self.description_cache.get() {
+            Some((info, Freshness::Fresh)) => Ok(info),
+            Some((info, Freshness::Stale)) => {
+                self.revalidate.notify_one();
+                Ok(info)
+            }
+            _ => {
+                let _guard = self.fetch_lock.lock().await;
+
+                // Someone else may have fetched it while we were waiting.
+                if let Some((info, Freshness::Fresh)) = self.description_cache.get() {
+                    return Ok(info);
+                }
+
+                self.fetch_endpoint_info().await
+            }
+        }
+    }
+
+    /// Last known info, however old, falling back to fetching it.
+    ///
+    /// Good enough to say goodbye on behalf of the remote server.
+    async fn last_endpoint_info(&self) -> Result<Arc<EndpointInfo>> {
+        match self.description_cache.get() {
+            Some((info, _)) => Ok(info),
+            None => self.endpoint_info().await,
+        }
+    }
+
+    /// Revalidate the cached description with the origin.
+    pub async fn refresh_endpoint_info(&self) -> Result<Arc<EndpointInfo>> {
+        let _guard = self.fetch_lock.lock().await;
+
+        self.fetch_endpoint_info().await
+    }
+
+    async fn fetch_endpoint_info(&self) -> Result<Arc<EndpointInfo>> {
         trace!(target: "dlnaproxy", "Fetching remote server's info.");
 
-        let endpoint_response = self
-            .http_client
-            .get(&self.remote_desc_url)
+        let validators = self.description_cache.validators();
+
+        let mut request = self.http_client.get(self.description_url());
+
+        if let Some(etag) = &validators.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+
+        let endpoint_response = request
             .send()
             .await
             .context("Failed to get description of remote endpoint.")?;
 
-        let server_ua = endpoint_response
-            .headers()
-            .get(SERVER)
-            .map(|hv| String::from_utf8_lossy(hv.as_bytes()).to_string())
-            .unwrap_or_else(|| "DLNAProxy/1.0".into());
+        if endpoint_response.status() == StatusCode::NOT_MODIFIED {
+            if let Some(info) = self.description_cache.revalidated() {
+                trace!(target: "dlnaproxy", "Remote server's description not modified.");
+                return Ok(info);
+            }
+        }
+
+        let endpoint_response = endpoint_response
+            .error_for_status()
+            .context("Remote endpoint returned an error status.")?;
+
+        let header = |name: HeaderName| {
+            endpoint_response
+                .headers()
+                .get(name)
+                .map(|hv| String::from_utf8_lossy(hv.as_bytes()).to_string())
+        };
+
+        let server_ua = header(SERVER).unwrap_or_else(|| "DLNAProxy/1.0".into());
+
+        let validators = Validators {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        };
 
         let body = endpoint_response
             .text()
@@ -68,11 +409,15 @@ impl InteractiveSSDP {
         let device_description: DLNADescription =
             quick_xml::de::from_str(&body).context("Failed to parse device's XML description.")?;
 
-        Ok(EndpointInfo {
-            device_type: device_description.device.device_type,
-            unique_device_name: device_description.device.unique_device_name,
+        let info = EndpointInfo {
+            devices: device_description.devices(),
             server: server_ua,
-        })
+        };
+
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+
+        Ok(self.description_cache.store(info, validators, hasher.finish()))
     }
 
     async fn send_to(
@@ -90,44 +435,230 @@ impl InteractiveSSDP {
         Ok(())
     }
 
-    pub async fn send_alive(&self, socket: &UdpSocket, dest: impl ToSocketAddrs) -> Result<()> {
-        let info = self.fetch_endpoint_info().await?;
+    /// Send on `channel`. A unicast target being unreachable doesn't prevent announcing
+    /// to the others, so it is only logged.
+    async fn send_on(&self, channel: &SSDPChannel, ssdp_packet: SSDPPacket, p_type: &str) -> Result<()> {
+        let result = self
+            .send_to(channel.socket(), channel.destination(), ssdp_packet, p_type)
+            .await;
+
+        match result {
+            Err(e) if channel.is_unicast() => {
+                debug!(target: "dlnaproxy", "Couldn't send ssdp:{} to {}: {}", p_type, channel.destination(), e);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    pub async fn send_alive(&self, channels: &[SSDPChannel]) -> Result<()> {
+        let info = self.endpoint_info().await?;
+        let config_id = self.description_cache.config_id();
 
-        let ssdp_alive = SSDPPacket::Alive {
-            desc_url: self.remote_desc_url.clone(),
-            server_ua: info.server,
-            device_type: info.device_type,
-            unique_device_name: info.unique_device_name,
-            cache_max_age: self.cache_max_age,
-        };
+        self.send_alive_for(channels, &info, config_id, &advertisements(&info.devices))
+            .await?;
 
-        self.send_to(socket, dest, ssdp_alive, "alive").await
+        *self.announced.lock().unwrap() = Some(Announcement { info, config_id });
+
+        Ok(())
     }
 
-    pub async fn send_ok(&self, socket: &UdpSocket, dest: impl ToSocketAddrs) -> Result<()> {
-        let info = self.fetch_endpoint_info().await?;
+    async fn send_alive_for(
+        &self,
+        channels: &[SSDPChannel],
+        info: &EndpointInfo,
+        config_id: u32,
+        ads: &[Advertisement],
+    ) -> Result<()> {
+        for channel in channels {
+            for adv in ads {
+                let ssdp_alive = SSDPPacket::Alive {
+                    host: channel.host(),
+                    desc_url: self.location(channel.is_ipv6(), channel.address()),
+                    server_ua: info.server.clone(),
+                    notification_type: adv.notification_type.clone(),
+                    unique_service_name: adv.unique_service_name.clone(),
+                    cache_max_age: self.cache_max_age,
+                    boot: self.boot_info(config_id),
+                };
+
+                self.send_on(channel, ssdp_alive, "alive").await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answer an M-SEARCH for `search_target` received on the interface with
+    /// `interface_address`, returning how many responses were sent.
+    pub async fn send_ok(
+        &self,
+        socket: &UdpSocket,
+        dest: SocketAddr,
+        search_target: &str,
+        interface_address: Option<IpAddr>,
+    ) -> Result<usize> {
+        let info = self.endpoint_info().await?;
+
+        let responses = search_responses(&info.devices, search_target);
+        let boot = self.boot_info(self.description_cache.config_id());
+
+        for adv in &responses {
+            let ssdp_ok = SSDPPacket::Ok {
+                desc_url: self.location(dest.is_ipv6(), interface_address),
+                server_ua: info.server.clone(),
+                search_target: adv.notification_type.clone(),
+                unique_service_name: adv.unique_service_name.clone(),
+                cache_max_age: self.cache_max_age,
+                boot,
+            };
+
+            self.send_to(socket, dest, ssdp_ok, "ok").await?;
+        }
+
+        Ok(responses.len())
+    }
 
-        let ssdp_ok = SSDPPacket::Ok {
-            desc_url: self.remote_desc_url.clone(),
-            unique_device_name: info.unique_device_name,
-            device_type: info.device_type,
-            server_ua: info.server,
-            cache_max_age: self.cache_max_age,
+    /// Say goodbye for what clients last heard from us, or else for the last known description.
+    pub async fn send_byebye(&self, channels: &[SSDPChannel]) -> Result<()> {
+        let announced = self.announced.lock().unwrap().clone();
+
+        let (info, config_id) = match announced {
+            Some(Announcement { info, config_id }) => (info, config_id),
+            None => {
+                let info = self.last_endpoint_info().await?;
+                (info, self.description_cache.config_id())
+            }
         };
 
-        self.send_to(socket, dest, ssdp_ok, "ok").await
+        self.send_byebye_for(channels, config_id, &advertisements(&info.devices))
+            .await?;
+
+        *self.announced.lock().unwrap() = None;
+
+        Ok(())
     }
 
-    pub async fn send_byebye(&self, socket: &UdpSocket, dest: impl ToSocketAddrs) -> Result<()> {
-        let info = self.fetch_endpoint_info().await?;
+    /// Say goodbye on `channels` only, e.g. when their interface goes away,
+    /// while staying announced everywhere else.
+    pub async fn withdraw(&self, channels: &[SSDPChannel]) -> Result<()> {
+        let announced = self.announced.lock().unwrap().clone();
+
+        match announced {
+            Some(Announcement { info, config_id }) => {
+                self.send_byebye_for(channels, config_id, &advertisements(&info.devices))
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn send_byebye_for(
+        &self,
+        channels: &[SSDPChannel],
+        config_id: u32,
+        ads: &[Advertisement],
+    ) -> Result<()> {
+        for channel in channels {
+            for adv in ads {
+                let ssdp_byebye = SSDPPacket::ByeBye {
+                    host: channel.host(),
+                    notification_type: adv.notification_type.clone(),
+                    unique_service_name: adv.unique_service_name.clone(),
+                    boot: self.boot_info(config_id),
+                };
+
+                self.send_on(channel, ssdp_byebye, "byebye").await?;
+            }
+        }
+
+        Ok(())
+    }
 
-        let ssdp_byebye = SSDPPacket::ByeBye {
-            unique_device_name: info.unique_device_name,
-            device_type: info.device_type,
+    /// Tell clients that BOOTID is about to become `next_boot_id`, for everything
+    /// announced so far.
+    pub async fn send_update(&self, channels: &[SSDPChannel], next_boot_id: u32) -> Result<()> {
+        let Some(announced) = self.announced.lock().unwrap().clone() else {
+            return Ok(());
         };
 
-        self.send_to(socket, dest, ssdp_byebye, "byebye").await
+        let ads = advertisements(&announced.info.devices);
+
+        for channel in channels {
+            for adv in &ads {
+                let ssdp_update = SSDPPacket::Update {
+                    host: channel.host(),
+                    desc_url: self.location(channel.is_ipv6(), channel.address()),
+                    notification_type: adv.notification_type.clone(),
+                    unique_service_name: adv.unique_service_name.clone(),
+                    boot: self.boot_info(announced.config_id),
+                    next_boot_id,
+                };
+
+                self.send_on(channel, ssdp_update, "update").await?;
+            }
+        }
+
+        Ok(())
     }
+
+    /// If `info` differs from what was last announced, withdraw the advertisements
+    /// that are gone and announce the current ones with the new CONFIGID.
+    /// Returns whether anything changed.
+    pub async fn reannounce_if_changed(&self, channels: &[SSDPChannel], info: Arc<EndpointInfo>) -> Result<bool> {
+        let Some(previous) = self.announced.lock().unwrap().clone() else {
+            return Ok(false);
+        };
+
+        let config_id = self.description_cache.config_id();
+
+        let diff = AdvertisementDiff::between(&previous.info.devices, &info.devices);
+        if diff.is_empty() && config_id == previous.config_id {
+            return Ok(false);
+        }
+
+        if diff.is_empty() {
+            info!(target: "dlnaproxy", "{}: description changed, CONFIGID.UPNP.ORG is now {}.", self.description_url(), config_id);
+        } else {
+            log_identity_change(&self.description_url(), &previous.info, &info, &diff);
+        }
+
+        self.send_byebye_for(channels, previous.config_id, &diff.removed)
+            .await?;
+
+        // Every advertisement has to carry the new CONFIGID, not only the added ones.
+        self.send_alive_for(channels, &info, config_id, &advertisements(&info.devices))
+            .await?;
+
+        *self.announced.lock().unwrap() = Some(Announcement { info, config_id });
+
+        Ok(true)
+    }
+}
+
+fn log_identity_change(
+    url: &str,
+    previous: &EndpointInfo,
+    current: &EndpointInfo,
+    diff: &AdvertisementDiff,
+) {
+    let (old_root, new_root) = (&previous.devices[0], &current.devices[0]);
+
+    if old_root.unique_device_name != new_root.unique_device_name {
+        info!(target: "dlnaproxy", "{}: UDN changed from {} to {}", url, old_root.unique_device_name, new_root.unique_device_name);
+    }
+    if old_root.device_type != new_root.device_type {
+        info!(target: "dlnaproxy", "{}: device type changed from {} to {}", url, old_root.device_type, new_root.device_type);
+    }
+
+    for adv in &diff.removed {
+        debug!(target: "dlnaproxy", "{}: withdrawing {}", url, adv.unique_service_name);
+    }
+    for adv in &diff.added {
+        debug!(target: "dlnaproxy", "{}: announcing {}", url, adv.unique_service_name);
+    }
+
+    info!(target: "dlnaproxy", "{}: description changed, {} advertisement(s) withdrawn and {} added.", url, diff.removed.len(), diff.added.len());
 }
 
 #[cfg(test)]
@@ -182,6 +713,81 @@ mod tests {
         assert_eq!(desc.device.unique_device_name, "uuid:test-device-udn");
     }
 
+    #[test]
+    fn test_parse_dlna_description_services() {
+        let xml = r#"<root xmlns="urn:schemas-upnp-org:device-1-0">
+    <device>
+        <deviceType>urn:schemas-upnp-org:device:MediaServer:1</deviceType>
+        <UDN>uuid:test-device-udn</UDN>
+        <serviceList>
+            <service>
+                <serviceType>urn:schemas-upnp-org:service:ContentDirectory:1</serviceType>
+                <serviceId>urn:upnp-org:serviceId:ContentDirectory</serviceId>
+            </service>
+            <service>
+                <serviceType>urn:schemas-upnp-org:service:ConnectionManager:1</serviceType>
+                <serviceId>urn:upnp-org:serviceId:ConnectionManager</serviceId>
+            </service>
+            <service>
+                <serviceType>urn:microsoft.com:service:X_MS_MediaReceiverRegistrar:1</serviceType>
+                <serviceId>urn:microsoft.com:serviceId:X_MS_MediaReceiverRegistrar</serviceId>
+            </service>
+        </serviceList>
+    </device>
+</root>"#;
+
+        let desc: DLNADescription = quick_xml::de::from_str(xml).unwrap();
+        let devices = desc.devices();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(
+            devices[0].service_types,
+            vec![
+                "urn:schemas-upnp-org:service:ContentDirectory:1",
+                "urn:schemas-upnp-org:service:ConnectionManager:1",
+                "urn:microsoft.com:service:X_MS_MediaReceiverRegistrar:1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_dlna_description_embedded_devices() {
+        let xml = r#"<root>
+    <device>
+        <deviceType>urn:schemas-upnp-org:device:MediaServer:1</deviceType>
+        <UDN>uuid:root</UDN>
+        <deviceList>
+            <device>
+                <deviceType>urn:schemas-upnp-org:device:Embedded:1</deviceType>
+                <UDN>uuid:embedded-1</UDN>
+                <serviceList>
+                    <service>
+                        <serviceType>urn:schemas-upnp-org:service:Foo:1</serviceType>
+                    </service>
+                </serviceList>
+                <deviceList>
+                    <device>
+                        <deviceType>urn:schemas-upnp-org:device:Nested:1</deviceType>
+                        <UDN>uuid:nested</UDN>
+                    </device>
+                </deviceList>
+            </device>
+            <device>
+                <deviceType>urn:schemas-upnp-org:device:Embedded:1</deviceType>
+                <UDN>uuid:embedded-2</UDN>
+            </device>
+        </deviceList>
+    </device>
+</root>"#;
+
+        let desc: DLNADescription = quick_xml::de::from_str(xml).unwrap();
+        let udns: Vec<_> = desc
+            .devices()
+            .into_iter()
+            .map(|d| d.unique_device_name)
+            .collect();
+        assert_eq!(udns, vec!["uuid:root", "uuid:embedded-1", "uuid:nested", "uuid:embedded-2"]);
+    }
+
     #[test]
     fn test_parse_dlna_description_minimal() {
         // Test with minimal required fields only
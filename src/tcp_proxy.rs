@@ -1,86 +1,216 @@
 use log::{debug, error, info, trace, warn};
 
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
 use tokio::{
-    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    io::{self, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, ReadBuf},
     net::{TcpListener, TcpStream},
     sync::Semaphore,
     task::JoinHandle,
-    time::timeout,
+    time::{timeout, Sleep},
 };
 
+use crate::encoding;
+use crate::gena::{EventRelay, EventRequest};
+use crate::rewrite::{RewriteRule, Rewriter};
+
 //Adapted from https://github.com/hishboy/rust-tcp-proxy/
 
 /// Maximum body size (10 MB) for content that needs URL rewriting.
 /// Bodies larger than this will be passed through without rewriting to prevent OOM.
-const MAX_REWRITABLE_BODY_SIZE: usize = 10 * 1024 * 1024;
+pub(crate) const MAX_REWRITABLE_BODY_SIZE: usize = 10 * 1024 * 1024;
+
+/// Maximum size of an HTTP head read from a client, so a peer can't make the proxy
+/// buffer an endless one.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Maximum size of the chunks a body is sent in, however much of it is at hand.
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Maximum size of a body rewritten whole just to keep an exact Content-Length,
+/// rather than being streamed and sent chunked.
+const MAX_BUFFERED_BODY_SIZE: usize = 64 * 1024;
 
 /// Maximum number of concurrent proxy connections.
 /// Provides backpressure to prevent resource exhaustion.
 const MAX_CONCURRENT_CONNECTIONS: usize = 100;
 
+/// Maximum number of idle keep-alive connections kept open to the origin.
+const MAX_IDLE_CONNECTIONS: usize = 8;
+
+/// Time after which an idle origin connection is no longer reused, as the origin
+/// is likely to close it about then.
+const MAX_IDLE_TIME: Duration = Duration::from_secs(30);
+
+/// Address of the origin server, which moves when it is re-discovered, and the
+/// hostname it is known by, if any.
+#[derive(Clone)]
+pub struct OriginAddr {
+    addr: Arc<RwLock<SocketAddr>>,
+    name: Option<Arc<str>>,
+}
+
+impl OriginAddr {
+    pub fn new(addr: SocketAddr, name: Option<&str>) -> Self {
+        OriginAddr {
+            addr: Arc::new(RwLock::new(addr)),
+            name: name.map(Arc::from),
+        }
+    }
+
+    pub fn get(&self) -> SocketAddr {
+        *self.addr.read().unwrap()
+    }
+
+    pub fn set(&self, addr: SocketAddr) {
+        *self.addr.write().unwrap() = addr;
+    }
+
+    /// Host header of requests to the origin. Its hostname is kept for servers
+    /// behind a virtual-hosted reverse proxy.
+    pub fn host(&self) -> String {
+        let addr = self.get();
+
+        let host = match (&self.name, addr) {
+            (Some(name), _) => name.to_string(),
+            (None, SocketAddr::V4(v4)) => v4.ip().to_string(),
+            (None, SocketAddr::V6(v6)) => format!("[{}]", v6.ip()),
+        };
+
+        match addr.port() {
+            80 => host,
+            port => format!("{}:{}", host, port),
+        }
+    }
+}
+
+/// How the proxy answers clients when the origin can't be reached.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Unreachable {
+    /// Status when connecting to the origin fails, None to just close the connection.
+    pub refused: Option<u16>,
+    /// Status when connecting to the origin times out, None to just close the connection.
+    pub timeout: Option<u16>,
+    /// Retry-After of 503 responses, in seconds.
+    pub retry_after: Option<u64>,
+}
+
+impl Default for Unreachable {
+    fn default() -> Self {
+        Unreachable {
+            refused: Some(502),
+            timeout: Some(503),
+            retry_after: Some(30),
+        }
+    }
+}
+
 pub struct TCPProxy {
     connect_timeout: Duration,
     stream_timeout: Duration,
-    origin_url_base: String,
+    origin: OriginAddr,
     proxy_url_base: String,
+    event_port: Option<u16>,
+    rules: Arc<Vec<RewriteRule>>,
+    unreachable: Unreachable,
+    identity_encoding: bool,
 }
 
 impl TCPProxy {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         connect_timeout: Duration,
         stream_timeout: Duration,
-        origin_addr: SocketAddr,
+        origin: OriginAddr,
         proxy_addr: SocketAddr,
+        event_port: Option<u16>,
+        rules: Vec<RewriteRule>,
+        unreachable: Unreachable,
+        identity_encoding: bool,
     ) -> Self {
-        // Create URL bases for rewriting (e.g., "http://192.168.1.41:55555" -> "http://192.168.1.52:8100")
-        let origin_url_base = format!("http://{}:{}", origin_addr.ip(), origin_addr.port());
-        let proxy_url_base = format!("http://{}:{}", proxy_addr.ip(), proxy_addr.port());
+        // URL bases for rewriting (e.g., "http://192.168.1.41:55555" -> "http://192.168.1.52:8100")
+        let proxy_url_base = url_base(proxy_addr);
 
         TCPProxy {
             connect_timeout,
             stream_timeout,
-            origin_url_base,
+            origin,
             proxy_url_base,
+            event_port,
+            rules: Arc::new(rules),
+            unreachable,
+            identity_encoding,
         }
     }
 
-    pub async fn start(self, to: SocketAddr, from: SocketAddr) -> io::Result<JoinHandle<()>> {
+    pub async fn start(self, from: SocketAddr) -> io::Result<JoinHandle<()>> {
         let listener = TcpListener::bind(from).await.map_err(|e| {
             error!(target: "dlnaproxy", "Failed to bind TCP proxy to {}: {}", from, e);
             e
         })?;
 
-        info!(target: "dlnaproxy", "Proxying TCP connections from {} to {} (with URL rewriting)", from, to);
+        info!(target: "dlnaproxy", "Proxying TCP connections from {} to {} (with URL rewriting)", from, self.origin.get());
 
         let connect_timeout = self.connect_timeout;
         let stream_timeout = self.stream_timeout;
-        let origin_url_base = self.origin_url_base;
+        let origin = self.origin;
         let proxy_url_base = self.proxy_url_base;
+        let rules = self.rules;
+        let unreachable = self.unreachable;
+        let identity_encoding = self.identity_encoding;
+
+        // Without it subscriptions still go through, but the origin may not reach the subscribers.
+        let events = match EventRelay::start(self.event_port, origin.clone()).await {
+            Ok(events) => Some(events),
+            Err(e) => {
+                warn!(target: "dlnaproxy", "Not relaying GENA events: {:#}", e);
+                None
+            }
+        };
 
         Ok(tokio::spawn(async move {
             listen_loop(
                 listener,
-                to,
+                origin,
                 connect_timeout,
                 stream_timeout,
-                origin_url_base,
                 proxy_url_base,
+                rules,
+                events,
+                unreachable,
+                identity_encoding,
             )
             .await
         }))
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn listen_loop(
     listener: TcpListener,
-    origin: SocketAddr,
+    origin: OriginAddr,
     connect_timeout: Duration,
-    _stream_timeout: Duration,
-    origin_url_base: String,
+    stream_timeout: Duration,
     proxy_url_base: String,
+    rules: Arc<Vec<RewriteRule>>,
+    events: Option<Arc<EventRelay>>,
+    unreachable: Unreachable,
+    identity_encoding: bool,
 ) {
     let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_CONNECTIONS));
+    let pool = Arc::new(OriginPool::new(origin.clone(), connect_timeout));
+
+    // When listening on every address, URLs are rewritten to the one each client reached us on.
+    let listens_on_any = listener
+        .local_addr()
+        .map(|addr| addr.ip().is_unspecified())
+        .unwrap_or(false);
 
     loop {
         let (proxied_stream, peer_addr) = match listener.accept().await {
@@ -101,85 +231,619 @@ async fn listen_loop(
             }
         };
 
-        // Connect to origin with timeout
-        let to_stream = match timeout(connect_timeout, TcpStream::connect(origin)).await {
-            Ok(Ok(stream)) => stream,
-            Ok(Err(e)) => {
-                warn!(target: "dlnaproxy", "Failed to connect to origin {}: {}", origin, e);
-                // permit is dropped here, releasing the slot
-                continue;
-            }
-            Err(_) => {
-                warn!(target: "dlnaproxy", "Timeout connecting to origin {}", origin);
-                // permit is dropped here, releasing the slot
-                continue;
-            }
+        let origin_base = url_base(origin.get());
+        let proxy_base = match proxied_stream.local_addr() {
+            Ok(local_addr) if listens_on_any => url_base(local_addr),
+            _ => proxy_url_base.clone(),
         };
 
-        let origin_base = origin_url_base.clone();
-        let proxy_base = proxy_url_base.clone();
+        let mut rewriter = Rewriter::new(origin_base, proxy_base, origin.host(), rules.clone());
+        rewriter.identity_encoding = identity_encoding;
+        let events = events.clone();
+        let pool = pool.clone();
 
         // Spawn handler task - permit is moved in and released when task completes
         tokio::spawn(async move {
-            handle_conn(proxied_stream, to_stream, peer_addr, origin_base, proxy_base).await;
+            handle_conn(proxied_stream, peer_addr, pool, rewriter, events, unreachable, stream_timeout).await;
             drop(permit); // Explicitly release permit when connection closes
         });
 
-        debug!(target: "dlnaproxy", "Successfully established a connection with client: {}", peer_addr);
+        debug!(target: "dlnaproxy", "Accepted a connection from client: {}", peer_addr);
     }
 }
 
+/// Keep-alive connections to the origin left idle by earlier requests, which spare
+/// later ones a handshake over a slow link.
+struct OriginPool {
+    origin: OriginAddr,
+    connect_timeout: Duration,
+    idle: Mutex<Vec<IdleConnection>>,
+}
+
+struct IdleConnection {
+    stream: TcpStream,
+    addr: SocketAddr,
+    since: Instant,
+}
+
+impl OriginPool {
+    fn new(origin: OriginAddr, connect_timeout: Duration) -> Self {
+        OriginPool {
+            origin,
+            connect_timeout,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// An idle connection to the origin that is still open, or else a new one, and
+    /// whether it was reused.
+    async fn get(&self) -> io::Result<(TcpStream, bool)> {
+        let addr = self.origin.get();
+
+        loop {
+            // The most recently used connection is the least likely to have been closed.
+            let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+
+            match idle {
+                Some(idle) if idle.addr == addr && idle.since.elapsed() < MAX_IDLE_TIME && is_open(&idle.stream) => {
+                    return Ok((idle.stream, true));
+                }
+                Some(_) => continue,
+                None => return Ok((self.connect().await?, false)),
+            }
+        }
+    }
+
+    /// A new connection to the origin.
+    async fn connect(&self) -> io::Result<TcpStream> {
+        let addr = self.origin.get();
+
+        match timeout(self.connect_timeout, TcpStream::connect(addr)).await {
+            Ok(stream) => stream,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("Timeout connecting to {}", addr))),
+        }
+    }
+
+    /// Keep a connection whose last response was read in full for a later request.
+    fn put(&self, stream: TcpStream) {
+        let Ok(addr) = stream.peer_addr() else {
+            return;
+        };
+
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        idle.retain(|idle| idle.since.elapsed() < MAX_IDLE_TIME);
+
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(IdleConnection {
+                stream,
+                addr,
+                since: Instant::now(),
+            });
+        }
+    }
+}
+
+/// Whether an idle connection is still open: the origin sent nothing since, not even its close.
+fn is_open(stream: &TcpStream) -> bool {
+    let mut byte = [0u8; 1];
+    matches!(stream.try_read(&mut byte), Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+}
+
+/// A reader failing with `TimedOut` once `timeout` goes by without anything to read.
+struct IdleTimeout<R> {
+    inner: R,
+    timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl<R> IdleTimeout<R> {
+    fn new(inner: R, timeout: Duration) -> Self {
+        IdleTimeout {
+            inner,
+            timeout,
+            deadline: Box::pin(tokio::time::sleep(timeout)),
+        }
+    }
+}
+
+/// Push `deadline` back after a read, or fail if it passed while waiting for one.
+fn poll_idle<T>(
+    deadline: &mut Pin<Box<Sleep>>,
+    timeout: Duration,
+    cx: &mut Context<'_>,
+    read: Poll<io::Result<T>>,
+) -> Poll<io::Result<T>> {
+    match read {
+        Poll::Ready(result) => {
+            deadline.as_mut().reset(tokio::time::Instant::now() + timeout);
+            Poll::Ready(result)
+        }
+        Poll::Pending => match deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Nothing to read in {:?}", timeout),
+            ))),
+            Poll::Pending => Poll::Pending,
+        },
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for IdleTimeout<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let IdleTimeout { inner, timeout, deadline } = &mut *self;
+        let read = Pin::new(inner).poll_read(cx, buf);
+        poll_idle(deadline, *timeout, cx, read)
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for IdleTimeout<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let IdleTimeout { inner, timeout, deadline } = self.get_mut();
+        let read = Pin::new(inner).poll_fill_buf(cx);
+        poll_idle(deadline, *timeout, cx, read)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.inner).consume(amt)
+    }
+}
+
+/// Answer a client the origin couldn't be reached for with an error `status`, or just
+/// close its connection without one.
+async fn answer_unreachable(
+    client: &mut BufReader<TcpStream>,
+    peer_addr: SocketAddr,
+    status: Option<u16>,
+    retry_after: Option<u64>,
+    head_only: bool,
+) {
+    let Some(status) = status else {
+        return;
+    };
+
+    let response = error_response(status, retry_after, head_only);
+
+    let answered = async {
+        client.get_mut().write_all(response.as_bytes()).await?;
+        client.get_mut().shutdown().await?;
+
+        // Whatever body the request had, until the client closes too: closing with it
+        // unread would reset the connection, and the response with it.
+        let mut sink = [0u8; 8192];
+        while let Ok(Ok(1..)) = timeout(Duration::from_secs(1), client.read(&mut sink)).await {}
+        Ok::<_, io::Error>(())
+    };
+
+    if let Err(e) = answered.await {
+        trace!(target: "dlnaproxy", "Couldn't answer {} with {}: {}", peer_addr, status, e);
+    }
+}
+
+/// A complete error response with a small HTML body, closing the connection.
+fn error_response(status: u16, retry_after: Option<u64>, head_only: bool) -> String {
+    let reason = match status {
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Error",
+    };
+
+    let body = format!(
+        "<html><head><title>{status} {reason}</title></head>\
+         <body><h1>{status} {reason}</h1><p>The media server can't be reached.</p></body></html>\r\n"
+    );
+
+    let mut response = format!("HTTP/1.1 {} {}\r\n", status, reason);
+    response.push_str("Content-Type: text/html; charset=\"utf-8\"\r\n");
+    response.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    response.push_str("Connection: close\r\n");
+
+    if let (503, Some(seconds)) = (status, retry_after) {
+        response.push_str(&format!("Retry-After: {}\r\n", seconds));
+    }
+
+    response.push_str("\r\n");
+
+    if !head_only {
+        response.push_str(&body);
+    }
+
+    response
+}
+
+/// Base of the URLs served at `addr`, e.g. "http://192.168.1.52:8100".
+pub(crate) fn url_base(addr: SocketAddr) -> String {
+    format!("http://{}", addr)
+}
+
 async fn handle_conn(
     client_stream: TcpStream,
-    origin_stream: TcpStream,
     peer_addr: SocketAddr,
-    origin_url_base: String,
-    proxy_url_base: String,
+    pool: Arc<OriginPool>,
+    rewriter: Rewriter,
+    events: Option<Arc<EventRelay>>,
+    unreachable: Unreachable,
+    stream_timeout: Duration,
 ) {
-    // Split streams for bidirectional communication
-    let (client_read, client_write) = client_stream.into_split();
-    let (origin_read, origin_write) = origin_stream.into_split();
-
-    // Client -> Origin: forward requests without modification
-    let peer_addr_copy = peer_addr;
-    let client_to_origin = tokio::spawn(async move {
-        let mut client_read = client_read;
-        let mut origin_write = origin_write;
-        match tokio::io::copy(&mut client_read, &mut origin_write).await {
-            Ok(bytes) => {
-                trace!(target: "dlnaproxy", "Copied {} bytes client->origin for {}", bytes, peer_addr_copy)
-            }
+    let mut client = BufReader::new(client_stream);
+
+    // One request at a time, each over whichever origin connection is free
+    loop {
+        let served = serve_request(
+            &mut client,
+            peer_addr,
+            &pool,
+            &rewriter,
+            events.as_deref(),
+            unreachable,
+            stream_timeout,
+        );
+        match served.await {
+            Ok(true) => {}
+            Ok(false) => break,
             Err(e) => {
-                trace!(target: "dlnaproxy", "Copy client->origin ended for {}: {}", peer_addr_copy, e)
+                trace!(target: "dlnaproxy", "Connection with {} ended: {}", peer_addr, e);
+                break;
             }
         }
-    });
+    }
 
-    // Origin -> Client: rewrite URLs in responses
-    let peer_addr_copy = peer_addr;
-    let origin_to_client = tokio::spawn(async move {
-        if let Err(e) = proxy_response_with_rewrite(
-            origin_read,
-            client_write,
-            &origin_url_base,
-            &proxy_url_base,
-            peer_addr_copy,
-        )
-        .await
-        {
-            trace!(target: "dlnaproxy", "Response proxy ended for {}: {}", peer_addr_copy, e);
+    trace!(target: "dlnaproxy", "Closed connection with: {}", peer_addr);
+}
+
+/// A request forwarded to the origin, as its response needs to know.
+struct PendingRequest {
+    method: String,
+    path: String,
+    event: EventRequest,
+    /// Whether the client speaks HTTP/1.1, and so takes chunked responses.
+    http_1_1: bool,
+}
+
+/// A client request moved to the origin's terms, ready to be sent.
+struct Request {
+    head: String,
+    body: RequestBody,
+}
+
+enum RequestBody {
+    /// Read, and rewritten, already: the request can be sent again.
+    Buffered(Vec<u8>),
+    /// Copied from the client as the request is sent.
+    Chunked,
+    Length(u64),
+}
+
+/// How the origin connection a response came on was left.
+#[derive(Debug)]
+enum ResponseEnd {
+    /// Read in full, the connection can take another request.
+    KeepAlive,
+    /// The connection is done, and the client's too as the response said so.
+    Close,
+    /// Switched protocols: bytes go both ways untouched from now on.
+    Upgrade,
+    /// The origin closed the connection without answering.
+    NoResponse,
+}
+
+/// Serve a client request from the origin, connecting to it only now, rewriting the
+/// request and its response. Whether the client connection can take another request.
+/// Neither the client nor the origin may leave the proxy waiting past `stream_timeout`.
+async fn serve_request(
+    client: &mut BufReader<TcpStream>,
+    peer_addr: SocketAddr,
+    pool: &OriginPool,
+    rewriter: &Rewriter,
+    events: Option<&EventRelay>,
+    unreachable: Unreachable,
+    stream_timeout: Duration,
+) -> io::Result<bool> {
+    // An idle keep-alive client would otherwise hold its connection slot forever.
+    let head = match timeout(stream_timeout, read_head(client)).await {
+        Ok(head) => head?,
+        Err(_) => {
+            trace!(target: "dlnaproxy", "No request from {} in {:?}, closing", peer_addr, stream_timeout);
+            return Ok(false);
         }
-    });
+    };
+    if head.is_empty() {
+        return Ok(false);
+    }
 
-    // Wait for both directions to complete
-    if let Err(e) = client_to_origin.await {
-        warn!(target: "dlnaproxy", "Client->origin task panicked for {}: {:?}", peer_addr, e);
+    // Not HTTP: stop looking at it, and pass on what was read as it came
+    if !is_http_request(&head) {
+        let mut origin = pool.connect().await?;
+        origin.write_all(&head).await?;
+        tokio::io::copy_bidirectional(client, &mut origin).await?;
+        return Ok(false);
     }
-    if let Err(e) = origin_to_client.await {
-        warn!(target: "dlnaproxy", "Origin->client task panicked for {}: {:?}", peer_addr, e);
+
+    let head = String::from_utf8_lossy(&head).into_owned();
+
+    let head = rewrite_request_head(&head, rewriter);
+    let method = head.split(' ').next().unwrap_or_default().to_string();
+    let path = head.split(' ').nth(1).unwrap_or_default().to_string();
+    let http_1_1 = head.lines().next().is_some_and(|line| line.trim_end().ends_with("HTTP/1.1"));
+
+    // Only the client's connection ends with this request, the origin's goes back to the pool.
+    let client_close = closes_connection(&head);
+    let head = match client_close {
+        true => keep_alive(&head),
+        false => head,
+    };
+
+    // Answered here, since the origin gets the body along with the head
+    let head = match header_value(&head, "Expect") {
+        Some(expect) if expect.eq_ignore_ascii_case("100-continue") => {
+            client.get_mut().write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+            remove_header(&head, "Expect")
+        }
+        _ => head,
+    };
+
+    let head_only = method.eq_ignore_ascii_case("HEAD");
+
+    let (origin, mut reused) = match pool.get().await {
+        Ok(origin) => origin,
+        Err(e) => {
+            warn!(target: "dlnaproxy", "Failed to connect to origin {}: {}", pool.origin.get(), e);
+            let status = match e.kind() {
+                io::ErrorKind::TimedOut => unreachable.timeout,
+                _ => unreachable.refused,
+            };
+            answer_unreachable(client, peer_addr, status, unreachable.retry_after, head_only).await;
+            return Ok(false);
+        }
+    };
+
+    // The origin sends events to the address we reach it from.
+    let (head, event) = match events {
+        Some(events) => events.intercept(head, origin.local_addr()?, rewriter),
+        None => (head, EventRequest::None),
+    };
+
+    let request = read_request(client, head, rewriter).await?;
+    let replayable = matches!(request.body, RequestBody::Buffered(_));
+    let mut pending = PendingRequest { method, path, event, http_1_1 };
+
+    let mut origin = BufReader::new(origin);
+    let end = loop {
+        let end = match send_request(origin.get_mut(), &request, client).await {
+            Ok(()) => {
+                let mut response = IdleTimeout::new(&mut origin, stream_timeout);
+                forward_response(&mut response, client.get_mut(), rewriter, peer_addr, &mut pending, events).await?
+            }
+            Err(e) if reused && replayable => {
+                trace!(target: "dlnaproxy", "Failed to send request for {} on an idle connection: {}", peer_addr, e);
+                ResponseEnd::NoResponse
+            }
+            Err(e) => return Err(e),
+        };
+
+        match end {
+            // The origin closed the idle connection as it was reused: try a new one.
+            ResponseEnd::NoResponse if reused && replayable => {
+                debug!(target: "dlnaproxy", "Idle origin connection closed, retrying request for {}", peer_addr);
+                origin = match pool.connect().await {
+                    Ok(stream) => BufReader::new(stream),
+                    Err(e) => {
+                        warn!(target: "dlnaproxy", "Failed to connect to origin {}: {}", pool.origin.get(), e);
+                        answer_unreachable(client, peer_addr, unreachable.refused, unreachable.retry_after, head_only).await;
+                        return Ok(false);
+                    }
+                };
+                reused = false;
+            }
+            end => break end,
+        }
+    };
+
+    match end {
+        ResponseEnd::KeepAlive => {
+            // Anything past the response would be mistaken for the next one's.
+            if origin.buffer().is_empty() {
+                pool.put(origin.into_inner());
+            }
+            Ok(!client_close)
+        }
+        ResponseEnd::Close => Ok(false),
+        ResponseEnd::Upgrade => {
+            tokio::io::copy_bidirectional(client, &mut origin).await?;
+            Ok(false)
+        }
+        ResponseEnd::NoResponse => {
+            warn!(target: "dlnaproxy", "Origin {} closed the connection without answering", pool.origin.get());
+            answer_unreachable(client, peer_addr, unreachable.refused, unreachable.retry_after, head_only).await;
+            Ok(false)
+        }
     }
+}
 
-    trace!(target: "dlnaproxy", "Closed connection with: {}", peer_addr);
+/// Whether a head read from a client starts with an HTTP/1.x request line.
+fn is_http_request(head: &[u8]) -> bool {
+    let first_line = head.split(|&b| b == b'\n').next().unwrap_or_default();
+    first_line.windows(b" HTTP/1.".len()).any(|w| w == b" HTTP/1.")
+}
+
+/// A request head asking the origin to keep the connection open.
+fn keep_alive(head: &str) -> String {
+    let head = remove_header(head, "Connection");
+    format!("{}Connection: keep-alive\r\n\r\n", head.strip_suffix("\r\n").unwrap_or(&head))
+}
+
+/// Read a request's body if it is text to rewrite, moving it from the proxy's URLs to the origin's.
+async fn read_request(client: &mut BufReader<TcpStream>, head: String, rewriter: &Rewriter) -> io::Result<Request> {
+    let is_chunked = header_value(&head, "Transfer-Encoding").is_some_and(|te| te.to_lowercase().contains("chunked"));
+    let content_length = header_value(&head, "Content-Length").and_then(|len| len.parse::<usize>().ok());
+
+    // SOAP requests may carry proxy URLs too, e.g. SetAVTransportURI to a renderer.
+    let rewritable = should_rewrite_content(&head)
+        && header_value(&head, "Content-Encoding").is_none()
+        && (is_chunked || content_length.is_some_and(|len| len <= MAX_REWRITABLE_BODY_SIZE));
+
+    if rewritable {
+        let body = match content_length {
+            Some(len) if !is_chunked => {
+                let mut body = vec![0u8; len];
+                client.read_exact(&mut body).await?;
+                body
+            }
+            _ => read_chunked_body(client, MAX_REWRITABLE_BODY_SIZE).await?,
+        };
+
+        let rewritten = rewriter.rewrite_request(&body);
+
+        // Sent with a length, which every server understands, unlike chunked requests.
+        let head = set_content_length(&head, rewritten.len());
+
+        return Ok(Request {
+            head,
+            body: RequestBody::Buffered(rewritten),
+        });
+    }
+
+    let body = match (is_chunked, content_length) {
+        (true, _) => RequestBody::Chunked,
+        (false, Some(length)) if length > 0 => RequestBody::Length(length as u64),
+        (false, _) => RequestBody::Buffered(Vec::new()),
+    };
+
+    Ok(Request { head, body })
+}
+
+/// Send a request to the origin, copying its body from the client unless it was read already.
+async fn send_request(
+    origin: &mut TcpStream,
+    request: &Request,
+    client: &mut BufReader<TcpStream>,
+) -> io::Result<()> {
+    origin.write_all(request.head.as_bytes()).await?;
+
+    match &request.body {
+        RequestBody::Buffered(body) => origin.write_all(body).await?,
+        RequestBody::Chunked => pass_through_chunked(client, origin).await?,
+        RequestBody::Length(length) => {
+            tokio::io::copy(&mut client.take(*length), origin).await?;
+        }
+    }
+
+    origin.flush().await
+}
+
+/// Move a request head from the proxy to the origin: absolute targets become paths,
+/// Host names the origin, only identity encoding is accepted if so configured and
+/// proxy URLs in other headers point at the origin.
+fn rewrite_request_head(head: &str, rewriter: &Rewriter) -> String {
+    let mut result = String::new();
+
+    for (i, line) in head.lines().enumerate() {
+        if i == 0 {
+            // Absolute-form is only meant for proxies, and this one only forwards to the origin.
+            let mut parts = line.splitn(3, ' ');
+            let (method, target, version) = (parts.next(), parts.next(), parts.next());
+
+            match (method, target.and_then(origin_form), version) {
+                (Some(method), Some(path), Some(version)) => {
+                    result.push_str(&format!("{} {} {}\r\n", method, path, version))
+                }
+                _ => {
+                    result.push_str(line);
+                    result.push_str("\r\n");
+                }
+            }
+            continue;
+        }
+
+        match line.split_once(':') {
+            Some((key, _)) if key.trim().eq_ignore_ascii_case("Host") => {
+                result.push_str(&format!("{}: {}\r\n", key, rewriter.origin_host));
+            }
+            // Uncompressed responses can be rewritten as they stream through, whatever their size.
+            Some((key, _)) if rewriter.identity_encoding && key.trim().eq_ignore_ascii_case("Accept-Encoding") => {
+                result.push_str(&format!("{}: identity\r\n", key));
+            }
+            _ => {
+                result.push_str(&line.replace(&rewriter.proxy_url_base, &rewriter.origin_url_base));
+                result.push_str("\r\n");
+            }
+        }
+    }
+
+    result
+}
+
+/// Path, query included, of an absolute-form request target, e.g. "http://host:8200/a?b".
+fn origin_form(target: &str) -> Option<String> {
+    let (scheme, rest) = target.split_once("://")?;
+
+    if !scheme.eq_ignore_ascii_case("http") {
+        return None;
+    }
+
+    match rest.find(['/', '?']) {
+        Some(start) if rest[start..].starts_with('?') => Some(format!("/{}", &rest[start..])),
+        Some(start) => Some(rest[start..].to_string()),
+        None => Some("/".to_string()),
+    }
+}
+
+/// Read the head of an HTTP message, up to and including the empty line.
+/// Empty when the connection closed before it, an error past `MAX_HEAD_SIZE`.
+pub(crate) async fn read_head<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+
+    loop {
+        let mut line = Vec::new();
+        let limit = (MAX_HEAD_SIZE - head.len()) as u64;
+        (&mut *reader).take(limit).read_until(b'\n', &mut line).await?;
+        if line.len() as u64 == limit && !line.ends_with(b"\n") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("HTTP head exceeds maximum size ({} bytes)", MAX_HEAD_SIZE),
+            ));
+        }
+        if line.is_empty() {
+            if head.is_empty() {
+                return Ok(head);
+            }
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed within headers"));
+        }
+
+        head.extend_from_slice(&line);
+
+        if line == b"\r\n" || line == b"\n" {
+            return Ok(head);
+        }
+    }
+}
+
+/// Value of the first header called `name` (case-insensitive) in an HTTP head.
+pub(crate) fn header_value<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// Replace the value of the header called `name` in an HTTP head.
+pub(crate) fn set_header(head: &str, name: &str, value: &str) -> String {
+    let mut result = String::new();
+
+    for (i, line) in head.lines().enumerate() {
+        match line.split_once(':') {
+            Some((key, _)) if i > 0 && key.trim().eq_ignore_ascii_case(name) => {
+                result.push_str(&format!("{}: {}\r\n", key, value));
+            }
+            _ => {
+                result.push_str(line);
+                result.push_str("\r\n");
+            }
+        }
+    }
+
+    result
 }
 
 /// Read a line (until \n) as raw bytes, without requiring valid UTF-8.
@@ -228,45 +892,43 @@ fn should_rewrite_content(headers: &str) -> bool {
     false
 }
 
-/// Proxy HTTP responses from origin to client, rewriting URLs in the body
-async fn proxy_response_with_rewrite(
-    origin_read: tokio::net::tcp::OwnedReadHalf,
-    mut client_write: tokio::net::tcp::OwnedWriteHalf,
-    origin_url_base: &str,
-    proxy_url_base: &str,
+/// Proxy the HTTP response to a request from origin to client, interim ones included,
+/// rewriting URLs in the body
+async fn forward_response<R, W>(
+    reader: &mut R,
+    client_write: &mut W,
+    rewriter: &Rewriter,
     peer_addr: SocketAddr,
-) -> io::Result<()> {
-    let mut reader = BufReader::new(origin_read);
+    pending: &mut PendingRequest,
+    events: Option<&EventRelay>,
+) -> io::Result<ResponseEnd>
+where
+    R: AsyncBufReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let mut interim = false;
 
     loop {
         // Read the HTTP response status line and headers
         let mut header_buf = Vec::new();
-        let mut content_length: Option<usize> = None;
-        let mut is_chunked = false;
 
         // Read headers line by line (as raw bytes to handle non-UTF8 gracefully)
         loop {
-            let line = read_line_bytes(&mut reader).await?;
+            let line = match read_line_bytes(reader).await {
+                Ok(line) => line,
+                // Nothing was forwarded yet, so the request can be sent again
+                Err(e) if header_buf.is_empty() && !interim => {
+                    trace!(target: "dlnaproxy", "No response for {}: {}", peer_addr, e);
+                    return Ok(ResponseEnd::NoResponse);
+                }
+                Err(e) => return Err(e),
+            };
             if line.is_empty() {
                 // Connection closed
-                return Ok(());
-            }
-
-            // Convert to string for header matching (lossy conversion is fine for headers)
-            let line_str = String::from_utf8_lossy(&line);
-
-            // Check for Content-Length header
-            if line_str.to_lowercase().starts_with("content-length:") {
-                if let Some(len_str) = line_str.split(':').nth(1) {
-                    content_length = len_str.trim().parse().ok();
-                }
-            }
-
-            // Check for Transfer-Encoding: chunked
-            if line_str.to_lowercase().starts_with("transfer-encoding:")
-                && line_str.to_lowercase().contains("chunked")
-            {
-                is_chunked = true;
+                return Ok(match header_buf.is_empty() && !interim {
+                    true => ResponseEnd::NoResponse,
+                    false => ResponseEnd::Close,
+                });
             }
 
             header_buf.extend_from_slice(&line);
@@ -277,11 +939,6 @@ async fn proxy_response_with_rewrite(
             }
         }
 
-        // If we got no headers at all, connection is closed
-        if header_buf.is_empty() {
-            return Ok(());
-        }
-
         let headers_str = String::from_utf8_lossy(&header_buf);
         // Only log the first line (status line), and sanitize it for display
         // Use is_ascii_graphic() to only allow printable ASCII (0x21-0x7E) plus space
@@ -296,6 +953,58 @@ async fn proxy_response_with_rewrite(
             .collect::<String>();
         trace!(target: "dlnaproxy", "Response headers for {}: {}", peer_addr, status_line);
 
+        let status: u16 = status_line
+            .split(' ')
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .unwrap_or(0);
+
+        // Interim responses come before the one answering the request.
+        if !(100..200).contains(&status) {
+            if let Some(events) = events {
+                let event = std::mem::replace(&mut pending.event, EventRequest::None);
+                events.on_response(event, status, &headers_str);
+            }
+        }
+
+        let (method, path, http_1_1) = (pending.method.as_str(), Some(pending.path.as_str()), pending.http_1_1);
+
+        // The protocol changed, nothing left to frame
+        if status == 101 {
+            client_write.write_all(&header_buf).await?;
+            client_write.flush().await?;
+            return Ok(ResponseEnd::Upgrade);
+        }
+
+        let close = closes_connection(&headers_str);
+
+        if !has_body(status, method) {
+            client_write.write_all(&header_buf).await?;
+            client_write.flush().await?;
+            trace!(target: "dlnaproxy", "Proxied response without body for {} ({} {})", peer_addr, method, status);
+
+            // The final response follows interim ones
+            if (100..200).contains(&status) {
+                interim = true;
+                continue;
+            }
+
+            return Ok(match close {
+                true => ResponseEnd::Close,
+                false => ResponseEnd::KeepAlive,
+            });
+        }
+
+        // A transfer coding overrides any Content-Length, and chunked must be its last
+        let transfer_encoding = header_value(&headers_str, "Transfer-Encoding");
+        let is_chunked = transfer_encoding
+            .and_then(|codings| codings.rsplit(',').next())
+            .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+        let content_length = match transfer_encoding {
+            Some(_) => None,
+            None => header_value(&headers_str, "Content-Length").and_then(|len| len.parse::<usize>().ok()),
+        };
+
         // Check if this is text/XML content that needs URL rewriting
         let needs_rewrite = should_rewrite_content(&headers_str);
 
@@ -306,85 +1015,291 @@ async fn proxy_response_with_rewrite(
             client_write.flush().await?;
 
             // Stream remaining data until origin closes connection
-            let bytes_copied = tokio::io::copy(&mut reader, &mut client_write).await?;
+            let bytes_copied = tokio::io::copy(reader, client_write).await?;
             trace!(target: "dlnaproxy", "Streamed {} bytes for {} (no Content-Length)", bytes_copied, peer_addr);
-            return Ok(()); // Connection is done after streaming
+            return Ok(ResponseEnd::Close); // Connection is done after streaming
         }
 
-        // Check if body is too large for URL rewriting (to prevent OOM)
-        let body_too_large = content_length.is_some_and(|len| len > MAX_REWRITABLE_BODY_SIZE);
-        if body_too_large {
-            warn!(target: "dlnaproxy", "Body too large for URL rewriting ({} bytes), passing through for {}",
-                  content_length.unwrap_or(0), peer_addr);
-        }
+        'body: {
+            // Binary content passes through without modification
+            if !needs_rewrite {
+                client_write.write_all(&header_buf).await?;
+
+                if is_chunked {
+                    // Pass through chunked data as-is
+                    pass_through_chunked(reader, client_write).await?;
+                } else if let Some(len) = content_length {
+                    // Pass through fixed-length binary data
+                    let mut remaining = len;
+                    let mut buf = [0u8; 8192];
+                    while remaining > 0 {
+                        let to_read = std::cmp::min(remaining, buf.len());
+                        let bytes_read = reader.read(&mut buf[..to_read]).await?;
+                        if bytes_read == 0 {
+                            // Cut short: the client mustn't take it for the whole body
+                            return Err(io::ErrorKind::UnexpectedEof.into());
+                        }
+                        client_write.write_all(&buf[..bytes_read]).await?;
+                        remaining -= bytes_read;
+                    }
+                }
 
-        // For binary content or bodies too large for rewriting, pass through without modification
-        if !needs_rewrite || body_too_large {
-            client_write.write_all(&header_buf).await?;
+                client_write.flush().await?;
+                trace!(target: "dlnaproxy", "Proxied binary response for {} ({} bytes)",
+                       peer_addr, content_length.unwrap_or(0));
+                break 'body;
+            }
 
-            if is_chunked {
-                // Pass through chunked data as-is
-                pass_through_chunked(&mut reader, &mut client_write).await?;
-            } else if let Some(len) = content_length {
-                // Pass through fixed-length binary data
-                let mut remaining = len;
-                let mut buf = [0u8; 8192];
-                while remaining > 0 {
-                    let to_read = std::cmp::min(remaining, buf.len());
-                    let bytes_read = reader.read(&mut buf[..to_read]).await?;
+            let content_type = header_value(&headers_str, "Content-Type").unwrap_or_default();
+            let content_coding = header_value(&headers_str, "Content-Encoding")
+                .filter(|coding| !coding.eq_ignore_ascii_case("identity"));
+            let mut body = BodyReader::new(content_length, is_chunked);
+            let mut buf = vec![0u8; 8192];
+
+            let mut stream = rewriter.stream(content_type, path);
+            let chunked_out = is_chunked || !stream.preserves_length();
+
+            // Bodies are rewritten as they stream through, unless that can't be done right:
+            // DIDL-Lite metadata in SOAP responses and regex rules need the whole body, as
+            // does decoding, and an HTTP/1.0 client needs the new length up front. Small
+            // bodies keep their Content-Length rather than being sent chunked.
+            let whole = method.eq_ignore_ascii_case("POST")
+                || rewriter.needs_whole_body(content_type, path)
+                || content_coding.is_some()
+                || (chunked_out
+                    && !is_chunked
+                    && (!http_1_1 || content_length.is_some_and(|len| len <= MAX_BUFFERED_BODY_SIZE)));
+
+            let mut start = Vec::new();
+            let complete = match content_length {
+                _ if !whole => false,
+                Some(len) if len > MAX_REWRITABLE_BODY_SIZE => false,
+                _ => loop {
+                    if start.len() > MAX_REWRITABLE_BODY_SIZE {
+                        break false;
+                    }
+                    let bytes_read = body.read(reader, &mut buf).await?;
+                    if bytes_read == 0 {
+                        break true;
+                    }
+                    start.extend_from_slice(&buf[..bytes_read]);
+                },
+            };
+
+            if complete {
+                let body_len = start.len();
+                let (head, rewritten, chunked_out) = match content_coding {
+                    None => {
+                        let rewritten = rewriter.rewrite(&start, content_type, path);
+
+                        // Update Content-Length if body was rewritten and size changed
+                        let head = match !is_chunked && rewritten.len() != start.len() {
+                            true => update_content_length(&headers_str, rewritten.len()).into_bytes(),
+                            false => std::mem::take(&mut header_buf),
+                        };
+                        (head, rewritten, is_chunked)
+                    }
+                    Some(coding) => match encoding::decode(coding, &start, MAX_REWRITABLE_BODY_SIZE) {
+                        // Sent decoded, with a length, rather than compressed again
+                        Ok(decoded) => {
+                            let rewritten = rewriter.rewrite(&decoded, content_type, path);
+                            let head = remove_header(&set_content_length(&headers_str, rewritten.len()), "Content-Encoding");
+                            (head.into_bytes(), rewritten, false)
+                        }
+                        Err(e) => {
+                            warn!(target: "dlnaproxy", "Can't decode {} response for URL rewriting, passing through for {}: {}",
+                                  coding, peer_addr, e);
+                            (std::mem::take(&mut header_buf), std::mem::take(&mut start), is_chunked)
+                        }
+                    },
+                };
+
+                client_write.write_all(&head).await?;
+
+                if chunked_out {
+                    write_chunked_body(client_write, &rewritten, body.trailers()).await?;
+                } else {
+                    client_write.write_all(&rewritten).await?;
+                }
+
+                client_write.flush().await?;
+
+                trace!(target: "dlnaproxy", "Proxied response with URL rewriting for {} ({} -> {} bytes)",
+                       peer_addr, body_len, rewritten.len());
+                break 'body;
+            }
+
+            if content_coding.is_some() || (chunked_out && !is_chunked && !http_1_1) {
+                // Compressed bodies can't be matched as they stream, and an HTTP/1.0 client
+                // can't take a chunked body while the new length isn't known yet.
+                warn!(target: "dlnaproxy", "Body too large for URL rewriting ({} bytes), passing through for {}",
+                      content_length.unwrap_or(start.len()), peer_addr);
+
+                client_write.write_all(&header_buf).await?;
+                write_body_piece(client_write, &start, is_chunked).await?;
+                loop {
+                    let bytes_read = body.read(reader, &mut buf).await?;
                     if bytes_read == 0 {
                         break;
                     }
-                    client_write.write_all(&buf[..bytes_read]).await?;
-                    remaining -= bytes_read;
+                    write_body_piece(client_write, &buf[..bytes_read], is_chunked).await?;
+                }
+                if is_chunked {
+                    client_write.write_all(&body.last_chunk()).await?;
+                }
+                client_write.flush().await?;
+                break 'body;
+            }
+
+            if chunked_out && !is_chunked {
+                client_write.write_all(set_chunked(&headers_str).as_bytes()).await?;
+            } else {
+                client_write.write_all(&header_buf).await?;
+            }
+
+            let mut total = start.len();
+            write_body_piece(client_write, &stream.feed(&start), chunked_out).await?;
+            loop {
+                let bytes_read = body.read(reader, &mut buf).await?;
+                if bytes_read == 0 {
+                    break;
                 }
+                total += bytes_read;
+                write_body_piece(client_write, &stream.feed(&buf[..bytes_read]), chunked_out).await?;
             }
+            write_body_piece(client_write, &stream.finish(), chunked_out).await?;
 
+            if chunked_out {
+                client_write.write_all(&body.last_chunk()).await?;
+            }
             client_write.flush().await?;
-            trace!(target: "dlnaproxy", "Proxied binary response for {} ({} bytes)",
-                   peer_addr, content_length.unwrap_or(0));
-            continue;
+
+            trace!(target: "dlnaproxy", "Streamed response with URL rewriting for {} ({} bytes)", peer_addr, total);
         }
 
-        // Read body for text/XML content that needs URL rewriting
-        let body = if is_chunked {
-            read_chunked_body(&mut reader, MAX_REWRITABLE_BODY_SIZE).await?
-        } else if let Some(len) = content_length {
-            let mut body = vec![0u8; len];
-            reader.read_exact(&mut body).await?;
-            body
-        } else {
-            // Already handled above
-            continue;
-        };
+        return Ok(match close {
+            true => ResponseEnd::Close,
+            false => ResponseEnd::KeepAlive,
+        });
+    }
+}
 
-        // Rewrite URLs in the body
-        let body_str = String::from_utf8_lossy(&body);
-        let rewritten_body = body_str.replace(origin_url_base, proxy_url_base);
-        let rewritten_bytes = rewritten_body.as_bytes();
+/// Reads a message body in pieces, undoing its chunked coding if any.
+enum BodyReader {
+    /// Bytes left in the body.
+    Length(usize),
+    /// Bytes left in the current chunk, and the trailers once the last one is read.
+    Chunked { remaining: usize, done: bool, trailers: Vec<u8> },
+}
 
-        // Update Content-Length if body was rewritten and size changed
-        let updated_headers = if content_length.is_some() && rewritten_bytes.len() != body.len() {
-            // Need to update Content-Length
-            update_content_length(&headers_str, rewritten_bytes.len())
-        } else {
-            headers_str.to_string()
+impl BodyReader {
+    fn new(content_length: Option<usize>, is_chunked: bool) -> Self {
+        match is_chunked {
+            true => BodyReader::Chunked {
+                remaining: 0,
+                done: false,
+                trailers: Vec::new(),
+            },
+            false => BodyReader::Length(content_length.unwrap_or(0)),
+        }
+    }
+
+    /// Read the next piece of the body into `buf`, 0 bytes at its end.
+    async fn read<R: AsyncBufReadExt + Unpin>(&mut self, reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = match self {
+            BodyReader::Length(remaining) => remaining,
+            BodyReader::Chunked { remaining, done, trailers } => {
+                while *remaining == 0 {
+                    if *done {
+                        return Ok(0);
+                    }
+
+                    let size_line = read_line_bytes(reader).await?;
+                    if size_line.is_empty() {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+
+                    *remaining = parse_chunk_size(&size_line)?;
+                    if *remaining == 0 {
+                        *trailers = read_trailers(reader).await?;
+                        *done = true;
+                    }
+                }
+                remaining
+            }
         };
 
-        // Send updated headers and body
-        client_write.write_all(updated_headers.as_bytes()).await?;
+        if *remaining == 0 {
+            return Ok(0);
+        }
 
-        if is_chunked {
-            // Re-encode as chunked
-            write_chunked_body(&mut client_write, rewritten_bytes).await?;
-        } else {
-            client_write.write_all(rewritten_bytes).await?;
+        let to_read = std::cmp::min(*remaining, buf.len());
+        let bytes_read = reader.read(&mut buf[..to_read]).await?;
+        if bytes_read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
         }
+        *remaining -= bytes_read;
+
+        // Read trailing CRLF after chunk
+        if *remaining == 0 && matches!(self, BodyReader::Chunked { .. }) {
+            let mut crlf = [0u8; 2];
+            reader.read_exact(&mut crlf).await?;
+        }
+
+        Ok(bytes_read)
+    }
+
+    /// Trailer fields of a chunked body that has been read to its end.
+    fn trailers(&self) -> &[u8] {
+        match self {
+            BodyReader::Chunked { trailers, .. } => trailers,
+            BodyReader::Length(_) => &[],
+        }
+    }
+
+    /// The last chunk ending a chunked body, with the trailers it came with.
+    fn last_chunk(&self) -> Vec<u8> {
+        [b"0\r\n", self.trailers(), b"\r\n"].concat()
+    }
+}
+
+/// Read the trailer fields after the last chunk, up to the empty line ending the body.
+async fn read_trailers<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut trailers = Vec::new();
+
+    loop {
+        let line = read_line_bytes(reader).await?;
+        if line.is_empty() || line == b"\r\n" || line == b"\n" {
+            return Ok(trailers);
+        }
+        trailers.extend_from_slice(&line);
+    }
+}
+
+/// Whether a response with `status` to a `method` request has a body (RFC 7230, 3.3.3),
+/// whatever its Content-Length says.
+fn has_body(status: u16, method: &str) -> bool {
+    !((100..200).contains(&status) || status == 204 || status == 304 || method.eq_ignore_ascii_case("HEAD"))
+}
+
+/// Whether the connection ends after this request or response: `Connection: close`,
+/// or HTTP/1.0 without keep-alive.
+fn closes_connection(head: &str) -> bool {
+    let options = header_value(head, "Connection").unwrap_or_default().to_lowercase();
+    let has_option = |option: &str| options.split(',').any(|o| o.trim() == option);
 
-        client_write.flush().await?;
+    let first_line = head.lines().next().unwrap_or_default();
+    match first_line.starts_with("HTTP/1.0") || first_line.trim_end().ends_with(" HTTP/1.0") {
+        true => !has_option("keep-alive"),
+        false => has_option("close"),
+    }
+}
 
-        trace!(target: "dlnaproxy", "Proxied response with URL rewriting for {} ({} -> {} bytes)",
-               peer_addr, body.len(), rewritten_bytes.len());
+/// Write a piece of a body, as a chunk of its own if `chunked`.
+async fn write_body_piece<W: AsyncWriteExt + Unpin>(writer: &mut W, piece: &[u8], chunked: bool) -> io::Result<()> {
+    match chunked {
+        true => write_chunk(writer, piece).await,
+        false => writer.write_all(piece).await,
     }
 }
 
@@ -398,7 +1313,7 @@ where
         // Read chunk size line as raw bytes
         let size_line = read_line_bytes(reader).await?;
         if size_line.is_empty() {
-            break;
+            return Err(io::ErrorKind::UnexpectedEof.into());
         }
         writer.write_all(&size_line).await?;
 
@@ -406,10 +1321,9 @@ where
         let chunk_size = parse_chunk_size(&size_line)?;
 
         if chunk_size == 0 {
-            // Read and forward trailing CRLF after last chunk
-            let mut trailer = Vec::new();
-            reader.read_until(b'\n', &mut trailer).await?;
-            writer.write_all(&trailer).await?;
+            // Forward the trailers and the empty line after them
+            writer.write_all(&read_trailers(reader).await?).await?;
+            writer.write_all(b"\r\n").await?;
             break;
         }
 
@@ -420,7 +1334,7 @@ where
             let to_read = std::cmp::min(remaining, buf.len());
             let bytes_read = reader.read(&mut buf[..to_read]).await?;
             if bytes_read == 0 {
-                break;
+                return Err(io::ErrorKind::UnexpectedEof.into());
             }
             writer.write_all(&buf[..bytes_read]).await?;
             remaining -= bytes_read;
@@ -436,7 +1350,7 @@ where
 }
 
 /// Read a chunked HTTP body with a maximum size limit to prevent OOM
-async fn read_chunked_body<R: AsyncBufReadExt + Unpin>(
+pub(crate) async fn read_chunked_body<R: AsyncBufReadExt + Unpin>(
     reader: &mut R,
     max_size: usize,
 ) -> io::Result<Vec<u8>> {
@@ -453,9 +1367,8 @@ async fn read_chunked_body<R: AsyncBufReadExt + Unpin>(
         let chunk_size = parse_chunk_size(&size_line)?;
 
         if chunk_size == 0 {
-            // Read trailing CRLF after last chunk
-            let mut trailer = Vec::new();
-            reader.read_until(b'\n', &mut trailer).await?;
+            // Skip the trailers after last chunk
+            read_trailers(reader).await?;
             break;
         }
 
@@ -483,21 +1396,91 @@ async fn read_chunked_body<R: AsyncBufReadExt + Unpin>(
     Ok(body)
 }
 
-/// Write body as chunked encoding
+/// Write body as chunked encoding, followed by `trailers`
 async fn write_chunked_body<W: AsyncWriteExt + Unpin>(
     writer: &mut W,
     body: &[u8],
+    trailers: &[u8],
 ) -> io::Result<()> {
-    // Write single chunk with all data
-    let size_line = format!("{:x}\r\n", body.len());
-    writer.write_all(size_line.as_bytes()).await?;
-    writer.write_all(body).await?;
-    writer.write_all(b"\r\n").await?;
+    write_chunk(writer, body).await?;
     // Write terminating chunk
-    writer.write_all(b"0\r\n\r\n").await?;
+    writer.write_all(b"0\r\n").await?;
+    writer.write_all(trailers).await?;
+    writer.write_all(b"\r\n").await?;
+    Ok(())
+}
+
+/// Write data as chunks of a chunked body, up to `MAX_CHUNK_SIZE` each, and nothing
+/// for no data as that would end the body.
+async fn write_chunk<W: AsyncWriteExt + Unpin>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    for chunk in data.chunks(MAX_CHUNK_SIZE) {
+        let size_line = format!("{:x}\r\n", chunk.len());
+        writer.write_all(size_line.as_bytes()).await?;
+        writer.write_all(chunk).await?;
+        writer.write_all(b"\r\n").await?;
+    }
     Ok(())
 }
 
+/// Give a head a Content-Length of `length` in place of any transfer coding.
+fn set_content_length(headers: &str, length: usize) -> String {
+    let mut result = String::new();
+
+    for line in headers.lines() {
+        let name = line.split(':').next().unwrap_or_default().trim();
+
+        if name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Transfer-Encoding") {
+            continue;
+        }
+
+        if line.is_empty() {
+            result.push_str(&format!("Content-Length: {}\r\n", length));
+        }
+
+        result.push_str(line);
+        result.push_str("\r\n");
+    }
+
+    result
+}
+
+/// Give a head a chunked transfer coding in place of its Content-Length.
+fn set_chunked(headers: &str) -> String {
+    let mut result = String::new();
+
+    for line in headers.lines() {
+        if line.to_lowercase().starts_with("content-length:") {
+            continue;
+        }
+
+        if line.is_empty() {
+            result.push_str("Transfer-Encoding: chunked\r\n");
+        }
+
+        result.push_str(line);
+        result.push_str("\r\n");
+    }
+
+    result
+}
+
+/// Drop the header called `name` from an HTTP head.
+fn remove_header(head: &str, name: &str) -> String {
+    let mut result = String::new();
+
+    for (i, line) in head.lines().enumerate() {
+        match line.split_once(':') {
+            Some((key, _)) if i > 0 && key.trim().eq_ignore_ascii_case(name) => {}
+            _ => {
+                result.push_str(line);
+                result.push_str("\r\n");
+            }
+        }
+    }
+
+    result
+}
+
 /// Update Content-Length header in the headers string
 fn update_content_length(headers: &str, new_length: usize) -> String {
     let mut result = String::new();
@@ -688,6 +1671,111 @@ mod tests {
         assert!(!result.contains("Content-Length:"));
     }
 
+    // ============================================
+    // Request direction tests
+    // ============================================
+
+    fn request_rewriter() -> Rewriter {
+        Rewriter::new(
+            "http://10.8.0.2:8096".into(),
+            "http://192.168.1.50:8200".into(),
+            "jellyfin.lan:8096".into(),
+            Arc::new(Vec::new()),
+        )
+    }
+
+    #[test]
+    fn test_rewrite_request_head() {
+        let head = "GET /MediaItems/1.mkv HTTP/1.1\r\nHost: 192.168.1.50:8200\r\nReferer: http://192.168.1.50:8200/web/\r\n\r\n";
+        assert_eq!(
+            rewrite_request_head(head, &request_rewriter()),
+            "GET /MediaItems/1.mkv HTTP/1.1\r\nHost: jellyfin.lan:8096\r\nReferer: http://10.8.0.2:8096/web/\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_request_accept_encoding() {
+        let head = "POST /ctl/ContentDir HTTP/1.1\r\nAccept-Encoding: gzip, deflate, br\r\n\r\n";
+        // Compressed responses are decoded for rewriting
+        assert_eq!(rewrite_request_head(head, &request_rewriter()), head);
+
+        let mut rewriter = request_rewriter();
+        rewriter.identity_encoding = true;
+        assert_eq!(
+            rewrite_request_head(head, &rewriter),
+            "POST /ctl/ContentDir HTTP/1.1\r\nAccept-Encoding: identity\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_request_absolute_target() {
+        let head = "POST http://192.168.1.50:8200/ctl/ContentDir?x=1 HTTP/1.1\r\nhost: 192.168.1.50:8200\r\n\r\n";
+        assert_eq!(
+            rewrite_request_head(head, &request_rewriter()),
+            "POST /ctl/ContentDir?x=1 HTTP/1.1\r\nhost: jellyfin.lan:8096\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_origin_form() {
+        assert_eq!(origin_form("http://a:1/b/c?d").as_deref(), Some("/b/c?d"));
+        assert_eq!(origin_form("HTTP://a:1?d").as_deref(), Some("/?d"));
+        assert_eq!(origin_form("http://a:1").as_deref(), Some("/"));
+        assert_eq!(origin_form("/b/c"), None);
+        assert_eq!(origin_form("*"), None);
+        assert_eq!(origin_form("https://a/b"), None);
+    }
+
+    #[test]
+    fn test_set_content_length() {
+        let head = "POST /ctl HTTP/1.1\r\nTransfer-Encoding: chunked\r\nSOAPAction: \"x\"\r\n\r\n";
+        assert_eq!(
+            set_content_length(head, 42),
+            "POST /ctl HTTP/1.1\r\nSOAPAction: \"x\"\r\nContent-Length: 42\r\n\r\n"
+        );
+
+        let head = "POST /ctl HTTP/1.1\r\ncontent-length: 10\r\n\r\n";
+        assert_eq!(set_content_length(head, 12), "POST /ctl HTTP/1.1\r\nContent-Length: 12\r\n\r\n");
+    }
+
+    #[test]
+    fn test_origin_host() {
+        assert_eq!(OriginAddr::new("10.8.0.2:8096".parse().unwrap(), None).host(), "10.8.0.2:8096");
+        assert_eq!(OriginAddr::new("10.8.0.2:80".parse().unwrap(), Some("jellyfin.lan")).host(), "jellyfin.lan");
+        assert_eq!(OriginAddr::new("[fd00::2]:8200".parse().unwrap(), None).host(), "[fd00::2]:8200");
+    }
+
+    // ============================================
+    // header_value() / set_header() tests
+    // ============================================
+
+    #[test]
+    fn test_header_value_case_insensitive() {
+        let head = "SUBSCRIBE /evt HTTP/1.1\r\nsid: uuid:abc\r\nTIMEOUT:Second-300\r\n\r\n";
+        assert_eq!(header_value(head, "SID"), Some("uuid:abc"));
+        assert_eq!(header_value(head, "timeout"), Some("Second-300"));
+        assert_eq!(header_value(head, "CALLBACK"), None);
+    }
+
+    #[test]
+    fn test_header_value_skips_request_line() {
+        let head = "GET http://host/ HTTP/1.1\r\n\r\n";
+        assert_eq!(header_value(head, "GET http"), None);
+    }
+
+    #[test]
+    fn test_set_header() {
+        let head = "SUBSCRIBE /evt HTTP/1.1\r\nCallback: <http://a/>\r\nNT: upnp:event\r\n\r\n";
+        let result = set_header(head, "CALLBACK", "<http://b/>");
+        assert_eq!(result, "SUBSCRIBE /evt HTTP/1.1\r\nCallback: <http://b/>\r\nNT: upnp:event\r\n\r\n");
+    }
+
+    #[test]
+    fn test_remove_header() {
+        let head = "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: 5\r\n\r\n";
+        assert_eq!(remove_header(head, "content-encoding"), "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n");
+    }
+
     // ============================================
     // read_line_bytes() tests
     // ============================================
@@ -716,6 +1804,51 @@ mod tests {
         assert_eq!(line, b"");
     }
 
+    #[tokio::test]
+    async fn test_read_head() {
+        let data = b"GET / HTTP/1.1\r\nHost: a\r\n\r\nbody";
+        let mut cursor = Cursor::new(&data[..]);
+        let head = read_head(&mut cursor).await.unwrap();
+        assert_eq!(head, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+
+        let mut rest = Vec::new();
+        cursor.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"body");
+    }
+
+    #[tokio::test]
+    async fn test_read_head_closed() {
+        let mut cursor = Cursor::new(&b""[..]);
+        assert!(read_head(&mut cursor).await.unwrap().is_empty());
+
+        let mut cursor = Cursor::new(&b"GET / HTTP/1.1\r\nHost: a\r\n"[..]);
+        assert!(read_head(&mut cursor).await.is_err());
+    }
+
+    #[test]
+    fn test_is_http_request() {
+        assert!(is_http_request(b"GET /a HTTP/1.1\r\nHost: a\r\n\r\n"));
+        assert!(is_http_request(b"NOTIFY /ev HTTP/1.0\r\n\r\n"));
+        assert!(!is_http_request(b"\xff\x00RTSP-ish\r\nGET / HTTP/1.1\r\n\r\n"));
+        assert!(!is_http_request(b"\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_read_head_too_large() {
+        let header = format!("X-Filler: {}\r\n", "a".repeat(1000));
+        let head = format!("GET / HTTP/1.1\r\n{}\r\n", header.repeat(MAX_HEAD_SIZE / header.len() + 1));
+        let result = read_head(&mut Cursor::new(head.as_bytes())).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // A single endless line
+        let line = vec![b'a'; MAX_HEAD_SIZE * 2];
+        let result = read_head(&mut Cursor::new(&line[..])).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let head = format!("GET / HTTP/1.1\r\n{}\r\n", header.repeat(MAX_HEAD_SIZE / header.len() - 1));
+        assert_eq!(read_head(&mut Cursor::new(head.as_bytes())).await.unwrap(), head.as_bytes());
+    }
+
     #[tokio::test]
     async fn test_read_line_bytes_binary_data() {
         let data = [0x00, 0xFF, 0x80, b'\n', 0x01, 0x02];
@@ -783,6 +1916,252 @@ mod tests {
         assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
     }
 
+    // ============================================
+    // Error response tests
+    // ============================================
+
+    #[test]
+    fn test_error_response() {
+        let response = error_response(503, Some(30), false);
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+
+        assert!(head.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert_eq!(header_value(head, "Retry-After"), Some("30"));
+        assert_eq!(header_value(head, "Connection"), Some("close"));
+        assert_eq!(header_value(head, "Content-Length"), Some(body.len().to_string().as_str()));
+        assert!(body.contains("<h1>503 Service Unavailable</h1>"));
+    }
+
+    #[test]
+    fn test_error_response_without_retry_after() {
+        let response = error_response(502, Some(30), true);
+
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        assert_eq!(header_value(&response, "Retry-After"), None);
+        // The length of the body a GET would get, but no body.
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    // ============================================
+    // Message framing tests
+    // ============================================
+
+    #[test]
+    fn test_has_body() {
+        assert!(has_body(200, "GET"));
+        assert!(!has_body(200, "HEAD"));
+        assert!(!has_body(100, ""));
+        assert!(!has_body(204, "POST"));
+        assert!(!has_body(304, "GET"));
+        assert!(has_body(404, "GET"));
+    }
+
+    #[test]
+    fn test_closes_connection() {
+        assert!(!closes_connection("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"));
+        assert!(closes_connection("HTTP/1.1 200 OK\r\nConnection: Keep-Alive, Close\r\n\r\n"));
+        assert!(closes_connection("HTTP/1.0 200 OK\r\nContent-Length: 0\r\n\r\n"));
+        assert!(!closes_connection("HTTP/1.0 200 OK\r\nConnection: keep-alive\r\n\r\n"));
+
+        // Requests have their version last
+        assert!(closes_connection("GET / HTTP/1.0\r\nHost: a\r\n\r\n"));
+        assert!(!closes_connection("GET / HTTP/1.1\r\nHost: a\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        assert_eq!(
+            keep_alive("GET / HTTP/1.1\r\nConnection: close\r\nHost: a\r\n\r\n"),
+            "GET / HTTP/1.1\r\nHost: a\r\nConnection: keep-alive\r\n\r\n"
+        );
+        assert_eq!(keep_alive("GET / HTTP/1.0\r\n\r\n"), "GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+    }
+
+    fn pending_request(method: &str) -> PendingRequest {
+        PendingRequest {
+            method: method.into(),
+            path: "/ctl/ContentDir".into(),
+            event: EventRequest::None,
+            http_1_1: true,
+        }
+    }
+
+    async fn forward(response: &[u8], method: &str) -> (io::Result<ResponseEnd>, Vec<u8>) {
+        let mut reader = Cursor::new(response);
+        let mut output = Vec::new();
+        let peer_addr = "127.0.0.1:1".parse().unwrap();
+        let mut pending = pending_request(method);
+        let end = forward_response(&mut reader, &mut output, &request_rewriter(), peer_addr, &mut pending, None).await;
+        (end, output)
+    }
+
+    async fn response_end(response: &[u8], method: &str) -> (ResponseEnd, Vec<u8>) {
+        let (end, output) = forward(response, method).await;
+        (end.unwrap(), output)
+    }
+
+    #[tokio::test]
+    async fn test_forward_response_end() {
+        let (end, output) = response_end(b"", "GET").await;
+        assert!(matches!(end, ResponseEnd::NoResponse));
+        assert!(output.is_empty());
+
+        // HEAD: the length is the GET's, no body follows.
+        let response = b"HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nContent-Length: 10\r\n\r\n";
+        let (end, output) = response_end(response, "HEAD").await;
+        assert!(matches!(end, ResponseEnd::KeepAlive));
+        assert_eq!(output, response);
+
+        let response = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+        let (end, output) = response_end(response, "POST").await;
+        assert!(matches!(end, ResponseEnd::Close));
+        assert_eq!(output, response);
+
+        // Closed after the interim response: the request got an answer of sorts.
+        let (end, _) = response_end(b"HTTP/1.1 100 Continue\r\n\r\n", "POST").await;
+        assert!(matches!(end, ResponseEnd::Close));
+
+        // Truncated bodies, passed through or rewritten: never kept alive.
+        for response in [
+            &b"HTTP/1.1 200 OK\r\nContent-Type: video/mp4\r\nContent-Length: 10\r\n\r\nabc"[..],
+            b"HTTP/1.1 200 OK\r\nContent-Type: video/mp4\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nabc",
+            b"HTTP/1.1 200 OK\r\nContent-Type: video/mp4\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nContent-Length: 10\r\n\r\nabc",
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n",
+        ] {
+            let (end, _) = forward(response, "GET").await;
+            assert_eq!(end.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward_response_rewrite() {
+        let xml = b"<URLBase>http://10.8.0.2:8096/</URLBase>";
+        let response = [
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nContent-Length: {}\r\n\r\n", xml.len()).as_bytes(),
+            xml,
+        ]
+        .concat();
+        let rewritten = b"<URLBase>http://192.168.1.50:8200/</URLBase>";
+        let expected = [
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nContent-Length: {}\r\n\r\n", rewritten.len()).as_bytes(),
+            rewritten,
+        ]
+        .concat();
+
+        // Small enough to be rewritten whole, keeping a Content-Length
+        let (_, output) = response_end(&response, "GET").await;
+        assert_eq!(String::from_utf8_lossy(&output), String::from_utf8_lossy(&expected));
+
+        // SOAP responses are rewritten whole, for their DIDL-Lite metadata
+        let (_, output) = response_end(&response, "POST").await;
+        assert_eq!(String::from_utf8_lossy(&output), String::from_utf8_lossy(&expected));
+
+        // Larger ones are streamed, so sent chunked as the new length isn't known up front
+        let xml = xml.repeat(MAX_BUFFERED_BODY_SIZE / xml.len() + 1);
+        let response = [
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nContent-Length: {}\r\n\r\n", xml.len()).as_bytes(),
+            &xml,
+        ]
+        .concat();
+
+        let (_, output) = response_end(&response, "GET").await;
+        let head = b"HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert!(output.starts_with(head));
+        assert!(output.ends_with(b"\r\n0\r\n\r\n"));
+        assert!(!output.windows(8).any(|w| w == b"10.8.0.2"));
+    }
+
+    #[tokio::test]
+    async fn test_origin_pool() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = OriginPool::new(OriginAddr::new(listener.local_addr().unwrap(), None), Duration::from_secs(1));
+
+        let (stream, reused) = pool.get().await.unwrap();
+        assert!(!reused);
+        let (server_side, _) = listener.accept().await.unwrap();
+
+        pool.put(stream);
+        let (stream, reused) = pool.get().await.unwrap();
+        assert!(reused);
+
+        // Closed by the origin while idle: a new connection is made instead.
+        pool.put(stream);
+        drop(server_side);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let (_, reused) = pool.get().await.unwrap();
+        assert!(!reused);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let (mut origin, proxy_side) = tokio::io::duplex(64);
+        let mut reader = IdleTimeout::new(BufReader::new(proxy_side), Duration::from_millis(100));
+
+        // Slow, but never idle for the whole timeout
+        let writer = tokio::spawn(async move {
+            for _ in 0..4 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                origin.write_all(b"ab\n").await.unwrap();
+            }
+            origin
+        });
+        for _ in 0..4 {
+            assert_eq!(read_line_bytes(&mut reader).await.unwrap(), b"ab\n");
+        }
+
+        let _origin = writer.await.unwrap();
+        let result = read_line_bytes(&mut reader).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_pass_through_chunked_trailers() {
+        let data = b"5\r\nHello\r\n0\r\nX-A: 1\r\nX-B: 2\r\n\r\nHTTP/1.1 200 OK\r\n";
+        let mut cursor = Cursor::new(&data[..]);
+        let mut output = Vec::new();
+        pass_through_chunked(&mut cursor, &mut output).await.unwrap();
+
+        // The next response is left alone.
+        assert_eq!(output, b"5\r\nHello\r\n0\r\nX-A: 1\r\nX-B: 2\r\n\r\n");
+        assert_eq!(cursor.position() as usize, output.len());
+    }
+
+    // ============================================
+    // BodyReader tests
+    // ============================================
+
+    async fn read_body(body: &mut BodyReader, data: &[u8], piece: usize) -> io::Result<Vec<u8>> {
+        let mut cursor = Cursor::new(data);
+        let mut buf = vec![0u8; piece];
+        let mut out = Vec::new();
+        loop {
+            let bytes_read = body.read(&mut cursor, &mut buf).await?;
+            if bytes_read == 0 {
+                return Ok(out);
+            }
+            out.extend_from_slice(&buf[..bytes_read]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_body_reader_chunked() {
+        let data = b"5\r\nHello\r\n7\r\n, World\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let mut reader = BodyReader::new(None, true);
+        let body = read_body(&mut reader, data, 3).await.unwrap();
+        assert_eq!(body, b"Hello, World");
+        assert_eq!(reader.last_chunk(), b"0\r\nX-Trailer: 1\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_body_reader_length() {
+        let body = read_body(&mut BodyReader::new(Some(5), false), b"Hello, World", 2).await.unwrap();
+        assert_eq!(body, b"Hello");
+
+        let result = read_body(&mut BodyReader::new(Some(20), false), b"Hello", 8).await;
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
     // ============================================
     // write_chunked_body() tests
     // ============================================
@@ -790,22 +2169,23 @@ mod tests {
     #[tokio::test]
     async fn test_write_chunked_body_simple() {
         let mut output = Vec::new();
-        write_chunked_body(&mut output, b"Hello").await.unwrap();
+        write_chunked_body(&mut output, b"Hello", b"").await.unwrap();
         assert_eq!(output, b"5\r\nHello\r\n0\r\n\r\n");
     }
 
     #[tokio::test]
     async fn test_write_chunked_body_empty() {
+        // No empty data chunk, which would end the body early
         let mut output = Vec::new();
-        write_chunked_body(&mut output, b"").await.unwrap();
-        assert_eq!(output, b"0\r\n\r\n0\r\n\r\n");
+        write_chunked_body(&mut output, b"", b"").await.unwrap();
+        assert_eq!(output, b"0\r\n\r\n");
     }
 
     #[tokio::test]
     async fn test_write_chunked_body_larger() {
         let body = b"This is a longer test body with multiple words";
         let mut output = Vec::new();
-        write_chunked_body(&mut output, body).await.unwrap();
+        write_chunked_body(&mut output, body, b"").await.unwrap();
 
         // Verify format: hex_size\r\nbody\r\n0\r\n\r\n
         let expected_size = format!("{:x}\r\n", body.len());
@@ -813,11 +2193,25 @@ mod tests {
         assert!(output.ends_with(b"\r\n0\r\n\r\n"));
     }
 
+    #[tokio::test]
+    async fn test_write_chunked_body_bounded() {
+        let body = vec![b'a'; MAX_CHUNK_SIZE * 2 + 10];
+        let mut output = Vec::new();
+        write_chunked_body(&mut output, &body, b"").await.unwrap();
+
+        let size_line = format!("{:x}\r\n", MAX_CHUNK_SIZE);
+        assert!(output.starts_with(size_line.as_bytes()));
+        assert!(output.ends_with(format!("\r\na\r\n{}\r\n0\r\n\r\n", "a".repeat(10)).as_bytes()));
+
+        let decoded = read_chunked_body(&mut Cursor::new(output), body.len()).await.unwrap();
+        assert_eq!(decoded, body);
+    }
+
     #[tokio::test]
     async fn test_write_chunked_body_binary() {
         let body: Vec<u8> = vec![0x00, 0xFF, 0x80, 0x7F];
         let mut output = Vec::new();
-        write_chunked_body(&mut output, &body).await.unwrap();
+        write_chunked_body(&mut output, &body, b"").await.unwrap();
 
         // Verify the body appears in the output
         // Format: "4\r\n" (3 bytes) + body (4 bytes) + "\r\n0\r\n\r\n"
@@ -835,7 +2229,7 @@ mod tests {
 
         // Write as chunked
         let mut encoded = Vec::new();
-        write_chunked_body(&mut encoded, original_body).await.unwrap();
+        write_chunked_body(&mut encoded, original_body, b"").await.unwrap();
 
         // Read back
         let mut cursor = Cursor::new(encoded);
@@ -850,7 +2244,7 @@ mod tests {
 
         // Write as chunked
         let mut encoded = Vec::new();
-        write_chunked_body(&mut encoded, &original_body).await.unwrap();
+        write_chunked_body(&mut encoded, &original_body, b"").await.unwrap();
 
         // Read back
         let mut cursor = Cursor::new(encoded);
@@ -912,4 +2306,10 @@ mod tests {
         let result = body.replace(origin, proxy);
         assert_eq!(result, body);
     }
+
+    #[test]
+    fn test_url_base() {
+        assert_eq!(url_base("192.168.1.52:8100".parse().unwrap()), "http://192.168.1.52:8100");
+        assert_eq!(url_base("[fe80::1]:8100".parse().unwrap()), "http://[fe80::1]:8100");
+    }
 }